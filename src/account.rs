@@ -0,0 +1,547 @@
+//! Optional registered accounts. Accounts live in a local JSON file with
+//! argon2 password hashes; signing in hands out a session token, sent back
+//! as a cookie and in the response body for bearer use. Guests keep working
+//! without an account, but cannot take a registered nickname.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    cookie::{self, Cookie, SameSite},
+    error,
+    http::header,
+    web, Error, HttpRequest, HttpResponse,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{config::Config, server::now_millis, validate::is_format};
+
+/// Cookie carrying the session token
+pub const COOKIE: &str = "together_session";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// JSON file holding the accounts
+    pub file: PathBuf,
+    /// How long a session token stays valid
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl_secs: u64,
+    #[serde(default = "default_min_password")]
+    pub min_password_len: usize,
+    /// Failed sign-ins to one name before it is locked out
+    #[serde(default = "default_max_failed")]
+    pub max_failed_signins: u32,
+    /// Failed sign-ins from one address, to any names, before it is locked out
+    #[serde(default = "default_max_failed_per_address")]
+    pub max_failed_signins_per_address: u32,
+    /// How long failures are counted and a lockout lasts
+    #[serde(default = "default_lockout")]
+    pub signin_lockout_secs: u64,
+    /// Sign-ups allowed from one address per hour
+    #[serde(default = "default_max_signups")]
+    pub max_signups_per_address: u32,
+}
+
+fn default_token_ttl() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_min_password() -> usize {
+    8
+}
+
+fn default_max_failed() -> u32 {
+    5
+}
+
+fn default_max_failed_per_address() -> u32 {
+    20
+}
+
+fn default_lockout() -> u64 {
+    15 * 60
+}
+
+fn default_max_signups() -> u32 {
+    5
+}
+
+/// Window for counting sign-ups per address
+const SIGNUP_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub name: String,
+    /// argon2 PHC string
+    hash: String,
+    /// Unix milliseconds
+    pub created: u64,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    NameTaken,
+    InvalidName,
+    WeakPassword,
+    BadCredentials,
+    /// Too many failed sign-ins for the name or from the address
+    Throttled,
+    Io(io::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::NameTaken => write!(f, "NAME_TAKEN"),
+            AccountError::InvalidName => write!(f, "INVALID_NAME"),
+            AccountError::WeakPassword => write!(f, "WEAK_PASSWORD"),
+            AccountError::BadCredentials => write!(f, "BAD_CREDENTIALS"),
+            AccountError::Throttled => write!(f, "TOO_MANY_ATTEMPTS"),
+            AccountError::Io(err) => write!(f, "IO_ERROR:{err}"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl error::ResponseError for AccountError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            AccountError::NameTaken => StatusCode::CONFLICT,
+            AccountError::InvalidName | AccountError::WeakPassword => StatusCode::BAD_REQUEST,
+            AccountError::BadCredentials => StatusCode::UNAUTHORIZED,
+            AccountError::Throttled => StatusCode::TOO_MANY_REQUESTS,
+            AccountError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Account store and issued session tokens. Tokens are kept in memory, so a
+/// restart signs everyone out.
+#[derive(Debug)]
+pub struct Accounts {
+    config: AccountsConfig,
+    accounts: RwLock<HashMap<String, Account>>,
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+    /// Failed sign-ins by `name:` or `addr:` key, with when counting started
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+    /// Sign-up attempts by address, with when counting started
+    signups: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+/// Nicknames are reserved regardless of case, width, compatibility forms
+/// and invisible format characters
+fn fold(name: &str) -> String {
+    let name: String = name.nfkc().filter(|c| !is_format(*c)).collect();
+    name.trim().to_lowercase().nfkc().collect()
+}
+
+/// Hash checked when the name is unknown, so a miss takes as long as a wrong
+/// password and does not reveal which names exist
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"not a password", &salt)
+            .map(|hash| hash.to_string())
+            .unwrap_or_default()
+    })
+}
+
+impl Accounts {
+    pub fn load(config: AccountsConfig) -> io::Result<Accounts> {
+        let accounts: Vec<Account> = match fs::read(&config.file) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        log::info!(
+            "loaded {} accounts from {}",
+            accounts.len(),
+            config.file.display()
+        );
+        Ok(Accounts {
+            config,
+            accounts: RwLock::new(accounts.into_iter().map(|a| (a.id.clone(), a)).collect()),
+            tokens: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            signups: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn token_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.token_ttl_secs)
+    }
+
+    fn find(&self, name: &str) -> Option<Account> {
+        let name = fold(name);
+        let accounts = self.accounts.read().unwrap_or_else(|e| e.into_inner());
+        accounts.values().find(|a| fold(&a.name) == name).cloned()
+    }
+
+    /// Register `name`. Every attempt counts toward the address limit before
+    /// the password is hashed, so hashing cannot be triggered at will.
+    pub fn sign_up(
+        &self,
+        name: &str,
+        password: &str,
+        addr: Option<IpAddr>,
+    ) -> Result<Account, AccountError> {
+        if password.chars().count() < self.config.min_password_len {
+            return Err(AccountError::WeakPassword);
+        }
+        if let Some(addr) = addr {
+            let mut signups = self.signups.lock().unwrap_or_else(|e| e.into_inner());
+            signups.retain(|_, (_, since)| since.elapsed() < SIGNUP_WINDOW);
+            let (count, _) = signups.entry(addr).or_insert((0, Instant::now()));
+            if *count >= self.config.max_signups_per_address {
+                return Err(AccountError::Throttled);
+            }
+            *count += 1;
+        }
+        if self.find(name).is_some() {
+            return Err(AccountError::NameTaken);
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| AccountError::WeakPassword)?
+            .to_string();
+        let mut accounts = self.accounts.write().unwrap_or_else(|e| e.into_inner());
+        if accounts.values().any(|a| fold(&a.name) == fold(name)) {
+            return Err(AccountError::NameTaken);
+        }
+        let account = Account {
+            id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            name: name.to_owned(),
+            hash,
+            created: now_millis(),
+        };
+        accounts.insert(account.id.clone(), account.clone());
+        if let Err(err) = write(&self.config.file, &accounts) {
+            accounts.remove(&account.id);
+            return Err(AccountError::Io(err));
+        }
+        log::info!("account {} registered as {}", account.id, account.name);
+        Ok(account)
+    }
+
+    /// Check a password. Names and addresses with too many recent failures
+    /// are refused without checking.
+    pub fn sign_in(
+        &self,
+        name: &str,
+        password: &str,
+        addr: Option<IpAddr>,
+    ) -> Result<Account, AccountError> {
+        let mut keys = vec![(
+            format!("name:{}", fold(name)),
+            self.config.max_failed_signins,
+        )];
+        if let Some(addr) = addr {
+            keys.push((
+                format!("addr:{addr}"),
+                self.config.max_failed_signins_per_address,
+            ));
+        }
+        if self.throttled(&keys) {
+            return Err(AccountError::Throttled);
+        }
+        let account = self.find(name);
+        let hash = account.as_ref().map_or(dummy_hash(), |a| a.hash.as_str());
+        let verified = PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+        let result = account
+            .filter(|_| verified)
+            .ok_or(AccountError::BadCredentials);
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Ok(_) => {
+                failures.remove(&keys[0].0);
+            }
+            Err(_) => {
+                let lockout = self.lockout();
+                failures.retain(|_, (_, since)| since.elapsed() < lockout);
+                for (key, _) in keys {
+                    failures.entry(key).or_insert((0, Instant::now())).0 += 1;
+                }
+            }
+        }
+        result
+    }
+
+    fn lockout(&self) -> Duration {
+        Duration::from_secs(self.config.signin_lockout_secs)
+    }
+
+    /// Whether any of the keys reached its failure limit
+    fn throttled(&self, keys: &[(String, u32)]) -> bool {
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        keys.iter().any(|(key, max)| {
+            failures
+                .get(key)
+                .is_some_and(|(count, since)| count >= max && since.elapsed() < self.lockout())
+        })
+    }
+
+    /// New session token for `account`
+    pub fn issue(&self, account: &Account) -> String {
+        let token: String = (0..32)
+            .map(|_| format!("{:02x}", rand::thread_rng().gen::<u8>()))
+            .collect();
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.retain(|_, (_, issued)| issued.elapsed() < self.token_ttl());
+        tokens.insert(token.clone(), (account.id.clone(), Instant::now()));
+        token
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Account> {
+        let id = {
+            let tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+            let (id, issued) = tokens.get(token)?;
+            if issued.elapsed() >= self.token_ttl() {
+                return None;
+            }
+            id.clone()
+        };
+        let accounts = self.accounts.read().unwrap_or_else(|e| e.into_inner());
+        accounts.get(&id).cloned()
+    }
+
+    pub fn revoke(&self, token: &str) {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.remove(token);
+    }
+
+    /// Whether `name` belongs to an account other than `account_id`
+    pub fn is_reserved(&self, name: &str, account_id: Option<&str>) -> bool {
+        self.find(name)
+            .is_some_and(|owner| Some(owner.id.as_str()) != account_id)
+    }
+
+    /// Account of the request's session cookie or bearer token
+    pub fn signed_in(&self, req: &HttpRequest) -> Option<Account> {
+        self.authenticate(&token(req)?)
+    }
+}
+
+fn write(path: &Path, accounts: &HashMap<String, Account>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut list: Vec<&Account> = accounts.values().collect();
+    list.sort_by_key(|a| a.created);
+    fs::write(&tmp, serde_json::to_vec_pretty(&list)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Session token from the cookie or an `Authorization: Bearer` header
+pub fn token(req: &HttpRequest) -> Option<String> {
+    if let Some(cookie) = req.cookie(COOKIE) {
+        return Some(cookie.value().to_owned());
+    }
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::to_owned)
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/account")
+            .route("/signup", web::post().to(sign_up))
+            .route("/signin", web::post().to(sign_in))
+            .route("/signout", web::post().to(sign_out))
+            .route("", web::get().to(me)),
+    );
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct SignedIn<'a> {
+    id: &'a str,
+    name: &'a str,
+    token: &'a str,
+}
+
+fn start_session(accounts: &Accounts, config: &Config, account: &Account) -> HttpResponse {
+    let token = accounts.issue(account);
+    let cookie = Cookie::build(COOKIE, token.clone())
+        .path("/")
+        .http_only(true)
+        .secure(config.tls.is_some())
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(
+            accounts.config.token_ttl_secs as i64,
+        ))
+        .finish();
+    HttpResponse::Ok().cookie(cookie).json(SignedIn {
+        id: &account.id,
+        name: &account.name,
+        token: &token,
+    })
+}
+
+async fn sign_up(
+    req: HttpRequest,
+    accounts: web::Data<Accounts>,
+    config: web::Data<Config>,
+    body: web::Json<Credentials>,
+) -> Result<HttpResponse, Error> {
+    let Credentials { name, password } = body.into_inner();
+    let name = config
+        .validation
+        .name(&name)
+        .map_err(|_| AccountError::InvalidName)?;
+    let addr = req.peer_addr().map(|addr| addr.ip());
+    let store = accounts.clone();
+    // hashing is deliberately slow, keep it off the worker thread
+    let account = web::block(move || store.sign_up(&name, &password, addr)).await??;
+    Ok(start_session(&accounts, &config, &account))
+}
+
+async fn sign_in(
+    req: HttpRequest,
+    accounts: web::Data<Accounts>,
+    config: web::Data<Config>,
+    body: web::Json<Credentials>,
+) -> Result<HttpResponse, Error> {
+    let Credentials { name, password } = body.into_inner();
+    let addr = req.peer_addr().map(|addr| addr.ip());
+    let store = accounts.clone();
+    let account = web::block(move || store.sign_in(&name, &password, addr)).await??;
+    Ok(start_session(&accounts, &config, &account))
+}
+
+async fn sign_out(req: HttpRequest, accounts: web::Data<Accounts>) -> HttpResponse {
+    if let Some(token) = token(&req) {
+        accounts.revoke(&token);
+    }
+    let mut cookie = Cookie::named(COOKIE);
+    cookie.set_path("/");
+    let mut res = HttpResponse::NoContent().finish();
+    res.add_removal_cookie(&cookie).ok();
+    res
+}
+
+/// The signed in account
+async fn me(req: HttpRequest, accounts: web::Data<Accounts>) -> HttpResponse {
+    match accounts.signed_in(&req) {
+        Some(account) => HttpResponse::Ok().json(serde_json::json!({
+            "id": account.id,
+            "name": account.name,
+        })),
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts(name: &str) -> Accounts {
+        let file = std::env::temp_dir().join(format!(
+            "together-accounts-{name}-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&file);
+        Accounts::load(AccountsConfig {
+            file,
+            token_ttl_secs: default_token_ttl(),
+            min_password_len: default_min_password(),
+            max_failed_signins: 2,
+            max_failed_signins_per_address: 3,
+            signin_lockout_secs: default_lockout(),
+            max_signups_per_address: 3,
+        })
+        .unwrap()
+    }
+
+    fn addr(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    #[test]
+    fn folding_ignores_case_width_and_invisible_characters() {
+        let alice = fold("alice");
+        assert_eq!(fold("Alice"), alice);
+        assert_eq!(fold("ＡＬＩＣＥ"), alice);
+        assert_eq!(fold("ali\u{200b}ce"), alice);
+        assert_eq!(fold(" alice "), alice);
+        assert_ne!(fold("alice2"), alice);
+    }
+
+    #[test]
+    fn names_are_reserved_for_their_account() {
+        let accounts = accounts("reserved");
+        let alice = accounts.sign_up("Alice", "password1", addr(1)).unwrap();
+        assert!(accounts.is_reserved("ＡＬＩＣＥ", None));
+        assert!(accounts.is_reserved("alice", Some("someone")));
+        assert!(!accounts.is_reserved("alice", Some(&alice.id)));
+        assert!(!accounts.is_reserved("bob", None));
+        let taken = accounts.sign_up("al\u{200b}ice", "password2", addr(1));
+        assert!(matches!(taken, Err(AccountError::NameTaken)));
+
+        let signed_in = accounts.sign_in("ALICE", "password1", addr(1)).unwrap();
+        assert_eq!(signed_in.id, alice.id);
+        let wrong = accounts.sign_in("alice", "password2", addr(1));
+        assert!(matches!(wrong, Err(AccountError::BadCredentials)));
+        let _ = fs::remove_file(&accounts.config.file);
+    }
+
+    #[test]
+    fn sign_ups_are_limited_per_address() {
+        let accounts = accounts("signups");
+        accounts.sign_up("alice", "password1", addr(1)).unwrap();
+        // taken names still count, without hashing
+        for _ in 0..2 {
+            let taken = accounts.sign_up("alice", "password1", addr(1));
+            assert!(matches!(taken, Err(AccountError::NameTaken)));
+        }
+        let throttled = accounts.sign_up("bob", "password1", addr(1));
+        assert!(matches!(throttled, Err(AccountError::Throttled)));
+        let elsewhere = accounts.sign_up("alice", "password1", addr(2));
+        assert!(matches!(elsewhere, Err(AccountError::NameTaken)));
+        let _ = fs::remove_file(&accounts.config.file);
+    }
+
+    #[test]
+    fn unknown_names_fail_like_wrong_passwords() {
+        let accounts = accounts("unknown");
+        for _ in 0..2 {
+            let missing = accounts.sign_in("nobody", "password1", addr(1));
+            assert!(matches!(missing, Err(AccountError::BadCredentials)));
+        }
+        let locked = accounts.sign_in("nobody", "password1", addr(2));
+        assert!(matches!(locked, Err(AccountError::Throttled)));
+        // the address limit is separate from the name limit
+        let other = accounts.sign_in("someone", "password1", addr(1));
+        assert!(matches!(other, Err(AccountError::BadCredentials)));
+        let blocked = accounts.sign_in("else", "password1", addr(1));
+        assert!(matches!(blocked, Err(AccountError::Throttled)));
+    }
+}
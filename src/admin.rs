@@ -0,0 +1,250 @@
+//! Operator API under `/admin`. Every request needs the configured token as
+//! `Authorization: Bearer <token>`; without a token the API is not mounted.
+
+use std::{
+    future::{ready, Ready},
+    io,
+};
+
+use actix::Addr;
+use actix_web::{
+    dev::Payload, error, http::header, web, Error, FromRequest, HttpRequest, HttpResponse,
+};
+use futures_util::stream;
+use serde::Deserialize;
+
+use crate::{audit::AuditQuery, config::Config, server};
+
+/// Proof that the request carried the admin token
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Admin, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(if is_admin(req) {
+            Ok(Admin)
+        } else {
+            Err(error::ErrorUnauthorized("invalid admin token"))
+        })
+    }
+}
+
+/// Whether the request carries the configured admin token
+pub fn is_admin(req: &HttpRequest) -> bool {
+    let expected = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.admin_token.clone());
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match (expected, given) {
+        (Some(expected), Some(given)) => same(expected.as_bytes(), given.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Compare without bailing out on the first differing byte
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .route("/sessions", web::get().to(sessions))
+            .route("/sessions/{id}", web::delete().to(kick))
+            .route("/announce", web::post().to(announce))
+            .route("/rooms/{name}", web::delete().to(close_room))
+            .route("/rooms/{name}/roomer", web::put().to(transfer))
+            .route("/audit", web::get().to(audit))
+            .route("/audit/export", web::get().to(audit_export)),
+    );
+}
+
+type Server = web::Data<Addr<server::ChatServer>>;
+
+fn found(ok: bool) -> HttpResponse {
+    if ok {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// All sessions of the answering instance with their user info and rooms
+async fn sessions(_: Admin, srv: Server) -> Result<HttpResponse, Error> {
+    let sessions = srv
+        .send(server::AdminSessions)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+#[derive(Deserialize)]
+struct Announcement {
+    text: String,
+}
+
+async fn announce(
+    _: Admin,
+    srv: Server,
+    body: web::Json<Announcement>,
+) -> Result<HttpResponse, Error> {
+    let text = body.into_inner().text;
+    if text.trim().is_empty() {
+        return Err(error::ErrorBadRequest("empty announcement"));
+    }
+    srv.send(server::Announce { text })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn close_room(_: Admin, srv: Server, room: web::Path<String>) -> Result<HttpResponse, Error> {
+    let closed = srv
+        .send(server::CloseRoom {
+            room: room.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(found(closed))
+}
+
+async fn kick(_: Admin, srv: Server, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let kicked = srv
+        .send(server::Kick {
+            id: id.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(found(kicked))
+}
+
+#[derive(Deserialize)]
+struct NewRoomer {
+    id: String,
+}
+
+async fn transfer(
+    _: Admin,
+    srv: Server,
+    room: web::Path<String>,
+    body: web::Json<NewRoomer>,
+) -> Result<HttpResponse, Error> {
+    let moved = srv
+        .send(server::AdminTransfer {
+            room: room.into_inner(),
+            to: body.into_inner().id,
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(found(moved))
+}
+
+/// Most recent audit events matching the query, oldest first
+async fn audit(
+    _: Admin,
+    config: web::Data<Config>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    let path = config.audit.file.clone();
+    let events = web::block(move || query.recent(&path))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Every matching audit event as JSON lines, streamed from the file
+async fn audit_export(
+    _: Admin,
+    config: web::Data<Config>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    let path = config.audit.file.clone();
+    let export = web::block(move || query.into_inner().export(&path))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    let lines = stream::try_unfold(export, |mut export| async move {
+        let (chunk, export) = web::block(move || export.next_chunk().map(|chunk| (chunk, export)))
+            .await
+            .map_err(io::Error::other)??;
+        Ok::<_, io::Error>((!chunk.is_empty()).then(|| (web::Bytes::from(chunk), export)))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit.jsonl\"",
+        ))
+        .streaming(lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test as http, App};
+
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123";
+
+    fn config(token: Option<&str>) -> web::Data<Config> {
+        web::Data::new(Config {
+            admin_token: token.map(str::to_owned),
+            ..Config::default()
+        })
+    }
+
+    fn request(token: Option<&str>, auth: Option<&str>) -> HttpRequest {
+        let req = http::TestRequest::default().app_data(config(token));
+        match auth {
+            Some(auth) => req.insert_header((header::AUTHORIZATION, auth)),
+            None => req,
+        }
+        .to_http_request()
+    }
+
+    #[test]
+    fn same_compares_whole_slices() {
+        assert!(same(b"", b""));
+        assert!(same(b"token", b"token"));
+        assert!(!same(b"token", b"tokem"));
+        assert!(!same(b"token", b"token2"));
+        assert!(!same(b"token", b""));
+    }
+
+    #[test]
+    fn admin_needs_the_configured_token() {
+        let bearer = format!("Bearer {TOKEN}");
+        assert!(is_admin(&request(Some(TOKEN), Some(&bearer))));
+        assert!(!is_admin(&request(Some(TOKEN), Some(TOKEN))));
+        assert!(!is_admin(&request(Some(TOKEN), Some("Bearer wrong"))));
+        assert!(!is_admin(&request(Some(TOKEN), None)));
+        // no token configured, nobody is admin
+        assert!(!is_admin(&request(None, Some(&bearer))));
+        assert!(!is_admin(&request(None, Some("Bearer "))));
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_requests_are_rejected() {
+        let app =
+            http::init_service(App::new().app_data(config(Some(TOKEN))).configure(routes)).await;
+        for auth in [None, Some("Bearer wrong")] {
+            let mut req = http::TestRequest::get().uri("/admin/sessions");
+            if let Some(auth) = auth {
+                req = req.insert_header((header::AUTHORIZATION, auth));
+            }
+            let res = http::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let req = http::TestRequest::post()
+            .uri("/admin/announce")
+            .set_json(serde_json::json!({ "text": "hi" }))
+            .to_request();
+        let res = http::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,392 @@
+//! Audit trail of state changing actions in `ChatServer`: who did what in
+//! which room and when. Events are appended to a JSON lines file that is
+//! never rewritten, by a writer thread so the server never waits on the
+//! disk; the admin API scans it for queries and exports.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Bytes of matching lines handed out at once by `Export`
+const EXPORT_CHUNK: usize = 64 * 1024;
+/// How long `sync` waits for the writer thread
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Append-only JSON lines file
+    pub file: PathBuf,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            file: PathBuf::from("./audit.jsonl"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Join,
+    Leave,
+    Share,
+    /// Roomer jumped to another position
+    Seek,
+    /// Roomer changed the speed, including pause and play
+    Speed,
+    Transfer,
+    Kick,
+    CloseRoom,
+    RoomMeta,
+    Filter,
+    Capacity,
+    Admit,
+}
+
+/// Who performed an action: a session, `admin` or `system`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditActor {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// `account:<id>` or `jwt:<sub>` of signed in sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+}
+
+impl AuditActor {
+    pub fn admin() -> AuditActor {
+        AuditActor {
+            id: "admin".to_owned(),
+            name: None,
+            identity: None,
+        }
+    }
+
+    pub fn system() -> AuditActor {
+        AuditActor {
+            id: "system".to_owned(),
+            name: None,
+            identity: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Unix milliseconds
+    pub at: u64,
+    pub action: Action,
+    pub actor: AuditActor,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub detail: Value,
+}
+
+enum Pending {
+    Line(String),
+    /// Answered once everything sent before is written
+    Sync(Sender<()>),
+}
+
+/// Writer side, owned by `ChatServer`
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    writer: Sender<Pending>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> io::Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.file)?;
+        let (writer, lines) = mpsc::channel();
+        let path = config.file.clone();
+        thread::Builder::new()
+            .name("audit".to_owned())
+            .spawn(move || write_lines(file, &path, lines))?;
+        Ok(AuditLog {
+            path: config.file.clone(),
+            writer,
+        })
+    }
+
+    /// Queue an event for the writer thread
+    pub fn record(&self, event: &AuditEvent) {
+        let result = serde_json::to_string(event)
+            .map_err(io::Error::from)
+            .and_then(|line| {
+                self.writer
+                    .send(Pending::Line(line))
+                    .map_err(|_| io::Error::other("audit writer is gone"))
+            });
+        if let Err(err) = result {
+            log::error!(
+                "failed to write audit event to {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+
+    /// Wait until every recorded event is written, before shutting down
+    pub fn sync(&self) {
+        let (done, written) = mpsc::channel();
+        if self.writer.send(Pending::Sync(done)).is_ok()
+            && written.recv_timeout(SYNC_TIMEOUT).is_err()
+        {
+            log::warn!("audit events may be missing from {}", self.path.display());
+        }
+    }
+}
+
+/// Append lines as they come, whatever is waiting at once in one write so
+/// concurrent readers do not see half lines
+fn write_lines(mut file: File, path: &Path, queue: mpsc::Receiver<Pending>) {
+    let mut synced = Vec::new();
+    while let Ok(first) = queue.recv() {
+        let mut out = String::new();
+        for pending in std::iter::once(first).chain(queue.try_iter()) {
+            match pending {
+                Pending::Line(line) => {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+                Pending::Sync(done) => synced.push(done),
+            }
+        }
+        if let Err(err) = file.write_all(out.as_bytes()) {
+            log::error!("failed to write audit events to {}: {err}", path.display());
+        }
+        for done in synced.drain(..) {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// `GET /admin/audit` filters, all optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub room: Option<String>,
+    /// Session id, nickname or identity
+    pub actor: Option<String>,
+    pub action: Option<Action>,
+    /// Unix milliseconds, inclusive
+    pub since: Option<u64>,
+    /// Unix milliseconds, exclusive
+    pub until: Option<u64>,
+    /// Most recent matches to return
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        let actor = &event.actor;
+        self.room
+            .as_ref()
+            .is_none_or(|room| event.room.as_ref() == Some(room))
+            && self.action.is_none_or(|action| action == event.action)
+            && self.since.is_none_or(|since| event.at >= since)
+            && self.until.is_none_or(|until| event.at < until)
+            && self.actor.as_ref().is_none_or(|who| {
+                *who == actor.id
+                    || actor.name.as_ref() == Some(who)
+                    || actor.identity.as_ref() == Some(who)
+            })
+    }
+
+    /// Call `f` with every matching event, oldest first. Lines that do not
+    /// parse, like one being written, are skipped.
+    fn scan(&self, path: &Path, mut f: impl FnMut(AuditEvent)) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let Ok(event) = serde_json::from_str::<AuditEvent>(&line) else {
+                continue;
+            };
+            if self.matches(&event) {
+                f(event);
+            }
+        }
+        Ok(())
+    }
+
+    /// The most recent `limit` matches, oldest first
+    pub fn recent(&self, path: &Path) -> io::Result<Vec<AuditEvent>> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let mut events = VecDeque::with_capacity(limit);
+        self.scan(path, |event| {
+            if events.len() == limit {
+                events.pop_front();
+            }
+            events.push_back(event);
+        })?;
+        Ok(events.into())
+    }
+
+    /// Every match as JSON lines, read a chunk at a time. `limit` is ignored.
+    pub fn export(self, path: &Path) -> io::Result<Export> {
+        let lines = match File::open(path) {
+            Ok(file) => Some(BufReader::new(file).lines()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Ok(Export { query: self, lines })
+    }
+}
+
+/// Matching lines of the audit file, for a streamed response
+pub struct Export {
+    query: AuditQuery,
+    lines: Option<io::Lines<BufReader<File>>>,
+}
+
+impl Export {
+    /// The next lines up to about `EXPORT_CHUNK` bytes, empty at the end.
+    /// Blocks on the file.
+    pub fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let Some(lines) = &mut self.lines else {
+            return Ok(out);
+        };
+        while out.len() < EXPORT_CHUNK {
+            let Some(line) = lines.next().transpose()? else {
+                self.lines = None;
+                break;
+            };
+            match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(event) if self.query.matches(&event) => {
+                    out.extend_from_slice(line.as_bytes());
+                    out.push(b'\n');
+                }
+                _ => {}
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("together-audit-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn event(at: u64, action: Action, room: &str) -> AuditEvent {
+        AuditEvent {
+            at,
+            action,
+            actor: AuditActor {
+                id: "1234".to_owned(),
+                name: Some("alice".to_owned()),
+                identity: Some("account:a".to_owned()),
+            },
+            room: Some(room.to_owned()),
+            detail: Value::Null,
+        }
+    }
+
+    #[test]
+    fn actors_match_by_id_name_or_identity() {
+        let event = event(10, Action::Join, "r");
+        let by = |who: &str| AuditQuery {
+            actor: Some(who.to_owned()),
+            ..AuditQuery::default()
+        };
+        assert!(by("1234").matches(&event));
+        assert!(by("alice").matches(&event));
+        assert!(by("account:a").matches(&event));
+        assert!(!by("bob").matches(&event));
+        assert!(!by("account:b").matches(&AuditEvent {
+            actor: AuditActor::system(),
+            ..event
+        }));
+    }
+
+    #[test]
+    fn since_is_inclusive_until_exclusive() {
+        let query = AuditQuery {
+            since: Some(10),
+            until: Some(20),
+            ..AuditQuery::default()
+        };
+        assert!(!query.matches(&event(9, Action::Join, "r")));
+        assert!(query.matches(&event(10, Action::Join, "r")));
+        assert!(query.matches(&event(19, Action::Join, "r")));
+        assert!(!query.matches(&event(20, Action::Join, "r")));
+        let query = AuditQuery {
+            room: Some("r".to_owned()),
+            action: Some(Action::Leave),
+            ..AuditQuery::default()
+        };
+        assert!(query.matches(&event(0, Action::Leave, "r")));
+        assert!(!query.matches(&event(0, Action::Join, "r")));
+        assert!(!query.matches(&event(0, Action::Leave, "s")));
+    }
+
+    #[test]
+    fn recent_keeps_the_last_matches() {
+        let file = temp("recent");
+        let log = AuditLog::open(&AuditConfig { file: file.clone() }).unwrap();
+        for at in 0..10 {
+            let room = if at % 2 == 0 { "r" } else { "s" };
+            log.record(&event(at, Action::Join, room));
+        }
+        log.sync();
+        let query = AuditQuery {
+            room: Some("r".to_owned()),
+            limit: Some(3),
+            ..AuditQuery::default()
+        };
+        let at: Vec<u64> = query.recent(&file).unwrap().iter().map(|e| e.at).collect();
+        assert_eq!(at, [4, 6, 8]);
+        assert!(AuditQuery::default()
+            .recent(&temp("missing"))
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn exports_skip_partial_lines() {
+        let file = temp("export");
+        let line = |at| serde_json::to_string(&event(at, Action::Join, "r")).unwrap();
+        let partial = &line(3)[..20];
+        let text = format!("{}\nnot json\n{}\n{partial}", line(1), line(2));
+        std::fs::write(&file, text).unwrap();
+        let mut export = AuditQuery::default().export(&file).unwrap();
+        let chunk = export.next_chunk().unwrap();
+        assert_eq!(
+            String::from_utf8(chunk).unwrap(),
+            format!("{}\n{}\n", line(1), line(2))
+        );
+        assert!(export.next_chunk().unwrap().is_empty());
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,336 @@
+//! Avatars served from our own origin. Uploads are sniffed, re-encoded to
+//! PNG at the standard sizes and stored by content hash; users without an
+//! upload get an SVG identicon derived from their id. Only signed in users
+//! may upload, a limited number of times per hour.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Cursor},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_files::NamedFile;
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse, Responder};
+use image::{imageops::FilterType, ImageError, ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{account::Accounts, config::Config, jwt, jwt::Verifier};
+
+/// Prefix of every avatar URL we hand out
+const PREFIX: &str = "/avatars/";
+
+/// Largest accepted source image, per side
+const MAX_SOURCE_SIDE: u32 = 4096;
+
+/// Hex digits of the content hash in stored file names
+const HASH_LEN: usize = 32;
+
+/// Window `max_uploads_per_hour` is counted over
+const UPLOAD_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AvatarConfig {
+    /// Where re-encoded uploads are stored
+    pub dir: PathBuf,
+    pub max_upload_bytes: usize,
+    /// Square sizes each upload is rendered at
+    pub sizes: Vec<u32>,
+    /// Uploads per hour allowed to one user and to one client address
+    pub max_uploads_per_hour: u32,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        AvatarConfig {
+            dir: PathBuf::from("./avatars"),
+            max_upload_bytes: 2 * 1024 * 1024,
+            sizes: vec![64, 128, 256],
+            max_uploads_per_hour: 20,
+        }
+    }
+}
+
+/// Whether `url` is one of the avatar URLs we hand out:
+/// `/avatars/<hash>.png` or `/avatars/identicon/<id>.svg`
+pub fn is_ours(url: &str) -> bool {
+    let Some(rest) = url.strip_prefix(PREFIX) else {
+        return false;
+    };
+    if let Some(id) = rest
+        .strip_prefix("identicon/")
+        .and_then(|rest| rest.strip_suffix(".svg"))
+    {
+        return !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || jwt::SUB_PUNCTUATION.contains(c));
+    }
+    rest.strip_suffix(".png")
+        .is_some_and(|hash| hash.len() == HASH_LEN && hash.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub fn identicon_url(id: &str) -> String {
+    format!("{PREFIX}identicon/{id}.svg")
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// 5x5 mirrored grid, colored by the hash
+pub fn identicon(id: &str) -> String {
+    let hash = fnv1a(id);
+    let hue = hash % 360;
+    let mut cells = String::new();
+    for row in 0..5 {
+        for col in 0..3 {
+            if hash >> (8 + row * 3 + col) & 1 == 0 {
+                continue;
+            }
+            for x in [col, 4 - col] {
+                cells.push_str(&format!(
+                    r#"<rect x="{x}" y="{row}" width="1" height="1"/>"#
+                ));
+                if x == 2 {
+                    break;
+                }
+            }
+        }
+    }
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="-0.5 -0.5 6 6"><rect x="-0.5" y="-0.5" width="6" height="6" fill="#f0f0f0"/><g fill="hsl({hue},55%,50%)">{cells}</g></svg>"##
+    )
+}
+
+/// Recent uploads by `user:` and `addr:` key, shared by all workers
+#[derive(Debug, Default)]
+pub struct Uploads {
+    recent: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl Uploads {
+    /// Count an upload for every key, false if one of them is over `max`
+    fn allow(&self, keys: &[String], max: u32) -> bool {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.retain(|_, (_, since)| since.elapsed() < UPLOAD_WINDOW);
+        if keys
+            .iter()
+            .any(|key| recent.get(key).is_some_and(|(count, _)| *count >= max))
+        {
+            return false;
+        }
+        for key in keys {
+            recent.entry(key.clone()).or_insert((0, Instant::now())).0 += 1;
+        }
+        true
+    }
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig, config: &AvatarConfig, uploads: web::Data<Uploads>) {
+    cfg.service(
+        web::resource("/api/avatar")
+            .app_data(web::PayloadConfig::new(config.max_upload_bytes))
+            .app_data(uploads)
+            .route(web::post().to(upload)),
+    )
+    .route("/avatars/identicon/{id}.svg", web::get().to(get_identicon))
+    .route("/avatars/{hash}.png", web::get().to(get_avatar));
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    Unsupported,
+    Decode(ImageError),
+    Io(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Unsupported => write!(f, "UNSUPPORTED_IMAGE"),
+            UploadError::Decode(err) => write!(f, "INVALID_IMAGE:{err}"),
+            UploadError::Io(err) => write!(f, "IO_ERROR:{err}"),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<io::Error> for UploadError {
+    fn from(err: io::Error) -> Self {
+        UploadError::Io(err)
+    }
+}
+
+impl error::ResponseError for UploadError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            UploadError::Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UploadError::Decode(_) => StatusCode::BAD_REQUEST,
+            UploadError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Uploaded {
+    avatar: String,
+}
+
+/// Raw image body, `image/png`, `image/jpeg`, `image/gif` or `image/webp`.
+/// Needs an account session or a JWT in `?token=`.
+async fn upload(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    uploads: web::Data<Uploads>,
+    accounts: Option<web::Data<Accounts>>,
+    verifier: Option<web::Data<Verifier>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let claims = verifier.and_then(|v| v.verify(&req, &config.validation).ok().flatten());
+    let user = match claims {
+        Some(claims) => format!("jwt:{}", claims.sub),
+        None => match accounts.and_then(|a| a.signed_in(&req)) {
+            Some(account) => format!("account:{}", account.id),
+            None => return Err(error::ErrorUnauthorized("SIGN_IN_REQUIRED")),
+        },
+    };
+    let mut keys = vec![format!("user:{user}")];
+    if let Some(addr) = req.peer_addr() {
+        keys.push(format!("addr:{}", addr.ip()));
+    }
+    if !uploads.allow(&keys, config.avatars.max_uploads_per_hour) {
+        return Err(error::ErrorTooManyRequests("TOO_MANY_UPLOADS"));
+    }
+    let avatars = config.avatars.clone();
+    let hash = web::block(move || store(&avatars, &body)).await??;
+    Ok(HttpResponse::Created().json(Uploaded {
+        avatar: format!("{PREFIX}{hash}.png"),
+    }))
+}
+
+/// Decode, resize and write every size, returns the content hash
+fn store(config: &AvatarConfig, bytes: &[u8]) -> Result<String, UploadError> {
+    let format = image::guess_format(bytes)
+        .ok()
+        .filter(|f| {
+            matches!(
+                f,
+                ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+            )
+        })
+        .ok_or(UploadError::Unsupported)?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_SIDE);
+    limits.max_image_height = Some(MAX_SOURCE_SIDE);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(UploadError::Decode)?;
+
+    let hash: String = Sha256::digest(bytes)[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    fs::create_dir_all(&config.dir)?;
+    for &size in &config.sizes {
+        let path = config.dir.join(format!("{hash}-{size}.png"));
+        if path.exists() {
+            continue;
+        }
+        let mut png = Vec::new();
+        image
+            .resize_to_fill(size, size, FilterType::Lanczos3)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(UploadError::Decode)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, png)?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(hash)
+}
+
+#[derive(Deserialize)]
+struct SizeQuery {
+    size: Option<u32>,
+}
+
+/// Smallest stored size covering `?size=`, the largest one otherwise
+async fn get_avatar(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    hash: web::Path<String>,
+    query: web::Query<SizeQuery>,
+) -> Result<HttpResponse, Error> {
+    let hash = hash.into_inner();
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(error::ErrorNotFound("no such avatar"));
+    }
+    let mut sizes = config.avatars.sizes.clone();
+    sizes.sort_unstable();
+    let size = match query.size {
+        Some(wanted) => sizes.iter().find(|&&s| s >= wanted).or(sizes.last()),
+        None => sizes.last(),
+    }
+    .ok_or_else(|| error::ErrorNotFound("no avatar sizes"))?;
+    let file = NamedFile::open_async(config.avatars.dir.join(format!("{hash}-{size}.png")))
+        .await
+        .map_err(|_| error::ErrorNotFound("no such avatar"))?;
+    Ok(file.respond_to(&req).map_into_boxed_body())
+}
+
+async fn get_identicon(id: web::Path<String>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+        .body(identicon(&id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn our_urls() {
+        assert!(is_ours("/avatars/0123456789abcdef0123456789abcdef.png"));
+        assert!(is_ours("/avatars/identicon/3468591732.svg"));
+        assert!(is_ours("/avatars/identicon/jwt:auth0|42.svg"));
+        assert!(is_ours(&identicon_url("f2aa29924152477948")));
+    }
+
+    #[test]
+    fn other_urls() {
+        assert!(!is_ours("/avatars/../config.toml"));
+        assert!(!is_ours("/avatars/0123.png"));
+        assert!(!is_ours("/avatars/0123456789abcdef0123456789abcdeg.png"));
+        assert!(!is_ours(
+            "/avatars/0123456789abcdef0123456789abcdef.png?x=1"
+        ));
+        assert!(!is_ours("/avatars/identicon/.svg"));
+        assert!(!is_ours("/avatars/identicon/a/b.svg"));
+        assert!(!is_ours("/avatars/identicon/a\"onload=\"x.svg"));
+        assert!(!is_ours("/api/avatar"));
+        assert!(!is_ours("https://example.com/avatars/x.png"));
+    }
+
+    #[test]
+    fn uploads_are_limited_per_key() {
+        let uploads = Uploads::default();
+        let alice = ["user:a".to_owned(), "addr:1".to_owned()];
+        let bob = ["user:b".to_owned(), "addr:1".to_owned()];
+        assert!(uploads.allow(&alice, 2));
+        assert!(uploads.allow(&alice, 2));
+        assert!(!uploads.allow(&alice, 2));
+        // same address
+        assert!(!uploads.allow(&bob, 2));
+        assert!(uploads.allow(&["user:b".to_owned()], 2));
+    }
+}
@@ -0,0 +1,664 @@
+//! Room fan-out across server instances. `ChatServer` delivers to its own
+//! sessions and publishes through a `Broker` so other instances can deliver
+//! to theirs. Membership, roomer and playback are shared through the broker
+//! as well.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use redis::{Commands, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::server::User;
+
+/// Pub/sub channel shared by all instances
+const CHANNEL: &str = "together:events";
+
+/// Membership, roomer and playback changes, so instances can keep their
+/// copy of the shared rooms current
+const STATE_CHANNEL: &str = "together:state";
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often an instance renews its liveness key and reloads the rooms
+const HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// An instance that missed this many heartbeats is gone, its members are
+/// removed by the others
+const INSTANCE_TTL: Duration = Duration::from_secs(30);
+
+/// Room keys nobody has written to for this long expire
+const ROOM_TTL: Duration = Duration::from_secs(3600);
+
+/// Length of the instance prefix of session ids
+const INSTANCE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BrokerConfig {
+    /// Single process, nothing is shared
+    #[default]
+    Local,
+    Redis {
+        url: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BrokerEvent {
+    /// Deliver `payload` to members of `room`, except `skip`
+    Room {
+        room: String,
+        payload: String,
+        skip: String,
+    },
+    /// Deliver `payload` to session `to`
+    Session { to: String, payload: String },
+    /// Deliver `payload` to every session
+    Everyone { payload: String },
+    /// Room ownership moved to `id`
+    Roomer { room: String, id: String },
+}
+
+/// Event published by another instance
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Remote(pub BrokerEvent);
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: String,
+    event: BrokerEvent,
+}
+
+/// Playback as stored in the broker, `updated` in unix milliseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedPlayback {
+    pub progress: f64,
+    pub speed: f64,
+    pub updated: u64,
+}
+
+/// Room state across all instances
+#[derive(Debug, Clone, Default)]
+pub struct SharedRoom {
+    pub roomer: Option<String>,
+    pub members: HashMap<String, User>,
+    pub playback: Option<SharedPlayback>,
+}
+
+pub trait Broker: fmt::Debug {
+    /// Prefix for session ids created by this instance
+    fn instance(&self) -> &str;
+    fn publish(&self, event: BrokerEvent);
+    /// Deliver events published by other instances to `server`
+    fn subscribe(&self, server: Recipient<Remote>);
+    fn join(&self, room: &str, user: &User);
+    fn leave(&self, room: &str, id: &str);
+    fn room(&self, room: &str) -> SharedRoom;
+    fn set_roomer(&self, room: &str, id: &str);
+    fn set_playback(&self, room: &str, playback: &SharedPlayback);
+}
+
+pub fn connect(config: &BrokerConfig) -> Result<Box<dyn Broker>, redis::RedisError> {
+    match config {
+        BrokerConfig::Local => Ok(Box::new(LocalBroker)),
+        BrokerConfig::Redis { url } => Ok(Box::new(RedisBroker::new(url)?)),
+    }
+}
+
+/// Everything lives in this process, the current behavior
+#[derive(Debug)]
+pub struct LocalBroker;
+
+impl Broker for LocalBroker {
+    fn instance(&self) -> &str {
+        ""
+    }
+    fn publish(&self, _: BrokerEvent) {}
+    fn subscribe(&self, _: Recipient<Remote>) {}
+    fn join(&self, _: &str, _: &User) {}
+    fn leave(&self, _: &str, _: &str) {}
+    fn room(&self, _: &str) -> SharedRoom {
+        SharedRoom::default()
+    }
+    fn set_roomer(&self, _: &str, _: &str) {}
+    fn set_playback(&self, _: &str, _: &SharedPlayback) {}
+}
+
+/// Shared room state change, applied by every instance to its copy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Change {
+    Join {
+        room: String,
+        user: User,
+    },
+    Leave {
+        room: String,
+        id: String,
+    },
+    Roomer {
+        room: String,
+        id: String,
+    },
+    Playback {
+        room: String,
+        playback: SharedPlayback,
+    },
+}
+
+impl Change {
+    fn apply(self, rooms: &mut HashMap<String, SharedRoom>) {
+        match self {
+            Change::Join { room, user } => {
+                let room = rooms.entry(room).or_default();
+                room.members.insert(user.id.clone(), user);
+            }
+            Change::Leave { room, id } => {
+                if let Some(room) = rooms.get_mut(&room) {
+                    room.members.remove(&id);
+                }
+            }
+            Change::Roomer { room, id } => rooms.entry(room).or_default().roomer = Some(id),
+            Change::Playback { room, playback } => {
+                rooms.entry(room).or_default().playback = Some(playback)
+            }
+        }
+    }
+
+    /// Write the change to the room hashes
+    fn store(&self, conn: &mut Connection) -> redis::RedisResult<()> {
+        let ttl = ROOM_TTL.as_secs() as usize;
+        match self {
+            Change::Join { room, user } => {
+                let json = serde_json::to_string(user).map_err(redis_error)?;
+                conn.hset::<_, _, _, ()>(members_key(room), &user.id, json)?;
+                conn.expire(members_key(room), ttl)
+            }
+            Change::Leave { room, id } => conn.hdel(members_key(room), id),
+            Change::Roomer { room, id } => {
+                conn.hset::<_, _, _, ()>(room_key(room), "roomer", id)?;
+                conn.expire(room_key(room), ttl)
+            }
+            Change::Playback { room, playback } => {
+                let json = serde_json::to_string(playback).map_err(redis_error)?;
+                conn.hset::<_, _, _, ()>(room_key(room), "playback", json)?;
+                conn.expire(room_key(room), ttl)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateEnvelope {
+    origin: String,
+    change: Change,
+}
+
+/// Work for the redis thread
+enum Op {
+    Publish(String),
+    Change(Change),
+}
+
+type Rooms = Arc<Mutex<HashMap<String, SharedRoom>>>;
+
+/// Redis pub/sub for fan-out, hashes for shared room state:
+/// `together:room:{name}` holds roomer and playback,
+/// `together:room:{name}:members` maps session id to user and
+/// `together:instance:{id}` is renewed by every live instance.
+///
+/// Redis is only talked to from a thread of its own: writes are queued to
+/// it and reads are served from a copy of the shared rooms, kept current
+/// through `STATE_CHANNEL` and reloaded on every heartbeat. Members of
+/// instances whose key expired are dropped on reload.
+pub struct RedisBroker {
+    instance: String,
+    client: redis::Client,
+    rooms: Rooms,
+    ops: Sender<Op>,
+}
+
+impl fmt::Debug for RedisBroker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisBroker")
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+fn open(client: &redis::Client) -> redis::RedisResult<Connection> {
+    let conn = client.get_connection_with_timeout(REDIS_TIMEOUT)?;
+    conn.set_read_timeout(Some(REDIS_TIMEOUT))?;
+    conn.set_write_timeout(Some(REDIS_TIMEOUT))?;
+    Ok(conn)
+}
+
+fn redis_error(err: serde_json::Error) -> redis::RedisError {
+    redis::RedisError::from((redis::ErrorKind::TypeError, "json", err.to_string()))
+}
+
+fn room_key(room: &str) -> String {
+    format!("together:room:{room}")
+}
+
+fn members_key(room: &str) -> String {
+    format!("together:room:{room}:members")
+}
+
+fn instance_key(instance: &str) -> String {
+    format!("together:instance:{instance}")
+}
+
+/// Instance a session id was created by
+fn instance_of(id: &str) -> &str {
+    id.get(..INSTANCE_LEN).unwrap_or(id)
+}
+
+/// Mark `instance` alive for another `INSTANCE_TTL`
+fn heartbeat(conn: &mut Connection, instance: &str) -> redis::RedisResult<()> {
+    conn.set_ex(instance_key(instance), 1, INSTANCE_TTL.as_secs() as usize)
+}
+
+/// Read every shared room, removing members of instances that are gone
+fn load(conn: &mut Connection) -> redis::RedisResult<HashMap<String, SharedRoom>> {
+    let keys: Vec<String> = conn.scan_match::<_, String>("together:room:*")?.collect();
+    let mut rooms: HashMap<String, SharedRoom> = HashMap::new();
+    let mut alive: HashMap<String, bool> = HashMap::new();
+    let names: HashSet<&str> = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix("together:room:"))
+        .map(|name| name.strip_suffix(":members").unwrap_or(name))
+        .collect();
+    for name in names {
+        let state: HashMap<String, String> = conn.hgetall(room_key(name))?;
+        let members: HashMap<String, String> = conn.hgetall(members_key(name))?;
+        let mut room = SharedRoom {
+            roomer: state.get("roomer").cloned(),
+            members: HashMap::new(),
+            playback: state
+                .get("playback")
+                .and_then(|json| serde_json::from_str(json).ok()),
+        };
+        for (id, json) in members {
+            let instance = instance_of(&id).to_owned();
+            let live = match alive.get(&instance) {
+                Some(live) => *live,
+                None => {
+                    let live = conn.exists(instance_key(&instance))?;
+                    alive.insert(instance, live);
+                    live
+                }
+            };
+            if !live {
+                conn.hdel::<_, _, ()>(members_key(name), &id)?;
+                continue;
+            }
+            if let Ok(user) = serde_json::from_str(&json) {
+                room.members.insert(id, user);
+            }
+        }
+        rooms.insert(name.to_owned(), room);
+    }
+    Ok(rooms)
+}
+
+/// Run `ops` against redis until the broker is dropped, renewing the
+/// instance and reloading the rooms every `HEARTBEAT`
+fn work(client: redis::Client, instance: String, rooms: Rooms, ops: mpsc::Receiver<Op>) {
+    let mut conn = None;
+    let mut next_beat = Instant::now() + HEARTBEAT;
+    loop {
+        let op = match ops.recv_timeout(next_beat.saturating_duration_since(Instant::now())) {
+            Ok(op) => Some(op),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        // reconnect once if the connection was lost
+        for _ in 0..2 {
+            if conn.is_none() {
+                conn = open(&client)
+                    .map_err(|err| log::warn!("redis reconnect failed: {err}"))
+                    .ok();
+            }
+            let Some(c) = conn.as_mut() else {
+                break;
+            };
+            let result = match &op {
+                Some(Op::Publish(json)) => c.publish(CHANNEL, json),
+                Some(Op::Change(change)) => change.store(c).and_then(|_| {
+                    let envelope = StateEnvelope {
+                        origin: instance.clone(),
+                        change: change.clone(),
+                    };
+                    let json = serde_json::to_string(&envelope).map_err(redis_error)?;
+                    c.publish(STATE_CHANNEL, json)
+                }),
+                None => heartbeat(c, &instance).and_then(|_| load(c)).map(|loaded| {
+                    *rooms.lock().unwrap() = loaded;
+                }),
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) => {
+                    log::warn!("redis command failed: {err}");
+                    conn = None;
+                }
+            }
+        }
+        match op {
+            // reapplied after the write so a reload in between cannot undo it
+            Some(Op::Change(change)) => change.apply(&mut rooms.lock().unwrap()),
+            Some(Op::Publish(_)) => {}
+            None => next_beat = Instant::now() + HEARTBEAT,
+        }
+    }
+}
+
+impl RedisBroker {
+    pub fn new(url: &str) -> redis::RedisResult<RedisBroker> {
+        let client = redis::Client::open(url)?;
+        let mut conn = open(&client)?;
+        let instance = format!("{:08x}", rand::random::<u32>());
+        heartbeat(&mut conn, &instance)?;
+        let rooms = Arc::new(Mutex::new(load(&mut conn)?));
+        let (ops, rx) = mpsc::channel();
+        {
+            let client = client.clone();
+            let instance = instance.clone();
+            let rooms = rooms.clone();
+            thread::spawn(move || work(client, instance, rooms, rx));
+        }
+        log::info!("connected to redis broker as instance {instance}");
+        Ok(RedisBroker {
+            instance,
+            client,
+            rooms,
+            ops,
+        })
+    }
+
+    fn change(&self, change: Change) {
+        change.clone().apply(&mut self.rooms.lock().unwrap());
+        let _ = self.ops.send(Op::Change(change));
+    }
+
+    /// Pass events published by other instances to `deliver` and apply
+    /// their room changes, from a thread of its own
+    fn listen(&self, deliver: impl Fn(BrokerEvent) + Send + 'static) {
+        let client = self.client.clone();
+        let instance = self.instance.clone();
+        let rooms = self.rooms.clone();
+        thread::spawn(move || loop {
+            let mut conn = match client.get_connection() {
+                Ok(conn) => conn,
+                Err(err) => {
+                    log::warn!("redis subscribe failed: {err}");
+                    thread::sleep(REDIS_TIMEOUT);
+                    continue;
+                }
+            };
+            let mut pubsub = conn.as_pubsub();
+            if let Err(err) = pubsub.subscribe(&[CHANNEL, STATE_CHANNEL]) {
+                log::warn!("redis subscribe failed: {err}");
+                thread::sleep(REDIS_TIMEOUT);
+                continue;
+            }
+            loop {
+                let msg = match pubsub.get_message() {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        log::warn!("redis subscription lost: {err}");
+                        break;
+                    }
+                };
+                let Ok(payload) = msg.get_payload::<String>() else {
+                    continue;
+                };
+                if msg.get_channel_name() == STATE_CHANNEL {
+                    match serde_json::from_str::<StateEnvelope>(&payload) {
+                        Ok(envelope) if envelope.origin != instance => {
+                            envelope.change.apply(&mut rooms.lock().unwrap())
+                        }
+                        Ok(_) => {}
+                        Err(err) => log::warn!("invalid broker change: {err}"),
+                    }
+                    continue;
+                }
+                match serde_json::from_str::<Envelope>(&payload) {
+                    Ok(envelope) if envelope.origin != instance => deliver(envelope.event),
+                    Ok(_) => {}
+                    Err(err) => log::warn!("invalid broker event: {err}"),
+                }
+            }
+        });
+    }
+}
+
+impl Broker for RedisBroker {
+    fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    fn publish(&self, event: BrokerEvent) {
+        let envelope = Envelope {
+            origin: self.instance.clone(),
+            event,
+        };
+        if let Ok(json) = serde_json::to_string(&envelope) {
+            let _ = self.ops.send(Op::Publish(json));
+        }
+    }
+
+    fn subscribe(&self, server: Recipient<Remote>) {
+        self.listen(move |event| server.do_send(Remote(event)));
+    }
+
+    fn join(&self, room: &str, user: &User) {
+        self.change(Change::Join {
+            room: room.to_owned(),
+            user: user.clone(),
+        });
+    }
+
+    fn leave(&self, room: &str, id: &str) {
+        self.change(Change::Leave {
+            room: room.to_owned(),
+            id: id.to_owned(),
+        });
+    }
+
+    fn room(&self, room: &str) -> SharedRoom {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_roomer(&self, room: &str, id: &str) {
+        self.change(Change::Roomer {
+            room: room.to_owned(),
+            id: id.to_owned(),
+        });
+    }
+
+    fn set_playback(&self, room: &str, playback: &SharedPlayback) {
+        self.change(Change::Playback {
+            room: room.to_owned(),
+            playback: playback.clone(),
+        });
+    }
+}
+
+/// These need a redis server: `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored`
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::server::Presence;
+
+    fn url() -> String {
+        std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned())
+    }
+
+    fn broker() -> RedisBroker {
+        RedisBroker::new(&url()).expect("redis at REDIS_URL")
+    }
+
+    fn user(broker: &RedisBroker) -> User {
+        User {
+            id: format!("{}{}", broker.instance(), rand::random::<u32>()),
+            name: Some("bob".to_owned()),
+            avatar: None,
+            presence: Presence::Watching,
+        }
+    }
+
+    fn room() -> String {
+        format!("test-{:08x}", rand::random::<u32>())
+    }
+
+    /// Wait for `check` to hold, giving pub/sub time to deliver
+    fn eventually(mut check: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    fn listen(broker: &RedisBroker) -> Receiver<BrokerEvent> {
+        let (tx, rx) = mpsc::channel();
+        broker.listen(move |event| {
+            let _ = tx.send(event);
+        });
+        rx
+    }
+
+    #[test]
+    #[ignore]
+    fn shares_room_state() {
+        let (a, b) = (broker(), broker());
+        let _rx = listen(&b);
+        // give the subscription time to start
+        thread::sleep(Duration::from_millis(200));
+        let room = room();
+        let user = user(&a);
+        a.join(&room, &user);
+        a.set_roomer(&room, &user.id);
+        a.set_playback(
+            &room,
+            &SharedPlayback {
+                progress: 12.0,
+                speed: 1.0,
+                updated: 1,
+            },
+        );
+        assert!(eventually(|| {
+            let shared = b.room(&room);
+            shared.members.contains_key(&user.id)
+                && shared.roomer.as_ref() == Some(&user.id)
+                && shared.playback.as_ref().is_some_and(|p| p.progress == 12.0)
+        }));
+        a.leave(&room, &user.id);
+        assert!(eventually(|| b.room(&room).members.is_empty()));
+    }
+
+    #[test]
+    #[ignore]
+    fn late_instances_load_existing_rooms() {
+        let a = broker();
+        let room = room();
+        let user = user(&a);
+        a.join(&room, &user);
+        let mut conn = open(&a.client).unwrap();
+        assert!(eventually(|| conn
+            .hexists(members_key(&room), &user.id)
+            .unwrap_or(false)));
+        let b = broker();
+        assert!(b.room(&room).members.contains_key(&user.id));
+        a.leave(&room, &user.id);
+    }
+
+    #[test]
+    #[ignore]
+    fn events_reach_other_instances_only() {
+        let (a, b) = (broker(), broker());
+        let from_a = listen(&a);
+        let from_b = listen(&b);
+        thread::sleep(Duration::from_millis(200));
+        let room = room();
+        a.publish(BrokerEvent::Roomer {
+            room: room.clone(),
+            id: "x".to_owned(),
+        });
+        match from_b.recv_timeout(Duration::from_secs(3)) {
+            Ok(BrokerEvent::Roomer { room: got, id }) => {
+                assert_eq!(got, room);
+                assert_eq!(id, "x");
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(from_a.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn members_of_dead_instances_are_removed() {
+        let (a, b) = (broker(), broker());
+        let room = room();
+        let user = user(&a);
+        a.join(&room, &user);
+        let mut conn = open(&b.client).unwrap();
+        assert!(eventually(|| conn
+            .hexists(members_key(&room), &user.id)
+            .unwrap_or(false)));
+        // a stops renewing its key
+        drop(a);
+        conn.del::<_, ()>(instance_key(instance_of(&user.id)))
+            .unwrap();
+        let rooms = load(&mut conn).unwrap();
+        assert!(rooms.get(&room).is_some_and(|r| r.members.is_empty()));
+        assert!(!conn
+            .hexists::<_, _, bool>(members_key(&room), &user.id)
+            .unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn keys_expire() {
+        let a = broker();
+        let room = room();
+        let user = user(&a);
+        a.join(&room, &user);
+        a.set_roomer(&room, &user.id);
+        let mut conn = open(&a.client).unwrap();
+        assert!(eventually(|| conn.exists(room_key(&room)).unwrap_or(false)));
+        for key in [
+            room_key(&room),
+            members_key(&room),
+            instance_key(a.instance()),
+        ] {
+            let ttl: i64 = conn.ttl(&key).unwrap();
+            assert!(ttl > 0, "{key} has no ttl");
+        }
+        a.leave(&room, &user.id);
+    }
+}
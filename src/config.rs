@@ -0,0 +1,251 @@
+//! Server configuration. Values are layered: built-in defaults, then the
+//! TOML config file, then environment variables, then command line flags.
+
+use std::{fmt, fs, io, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::AccountsConfig, audit::AuditConfig, avatar::AvatarConfig, broker::BrokerConfig,
+    jwt::JwtConfig, lifecycle::LifecycleConfig, limit::RateLimitConfig, party::PartyConfig,
+    recording::RecordingsConfig, tls::TlsConfig, validate::ValidationConfig,
+    webhook::WebhooksConfig,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub workers: usize,
+    /// Directory served as the web root
+    pub static_dir: PathBuf,
+    /// Banned word list for chat messages, one word per line
+    pub filter_words: Option<PathBuf>,
+    /// How often heartbeat pings are sent
+    pub heartbeat_interval_secs: u64,
+    /// How long before lack of client response causes a timeout
+    pub client_timeout_secs: u64,
+    /// Reconnect delay announced to clients on shutdown
+    pub shutdown_reconnect_secs: u64,
+    /// Rooms are flushed to this JSON file on shutdown and loaded back on
+    /// start
+    pub state_file: Option<PathBuf>,
+    pub rate_limit: RateLimitConfig,
+    pub validation: ValidationConfig,
+    /// Serve HTTPS directly instead of behind a proxy
+    pub tls: Option<TlsConfig>,
+    /// Share rooms with other instances
+    pub broker: BrokerConfig,
+    /// Bearer token for the `/admin` API, disabled when unset
+    pub admin_token: Option<String>,
+    /// Registered accounts, guests only when unset
+    pub accounts: Option<AccountsConfig>,
+    /// Identities signed by an embedding site
+    pub jwt: Option<JwtConfig>,
+    /// Uploaded avatar storage
+    pub avatars: AvatarConfig,
+    /// Default room capacity, unlimited when unset
+    pub room_max_members: Option<usize>,
+    /// Spectators per room, unlimited when unset and disabled at 0
+    pub room_max_spectators: Option<usize>,
+    /// Empty room reservation and idle expiry
+    pub lifecycle: LifecycleConfig,
+    /// Scheduled watch parties
+    pub parties: PartyConfig,
+    /// Outgoing room event notifications
+    pub webhooks: WebhooksConfig,
+    /// Audit trail of room actions
+    pub audit: AuditConfig,
+    /// Room timeline recordings for `/replay`
+    pub recordings: RecordingsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "0.0.0.0".to_owned(),
+            port: 8000,
+            workers: 6,
+            static_dir: PathBuf::from("./static"),
+            filter_words: None,
+            heartbeat_interval_secs: 5,
+            client_timeout_secs: 10,
+            shutdown_reconnect_secs: 5,
+            state_file: None,
+            rate_limit: RateLimitConfig::default(),
+            validation: ValidationConfig::default(),
+            tls: None,
+            broker: BrokerConfig::default(),
+            admin_token: None,
+            accounts: None,
+            jwt: None,
+            avatars: AvatarConfig::default(),
+            room_max_members: None,
+            room_max_spectators: None,
+            lifecycle: LifecycleConfig::default(),
+            parties: PartyConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            audit: AuditConfig::default(),
+            recordings: RecordingsConfig::default(),
+        }
+    }
+}
+
+/// Command line flags, each one can also be set through its env var
+#[derive(Debug, Parser)]
+#[command(version, about = "Watch Together server")]
+struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "HOST")]
+    host: Option<String>,
+    #[arg(short, long, env = "PORT")]
+    port: Option<u16>,
+    #[arg(long, env = "WORKERS")]
+    workers: Option<usize>,
+    #[arg(long, env = "STATIC")]
+    static_dir: Option<PathBuf>,
+    #[arg(long, env = "FILTER_WORDS")]
+    filter_words: Option<PathBuf>,
+    /// Only settable through the environment so it stays out of `ps`
+    #[arg(skip)]
+    admin_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {err}", path.display()),
+            ConfigError::Parse(path, err) => write!(f, "invalid config {}: {err}", path.display()),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the config from the process arguments and environment.
+    ///
+    /// Invalid flags or env values exit with a usage message.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut cli = Cli::parse();
+        cli.admin_token = std::env::var("ADMIN_TOKEN").ok();
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path.clone())?,
+            None => Config::default(),
+        };
+        if let Some(host) = cli.host {
+            config.host = host;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(workers) = cli.workers {
+            config.workers = workers;
+        }
+        if let Some(static_dir) = cli.static_dir {
+            config.static_dir = static_dir;
+        }
+        if let Some(filter_words) = cli.filter_words {
+            config.filter_words = Some(filter_words);
+        }
+        if let Some(admin_token) = cli.admin_token {
+            config.admin_token = Some(admin_token);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path, err))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.workers == 0 {
+            return Err(ConfigError::Invalid("workers must be at least 1"));
+        }
+        if self.heartbeat_interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "heartbeat_interval_secs must be at least 1",
+            ));
+        }
+        if self.client_timeout_secs <= self.heartbeat_interval_secs {
+            return Err(ConfigError::Invalid(
+                "client_timeout_secs must be longer than heartbeat_interval_secs",
+            ));
+        }
+        if self.validation.max_lengths().contains(&0) {
+            return Err(ConfigError::Invalid(
+                "validation max lengths must be at least 1",
+            ));
+        }
+        if !self.rate_limit.buckets().iter().all(|b| b.is_valid()) {
+            return Err(ConfigError::Invalid(
+                "rate_limit buckets need a capacity of at least 1 and a positive per_second",
+            ));
+        }
+        if self.rate_limit.strike_window_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "rate_limit.strike_window_secs must be at least 1",
+            ));
+        }
+        if self.admin_token.as_ref().is_some_and(|t| t.len() < 16) {
+            return Err(ConfigError::Invalid(
+                "admin_token must be at least 16 characters",
+            ));
+        }
+        if self
+            .accounts
+            .as_ref()
+            .is_some_and(|a| a.max_failed_signins == 0 || a.max_failed_signins_per_address == 0)
+        {
+            return Err(ConfigError::Invalid(
+                "accounts.max_failed_signins limits must be at least 1",
+            ));
+        }
+        if self.room_max_members == Some(0) {
+            return Err(ConfigError::Invalid("room_max_members must be at least 1"));
+        }
+        if self.avatars.max_uploads_per_hour == 0 {
+            return Err(ConfigError::Invalid(
+                "avatars.max_uploads_per_hour must be at least 1",
+            ));
+        }
+        if self.avatars.sizes.iter().any(|&s| s == 0 || s > 1024) {
+            return Err(ConfigError::Invalid(
+                "avatars.sizes must be between 1 and 1024",
+            ));
+        }
+        if let Some(tls) = &self.tls {
+            if tls.redirect_port == Some(self.port) {
+                return Err(ConfigError::Invalid(
+                    "tls.redirect_port must differ from port",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+}
@@ -28,6 +28,7 @@ where
     }
 }
 
+#[allow(dead_code)]
 pub trait Msg<T>
 where
     T: Serialize,
@@ -80,6 +81,20 @@ pub enum Code {
     Share,
     Speed,
     Members,
+    Direct,
+    Presence,
+    Ready,
+    Countdown,
+    Poll,
+    Shutdown,
+    Directory,
+    Queue,
+    Waiting,
+    Expiring,
+    Party,
+    Webhooks,
+    Recording,
+    Speaker,
 }
 impl Code {
     pub fn code(self) -> i32 {
@@ -91,6 +106,20 @@ impl Code {
             Code::Share => 4,
             Code::Speed => 5,
             Code::Members => 6,
+            Code::Direct => 7,
+            Code::Presence => 8,
+            Code::Ready => 9,
+            Code::Countdown => 10,
+            Code::Poll => 11,
+            Code::Shutdown => 12,
+            Code::Directory => 13,
+            Code::Queue => 14,
+            Code::Waiting => 15,
+            Code::Expiring => 16,
+            Code::Party => 17,
+            Code::Webhooks => 18,
+            Code::Recording => 19,
+            Code::Speaker => 20,
         }
     }
 }
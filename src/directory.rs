@@ -0,0 +1,333 @@
+//! Public room directory. Rooms are private unless their roomer lists them;
+//! only listed rooms ever show up in `/list`, `/directory` or
+//! `GET /api/rooms`.
+
+use std::{cmp::Reverse, collections::HashSet};
+
+use actix::Addr;
+use actix_web::{error, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::server;
+
+const TITLE_MAX_CHARS: usize = 100;
+const DESCRIPTION_MAX_CHARS: usize = 500;
+const TAG_MAX_CHARS: usize = 32;
+const MAX_TAGS: usize = 8;
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+/// Directory metadata of a room
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomMeta {
+    pub public: bool,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// What is currently playing
+    pub media_title: Option<String>,
+}
+
+/// `/room` subcommands, roomer only
+#[derive(Debug, Clone)]
+pub enum RoomCommand {
+    Public(bool),
+    Title(Option<String>),
+    Description(Option<String>),
+    Tags(Vec<String>),
+    Media(Option<String>),
+}
+
+impl RoomCommand {
+    /// `public`, `private`, or a field followed by its value, an empty value
+    /// clears the field
+    pub fn parse(arg: &str) -> Option<RoomCommand> {
+        let (field, value) = arg.split_once(' ').unwrap_or((arg, ""));
+        let value = value.trim();
+        let text = || (!value.is_empty()).then(|| value.to_owned());
+        Some(match field {
+            "public" => RoomCommand::Public(true),
+            "private" => RoomCommand::Public(false),
+            "title" => RoomCommand::Title(text()),
+            "description" => RoomCommand::Description(text()),
+            "media" => RoomCommand::Media(text()),
+            "tags" => RoomCommand::Tags(
+                value
+                    .split(',')
+                    .map(|t| t.trim().to_lowercase())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
+            ),
+            _ => return None,
+        })
+    }
+
+    /// Apply to `meta`, or the `TOO_LONG:field` error
+    pub fn apply(self, meta: &mut RoomMeta) -> Result<(), String> {
+        let check = |field: &str, value: &Option<String>, max: usize| match value {
+            Some(v) if v.chars().count() > max => Err(format!("TOO_LONG:{field}")),
+            _ => Ok(()),
+        };
+        match self {
+            RoomCommand::Public(public) => meta.public = public,
+            RoomCommand::Title(title) => {
+                check("title", &title, TITLE_MAX_CHARS)?;
+                meta.title = title;
+            }
+            RoomCommand::Description(description) => {
+                check("description", &description, DESCRIPTION_MAX_CHARS)?;
+                meta.description = description;
+            }
+            RoomCommand::Media(media) => {
+                check("media", &media, TITLE_MAX_CHARS)?;
+                meta.media_title = media;
+            }
+            RoomCommand::Tags(mut tags) => {
+                if tags.len() > MAX_TAGS {
+                    return Err("TOO_MANY:tags".to_owned());
+                }
+                if tags.iter().any(|t| t.chars().count() > TAG_MAX_CHARS) {
+                    return Err("TOO_LONG:tags".to_owned());
+                }
+                let mut seen = HashSet::new();
+                tags.retain(|t| seen.insert(t.clone()));
+                meta.tags = tags;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// Most members first
+    #[default]
+    Popular,
+    /// Newest rooms first
+    Recent,
+}
+
+impl std::str::FromStr for Sort {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "popular" => Ok(Sort::Popular),
+            "recent" => Ok(Sort::Recent),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DirectoryQuery {
+    /// Matched against name, title, description and tags
+    pub q: Option<String>,
+    pub sort: Sort,
+    /// Starts at 1
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl Default for DirectoryQuery {
+    fn default() -> Self {
+        DirectoryQuery {
+            q: None,
+            sort: Sort::default(),
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomSummary {
+    pub name: String,
+    #[serde(flatten)]
+    pub meta: RoomMeta,
+    pub members: usize,
+    pub spectators: usize,
+    /// Unix milliseconds
+    pub created: u64,
+}
+
+impl RoomSummary {
+    pub fn matches(&self, q: &str) -> bool {
+        let q = q.to_lowercase();
+        let has = |text: &str| text.to_lowercase().contains(&q);
+        has(&self.name)
+            || self.meta.title.as_deref().is_some_and(has)
+            || self.meta.description.as_deref().is_some_and(has)
+            || self.meta.tags.iter().any(|t| has(t))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectoryPage {
+    pub rooms: Vec<RoomSummary>,
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+}
+
+impl DirectoryQuery {
+    /// `/directory` arguments: `sort=popular|recent` and `page=n` options,
+    /// the remaining words are the search, so any room name can be found
+    pub fn parse(arg: &str) -> DirectoryQuery {
+        let mut query = DirectoryQuery::default();
+        let mut words = Vec::new();
+        for word in arg.split_whitespace() {
+            match word.split_once('=') {
+                Some(("sort", sort)) => match sort.parse() {
+                    Ok(sort) => query.sort = sort,
+                    Err(()) => words.push(word),
+                },
+                Some(("page", page)) => match page.parse() {
+                    Ok(page) => query.page = page,
+                    Err(_) => words.push(word),
+                },
+                _ => words.push(word),
+            }
+        }
+        if !words.is_empty() {
+            query.q = Some(words.join(" "));
+        }
+        query
+    }
+
+    /// Filter, sort and cut out the requested page
+    pub fn page(&self, mut rooms: Vec<RoomSummary>) -> DirectoryPage {
+        if let Some(q) = self.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+            rooms.retain(|room| room.matches(q));
+        }
+        match self.sort {
+            Sort::Popular => rooms.sort_by_key(|r| Reverse((r.members, r.created))),
+            Sort::Recent => rooms.sort_by_key(|r| Reverse(r.created)),
+        }
+        let page = self.page.max(1);
+        let per_page = self.per_page.clamp(1, MAX_PER_PAGE);
+        let total = rooms.len();
+        DirectoryPage {
+            rooms: rooms
+                .into_iter()
+                .skip((page - 1).saturating_mul(per_page))
+                .take(per_page)
+                .collect(),
+            page,
+            per_page,
+            total,
+        }
+    }
+}
+
+/// `GET /api/rooms?q=&sort=popular|recent&page=&per_page=`
+pub async fn list(
+    srv: web::Data<Addr<server::ChatServer>>,
+    query: web::Query<DirectoryQuery>,
+) -> Result<HttpResponse, Error> {
+    let page = srv
+        .send(server::Directory {
+            query: query.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(name: &str, members: usize, created: u64) -> RoomSummary {
+        RoomSummary {
+            name: name.to_owned(),
+            meta: RoomMeta {
+                public: true,
+                ..RoomMeta::default()
+            },
+            members,
+            spectators: 0,
+            created,
+        }
+    }
+
+    fn names(page: &DirectoryPage) -> Vec<&str> {
+        page.rooms.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    #[test]
+    fn search_matches_name_title_description_and_tags() {
+        let mut anime = room("a", 1, 1);
+        anime.meta.tags = vec!["anime".to_owned()];
+        let mut movie = room("b", 1, 2);
+        movie.meta.title = Some("Friday MOVIE night".to_owned());
+        let mut docs = room("c", 1, 3);
+        docs.meta.description = Some("nature documentaries".to_owned());
+        let rooms = vec![anime, movie, docs, room("Movies", 1, 4)];
+        let search = |q: &str| {
+            let query = DirectoryQuery {
+                q: Some(q.to_owned()),
+                sort: Sort::Recent,
+                ..DirectoryQuery::default()
+            };
+            let page = query.page(rooms.clone());
+            names(&page).join(",")
+        };
+        assert_eq!(search("movie"), "Movies,b");
+        assert_eq!(search(" ANIME "), "a");
+        assert_eq!(search("nature"), "c");
+        assert_eq!(search("cooking"), "");
+        // blank searches list everything
+        assert_eq!(search("  "), "Movies,c,b,a");
+    }
+
+    #[test]
+    fn rooms_are_sorted_and_paged() {
+        let rooms = vec![room("a", 1, 1), room("b", 3, 2), room("c", 3, 3)];
+        let popular = DirectoryQuery::default().page(rooms.clone());
+        assert_eq!(names(&popular), ["c", "b", "a"]);
+        assert_eq!(popular.total, 3);
+        let recent = DirectoryQuery {
+            sort: Sort::Recent,
+            page: 2,
+            per_page: 2,
+            ..DirectoryQuery::default()
+        }
+        .page(rooms);
+        assert_eq!(names(&recent), ["a"]);
+        assert_eq!(recent.total, 3);
+    }
+
+    #[test]
+    fn parse_takes_explicit_options() {
+        let query = DirectoryQuery::parse("sort=recent page=3 movie night");
+        assert_eq!(query.sort, Sort::Recent);
+        assert_eq!(query.page, 3);
+        assert_eq!(query.q.as_deref(), Some("movie night"));
+        // numbers and sort names are search terms
+        let query = DirectoryQuery::parse("2");
+        assert_eq!(query.page, 1);
+        assert_eq!(query.q.as_deref(), Some("2"));
+        let query = DirectoryQuery::parse("recent page=x");
+        assert_eq!(query.sort, Sort::Popular);
+        assert_eq!(query.q.as_deref(), Some("recent page=x"));
+        assert!(DirectoryQuery::parse("").q.is_none());
+    }
+
+    #[test]
+    fn huge_pages_are_empty() {
+        let query = DirectoryQuery {
+            page: usize::MAX,
+            per_page: usize::MAX,
+            ..DirectoryQuery::default()
+        };
+        let page = query.page(Vec::new());
+        assert!(page.rooms.is_empty());
+        assert_eq!(page.page, usize::MAX);
+        assert_eq!(page.per_page, MAX_PER_PAGE);
+    }
+}
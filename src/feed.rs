@@ -0,0 +1,271 @@
+//! Read-only Server-Sent Events feed of a room at
+//! `GET /api/rooms/{name}/events`. Every broadcast of the room goes out as
+//! one event with the same `[code,data]` payload members receive. The last
+//! events are kept so a client reconnecting with `Last-Event-ID` gets what
+//! it missed. Access is checked again before every send, watchers that may
+//! no longer follow the room or fall too far behind are dropped.
+
+use std::{collections::VecDeque, convert::Infallible, time::Duration};
+
+use actix::Addr;
+use actix_web::{error, web, web::Bytes, Error, HttpRequest, HttpResponse};
+use futures_channel::mpsc::{self, Receiver, Sender};
+
+use crate::{account::Accounts, admin, config::Config, jwt::Verifier, server};
+
+/// Events kept per room for resumption
+const BACKLOG: usize = 200;
+
+/// Chunks a watcher may lag behind before it is dropped, room for the
+/// whole backlog
+const BUFFER: usize = BACKLOG + 56;
+
+/// Feeds following one room at once
+const MAX_WATCHERS: usize = 100;
+
+/// Comment sent to idle feeds so proxies keep them open
+pub const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Milliseconds clients wait before reconnecting
+const RETRY_MS: u64 = 3000;
+
+type Chunk = Result<Bytes, Infallible>;
+
+/// Body of one feed response
+pub type FeedStream = Receiver<Chunk>;
+
+/// Who asks to follow a room
+#[derive(Debug, Clone, Default)]
+pub struct Viewer {
+    /// The request carried the admin token
+    pub admin: bool,
+    /// `account:<id>` or `jwt:<sub>` of the request
+    pub identity: Option<String>,
+}
+
+/// Who may follow a room right now
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    pub public: bool,
+    /// Identities of the members, left empty for public rooms
+    pub members: Vec<String>,
+}
+
+impl Access {
+    pub fn allows(&self, viewer: &Viewer) -> bool {
+        self.public
+            || viewer.admin
+            || viewer
+                .identity
+                .as_ref()
+                .is_some_and(|identity| self.members.contains(identity))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Watcher {
+    viewer: Viewer,
+    tx: Sender<Chunk>,
+}
+
+/// Events of one room and the feeds following it
+#[derive(Debug, Clone, Default)]
+pub struct Feed {
+    last_id: u64,
+    backlog: VecDeque<(u64, String)>,
+    watchers: Vec<Watcher>,
+}
+
+fn event(id: u64, payload: &str) -> Bytes {
+    Bytes::from(format!("id: {id}\ndata: {payload}\n\n"))
+}
+
+impl Feed {
+    pub fn publish(&mut self, payload: &str, access: &Access) {
+        self.last_id += 1;
+        if self.backlog.len() == BACKLOG {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back((self.last_id, payload.to_owned()));
+        self.send(event(self.last_id, payload), access);
+    }
+
+    /// Send to the watchers still allowed in, dropping the others and
+    /// those with a full buffer
+    fn send(&mut self, chunk: Bytes, access: &Access) {
+        self.watchers.retain_mut(|watcher| {
+            access.allows(&watcher.viewer) && watcher.tx.try_send(Ok(chunk.clone())).is_ok()
+        });
+    }
+
+    /// Follow the room, starting with the kept events after `last_seen`.
+    /// `None` when the room has `MAX_WATCHERS` already.
+    pub fn watch(&mut self, viewer: Viewer, last_seen: Option<u64>) -> Option<FeedStream> {
+        self.watchers.retain(|watcher| !watcher.tx.is_closed());
+        if self.watchers.len() >= MAX_WATCHERS {
+            return None;
+        }
+        let (mut tx, rx) = mpsc::channel(BUFFER);
+        let _ = tx.try_send(Ok(Bytes::from(format!("retry: {RETRY_MS}\n\n"))));
+        if let Some(last_seen) = last_seen {
+            // ids restart with the server, an unknown id gets everything kept
+            let last_seen = if last_seen > self.last_id {
+                0
+            } else {
+                last_seen
+            };
+            for (id, payload) in self.backlog.iter().filter(|(id, _)| *id > last_seen) {
+                let _ = tx.try_send(Ok(event(*id, payload)));
+            }
+        }
+        self.watchers.push(Watcher { viewer, tx });
+        Some(rx)
+    }
+
+    /// Ping the feeds and forget the closed ones
+    pub fn keepalive(&mut self, access: &Access) {
+        self.send(Bytes::from_static(b": keepalive\n\n"), access);
+    }
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    NoSuchRoom,
+    /// Private room and the request is not from one of its members
+    Forbidden,
+    /// The room has `MAX_WATCHERS` already
+    TooMany,
+}
+
+/// `GET /api/rooms/{name}/events`. Public rooms are open to anyone; private
+/// ones need the admin token or the account or JWT of someone in the room.
+pub async fn events(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    accounts: Option<web::Data<Accounts>>,
+    verifier: Option<web::Data<Verifier>>,
+) -> Result<HttpResponse, Error> {
+    let claims = verifier.and_then(|v| v.verify(&req, &config.validation).ok().flatten());
+    let identity = match claims {
+        Some(claims) => Some(format!("jwt:{}", claims.sub)),
+        None => accounts
+            .and_then(|a| a.signed_in(&req))
+            .map(|account| format!("account:{}", account.id)),
+    };
+    let last_seen = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let feed = srv
+        .send(server::Watch {
+            room: name.into_inner(),
+            viewer: Viewer {
+                admin: admin::is_admin(&req),
+                identity,
+            },
+            last_seen,
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|err| match err {
+            WatchError::NoSuchRoom => error::ErrorNotFound("ROOM_NOT_EXIST"),
+            WatchError::Forbidden => error::ErrorForbidden("ROOM_PRIVATE"),
+            WatchError::TooMany => error::ErrorTooManyRequests("TOO_MANY_WATCHERS"),
+        })?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(feed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(identity: &str) -> Viewer {
+        Viewer {
+            admin: false,
+            identity: Some(identity.to_owned()),
+        }
+    }
+
+    fn private(members: &[&str]) -> Access {
+        Access {
+            public: false,
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    /// Chunks waiting in `rx`, `None` once the feed dropped it
+    fn drain(rx: &mut FeedStream) -> Option<usize> {
+        let mut n = 0;
+        loop {
+            match rx.try_next() {
+                Ok(Some(_)) => n += 1,
+                Ok(None) => return None,
+                Err(_) => return Some(n),
+            }
+        }
+    }
+
+    #[test]
+    fn access() {
+        assert!(Access {
+            public: true,
+            members: Vec::new()
+        }
+        .allows(&Viewer::default()));
+        assert!(private(&[]).allows(&Viewer {
+            admin: true,
+            identity: None
+        }));
+        assert!(private(&["account:1"]).allows(&member("account:1")));
+        assert!(!private(&["account:1"]).allows(&member("account:2")));
+        assert!(!private(&[]).allows(&Viewer::default()));
+    }
+
+    #[test]
+    fn drops_watchers_no_longer_allowed() {
+        let mut feed = Feed::default();
+        let mut alice = feed.watch(member("account:1"), None).unwrap();
+        let mut bob = feed.watch(member("account:2"), None).unwrap();
+        feed.publish("[1,\"hi\"]", &private(&["account:1", "account:2"]));
+        assert_eq!(drain(&mut alice), Some(2));
+        assert_eq!(drain(&mut bob), Some(2));
+        // bob left the room
+        feed.publish("[1,\"bye\"]", &private(&["account:1"]));
+        assert_eq!(drain(&mut alice), Some(1));
+        assert_eq!(drain(&mut bob), None);
+        feed.keepalive(&private(&[]));
+        assert_eq!(drain(&mut alice), None);
+    }
+
+    #[test]
+    fn drops_lagging_watchers() {
+        let access = private(&["account:1"]);
+        let mut feed = Feed::default();
+        let mut slow = feed.watch(member("account:1"), None).unwrap();
+        for _ in 0..BUFFER + 1 {
+            feed.publish("[1,\"x\"]", &access);
+        }
+        let mut fast = feed.watch(member("account:1"), Some(0)).unwrap();
+        feed.publish("[1,\"x\"]", &access);
+        assert_eq!(drain(&mut slow), None);
+        assert_eq!(drain(&mut fast), Some(BACKLOG + 2));
+    }
+
+    #[test]
+    fn caps_watchers() {
+        let mut feed = Feed::default();
+        let mut feeds: Vec<_> = (0..MAX_WATCHERS)
+            .map(|_| feed.watch(Viewer::default(), None).unwrap())
+            .collect();
+        assert!(feed.watch(Viewer::default(), None).is_none());
+        feeds.pop();
+        assert!(feed.watch(Viewer::default(), None).is_some());
+    }
+}
@@ -0,0 +1,197 @@
+//! Chat content filtering. `ChatServer` runs every room message through a
+//! `ContentFilter` at the strictness chosen by the room.
+
+use std::{fmt::Debug, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// What to do with a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Deliver the masked text instead
+    Mask(String),
+    Reject,
+    /// Deliver unchanged, but report to the roomer
+    Flag,
+}
+
+/// How a room handles messages matching the filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    Off,
+    Flag,
+    #[default]
+    Mask,
+    Block,
+}
+
+impl std::str::FromStr for Strictness {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Strictness::Off),
+            "flag" => Ok(Strictness::Flag),
+            "mask" => Ok(Strictness::Mask),
+            "block" => Ok(Strictness::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+pub trait ContentFilter: Debug + Send {
+    fn check(&self, text: &str, strictness: Strictness) -> Verdict;
+}
+
+/// Filter matching a list of banned words, one per line in the list file.
+///
+/// ASCII words only match on word boundaries, words with other characters
+/// (CJK text has no spaces) match anywhere.
+#[derive(Debug, Clone, Default)]
+pub struct WordList {
+    words: Vec<String>,
+}
+
+impl WordList {
+    pub fn new<I: IntoIterator<Item = String>>(words: I) -> WordList {
+        WordList {
+            words: words
+                .into_iter()
+                .map(|w| w.trim().to_lowercase())
+                .filter(|w| !w.is_empty() && !w.starts_with('#'))
+                .collect(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<WordList> {
+        let text = fs::read_to_string(path)?;
+        Ok(WordList::new(text.lines().map(str::to_owned)))
+    }
+
+    /// Char ranges of `text` covered by banned words
+    fn matches(&self, text: &str) -> Vec<(usize, usize)> {
+        // to_lowercase may expand a char, keep where each lowered one came from
+        let (chars, origin): (Vec<char>, Vec<usize>) = text
+            .chars()
+            .enumerate()
+            .flat_map(|(i, c)| c.to_lowercase().map(move |c| (c, i)))
+            .unzip();
+        let original: Vec<char> = text.chars().collect();
+        let mut ranges = Vec::new();
+        for word in &self.words {
+            let word: Vec<char> = word.chars().collect();
+            let bounded = word.iter().all(char::is_ascii);
+            if word.len() > chars.len() {
+                continue;
+            }
+            for start in 0..=chars.len() - word.len() {
+                let end = start + word.len();
+                if chars[start..end] != word[..] {
+                    continue;
+                }
+                // boundaries are judged on the original chars, a match
+                // starting or ending inside an expanded char is not on one
+                let inside = |k: usize| origin[k - 1] == origin[k];
+                if bounded
+                    && (start > 0
+                        && (inside(start) || original[origin[start - 1]].is_alphanumeric())
+                        || end < chars.len()
+                            && (inside(end) || original[origin[end]].is_alphanumeric()))
+                {
+                    continue;
+                }
+                ranges.push((origin[start], origin[end - 1] + 1));
+            }
+        }
+        ranges
+    }
+}
+
+impl ContentFilter for WordList {
+    fn check(&self, text: &str, strictness: Strictness) -> Verdict {
+        if strictness == Strictness::Off {
+            return Verdict::Allow;
+        }
+        let ranges = self.matches(text);
+        if ranges.is_empty() {
+            return Verdict::Allow;
+        }
+        match strictness {
+            Strictness::Off => Verdict::Allow,
+            Strictness::Flag => Verdict::Flag,
+            Strictness::Block => Verdict::Reject,
+            Strictness::Mask => Verdict::Mask(
+                text.chars()
+                    .enumerate()
+                    .map(|(i, c)| {
+                        if ranges.iter().any(|(s, e)| (*s..*e).contains(&i)) {
+                            '*'
+                        } else {
+                            c
+                        }
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> WordList {
+        WordList::new(
+            ["# comment", " Bad ", "", "坏蛋", "straße"]
+                .into_iter()
+                .map(str::to_owned),
+        )
+    }
+
+    fn mask(text: &str) -> Verdict {
+        list().check(text, Strictness::Mask)
+    }
+
+    #[test]
+    fn ascii_words_match_on_boundaries() {
+        assert_eq!(mask("so BAD!"), Verdict::Mask("so ***!".to_owned()));
+        assert_eq!(mask("badge"), Verdict::Allow);
+        assert_eq!(
+            mask("a bad bad day"),
+            Verdict::Mask("a *** *** day".to_owned())
+        );
+    }
+
+    #[test]
+    fn other_words_match_anywhere() {
+        assert_eq!(mask("你是坏蛋吗"), Verdict::Mask("你是**吗".to_owned()));
+        assert_eq!(
+            mask("STRASSE Straße"),
+            Verdict::Mask("STRASSE ******".to_owned())
+        );
+    }
+
+    #[test]
+    fn expanding_lowercase_keeps_positions() {
+        // "İ" lowers to two chars
+        assert_eq!(mask("İ bad"), Verdict::Mask("İ ***".to_owned()));
+        assert_eq!(mask("İİbad"), Verdict::Allow);
+        assert_eq!(mask("İ坏蛋İ"), Verdict::Mask("İ**İ".to_owned()));
+    }
+
+    #[test]
+    fn comments_are_not_words() {
+        assert_eq!(mask("# comment"), Verdict::Allow);
+    }
+
+    #[test]
+    fn strictness() {
+        let list = list();
+        assert_eq!(list.check("bad", Strictness::Off), Verdict::Allow);
+        assert_eq!(list.check("bad", Strictness::Flag), Verdict::Flag);
+        assert_eq!(list.check("bad", Strictness::Block), Verdict::Reject);
+        assert_eq!(list.check("good", Strictness::Block), Verdict::Allow);
+    }
+}
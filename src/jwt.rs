@@ -0,0 +1,284 @@
+//! JWT identities for embedding into a site with its own login. The site
+//! signs a token for its user and passes it to `/ws?token=...`; the claims
+//! replace `/login`.
+
+use std::{fmt, fs, io, path::PathBuf};
+
+use actix_web::HttpRequest;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::validate::ValidationConfig;
+
+/// Longest `sub` claim accepted
+const MAX_SUB_BYTES: usize = 256;
+
+/// Allowed in `sub` besides ASCII letters and digits, it ends up in URLs
+pub const SUB_PUNCTUATION: &str = "-_.:@|+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret for HS256
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// PEM public key for RS256 and EdDSA
+    #[serde(default)]
+    pub public_key: Option<PathBuf>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Refuse connections without a token instead of letting guests in
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Identity asserted by the embedding site
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// User id on the embedding site
+    pub sub: String,
+    pub name: String,
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// Rooms the user may join, any room when absent
+    #[serde(default)]
+    pub rooms: Option<Vec<String>>,
+    pub exp: u64,
+}
+
+impl Claims {
+    pub fn may_join(&self, room: &str) -> bool {
+        self.rooms
+            .as_ref()
+            .is_none_or(|rooms| rooms.iter().any(|r| r == room))
+    }
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    Missing,
+    Invalid(jsonwebtoken::errors::Error),
+    /// Subject, name or avatar failed validation
+    Claims,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::Missing => write!(f, "missing token"),
+            JwtError::Invalid(err) => write!(f, "invalid token: {err}"),
+            JwtError::Claims => write!(f, "invalid claims"),
+        }
+    }
+}
+
+impl std::error::Error for JwtError {}
+
+pub struct Verifier {
+    key: DecodingKey,
+    validation: Validation,
+    required: bool,
+}
+
+impl Verifier {
+    pub fn new(config: &JwtConfig) -> io::Result<Verifier> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let pem = || match &config.public_key {
+            Some(path) => fs::read(path),
+            None => Err(invalid("jwt.public_key is required".to_owned())),
+        };
+        let (key, algorithm) = match config.algorithm {
+            JwtAlgorithm::HS256 => match &config.secret {
+                Some(secret) => (
+                    DecodingKey::from_secret(secret.as_bytes()),
+                    Algorithm::HS256,
+                ),
+                None => return Err(invalid("jwt.secret is required for HS256".to_owned())),
+            },
+            JwtAlgorithm::RS256 => (
+                DecodingKey::from_rsa_pem(&pem()?).map_err(|e| invalid(e.to_string()))?,
+                Algorithm::RS256,
+            ),
+            JwtAlgorithm::EdDSA => (
+                DecodingKey::from_ed_pem(&pem()?).map_err(|e| invalid(e.to_string()))?,
+                Algorithm::EdDSA,
+            ),
+        };
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Verifier {
+            key,
+            validation,
+            required: config.required,
+        })
+    }
+
+    /// Claims of the request's token, `None` for guests when allowed
+    pub fn verify(
+        &self,
+        req: &HttpRequest,
+        limits: &ValidationConfig,
+    ) -> Result<Option<Claims>, JwtError> {
+        let Some(token) = token(req) else {
+            return if self.required {
+                Err(JwtError::Missing)
+            } else {
+                Ok(None)
+            };
+        };
+        let mut claims = decode::<Claims>(&token, &self.key, &self.validation)
+            .map_err(JwtError::Invalid)?
+            .claims;
+        // the subject becomes the session id
+        if claims.sub.is_empty()
+            || claims.sub.len() > MAX_SUB_BYTES
+            || !claims
+                .sub
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || SUB_PUNCTUATION.contains(c))
+        {
+            return Err(JwtError::Claims);
+        }
+        claims.name = limits.name(&claims.name).map_err(|_| JwtError::Claims)?;
+        claims.avatar = match claims.avatar {
+            Some(avatar) => Some(limits.avatar(&avatar).map_err(|_| JwtError::Claims)?),
+            None => None,
+        };
+        Ok(Some(claims))
+    }
+}
+
+/// Token from the `token` query parameter, browsers cannot set headers on
+/// websockets and `Authorization` already carries account tokens
+fn token(req: &HttpRequest) -> Option<String> {
+    req.query_string().split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == "token" && !value.is_empty()).then(|| value.to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use super::*;
+    use crate::server::now_millis;
+
+    const SECRET: &str = "shared-secret";
+
+    fn verifier(required: bool) -> Verifier {
+        Verifier::new(&JwtConfig {
+            algorithm: JwtAlgorithm::HS256,
+            secret: Some(SECRET.to_owned()),
+            public_key: None,
+            issuer: Some("portal".to_owned()),
+            audience: Some("together".to_owned()),
+            required,
+        })
+        .unwrap()
+    }
+
+    fn token(secret: &str, claims: serde_json::Value) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    fn claims(exp: u64) -> serde_json::Value {
+        serde_json::json!({
+            "sub": "auth0|42",
+            "name": "alice",
+            "rooms": ["r"],
+            "exp": exp,
+            "iss": "portal",
+            "aud": "together",
+        })
+    }
+
+    fn in_an_hour() -> u64 {
+        now_millis() / 1000 + 3600
+    }
+
+    fn verify(verifier: &Verifier, token: Option<&str>) -> Result<Option<Claims>, JwtError> {
+        let uri = match token {
+            Some(token) => format!("/ws?token={token}"),
+            None => "/ws".to_owned(),
+        };
+        let req = TestRequest::get().uri(&uri).to_http_request();
+        verifier.verify(&req, &ValidationConfig::default())
+    }
+
+    #[test]
+    fn valid_tokens_carry_claims() {
+        let token = token(SECRET, claims(in_an_hour()));
+        let claims = verify(&verifier(false), Some(&token)).unwrap().unwrap();
+        assert_eq!(claims.sub, "auth0|42");
+        assert_eq!(claims.name, "alice");
+    }
+
+    #[test]
+    fn guests_only_when_not_required() {
+        assert!(verify(&verifier(false), None).unwrap().is_none());
+        assert!(matches!(
+            verify(&verifier(true), None),
+            Err(JwtError::Missing)
+        ));
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let verifier = verifier(false);
+        let invalid =
+            |token: String| matches!(verify(&verifier, Some(&token)), Err(JwtError::Invalid(_)));
+        assert!(invalid(token("other-secret", claims(in_an_hour()))));
+        assert!(invalid(token(SECRET, claims(now_millis() / 1000 - 3600))));
+        let mut other_issuer = claims(in_an_hour());
+        other_issuer["iss"] = "elsewhere".into();
+        assert!(invalid(token(SECRET, other_issuer)));
+        let mut other_audience = claims(in_an_hour());
+        other_audience["aud"] = "someone-else".into();
+        assert!(invalid(token(SECRET, other_audience)));
+        assert!(invalid("not.a.token".to_owned()));
+    }
+
+    #[test]
+    fn subjects_are_checked() {
+        let verifier = verifier(false);
+        for sub in ["", "a/b", "a b", &"x".repeat(MAX_SUB_BYTES + 1)] {
+            let mut claims = claims(in_an_hour());
+            claims["sub"] = sub.into();
+            let token = token(SECRET, claims);
+            assert!(matches!(
+                verify(&verifier, Some(&token)),
+                Err(JwtError::Claims)
+            ));
+        }
+    }
+
+    #[test]
+    fn rooms_limit_joins() {
+        let mut claims: Claims = serde_json::from_value(claims(0)).unwrap();
+        assert!(claims.may_join("r"));
+        assert!(!claims.may_join("other"));
+        claims.rooms = None;
+        assert!(claims.may_join("other"));
+        claims.rooms = Some(Vec::new());
+        assert!(!claims.may_join("r"));
+    }
+}
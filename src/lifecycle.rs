@@ -0,0 +1,81 @@
+//! Room lifecycle rules, applied by a periodic sweep in `ChatServer`.
+//!
+//! An empty room stays reserved for its last roomer for `empty_grace_secs`,
+//! when the roomer was signed in; rooms of guests are open to anyone.
+//! A room without chat, shares or playback reports for `idle_expiry_hours`
+//! is closed, with a warning `warning_secs` before.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LifecycleConfig {
+    /// How often rooms are checked
+    pub check_interval_secs: u64,
+    pub empty_grace_secs: u64,
+    /// 0 keeps idle rooms forever
+    pub idle_expiry_hours: u64,
+    pub warning_secs: u64,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        LifecycleConfig {
+            check_interval_secs: 30,
+            empty_grace_secs: 300,
+            idle_expiry_hours: 6,
+            warning_secs: 300,
+        }
+    }
+}
+
+impl LifecycleConfig {
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+
+    pub fn empty_grace(&self) -> Duration {
+        Duration::from_secs(self.empty_grace_secs)
+    }
+
+    pub fn idle_expiry(&self) -> Option<Duration> {
+        (self.idle_expiry_hours > 0)
+            .then(|| Duration::from_secs(self.idle_expiry_hours.saturating_mul(3600)))
+    }
+
+    pub fn warning(&self) -> Duration {
+        Duration::from_secs(self.warning_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_disables_idle_expiry() {
+        let config = LifecycleConfig {
+            idle_expiry_hours: 0,
+            ..LifecycleConfig::default()
+        };
+        assert_eq!(config.idle_expiry(), None);
+        let config = LifecycleConfig::default();
+        assert_eq!(config.idle_expiry(), Some(Duration::from_secs(6 * 3600)));
+        let config = LifecycleConfig {
+            idle_expiry_hours: u64::MAX,
+            ..LifecycleConfig::default()
+        };
+        assert_eq!(config.idle_expiry(), Some(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn sweeps_run_at_least_every_second() {
+        let config = LifecycleConfig {
+            check_interval_secs: 0,
+            ..LifecycleConfig::default()
+        };
+        assert_eq!(config.check_interval(), Duration::from_secs(1));
+    }
+}
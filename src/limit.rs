@@ -0,0 +1,340 @@
+//! Token bucket rate limiting for websocket commands.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Burst size and refill rate of a token bucket
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bucket {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+impl Bucket {
+    pub const fn new(capacity: f64, per_second: f64) -> Bucket {
+        Bucket {
+            capacity,
+            per_second,
+        }
+    }
+
+    /// Holds at least one token and refills
+    pub fn is_valid(&self) -> bool {
+        self.capacity.is_finite()
+            && self.capacity >= 1.0
+            && self.per_second.is_finite()
+            && self.per_second > 0.0
+    }
+}
+
+/// Limits for a single session, one bucket per command class plus one
+/// for every frame the session sends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub session: Bucket,
+    pub msg: Bucket,
+    pub share: Bucket,
+    pub speed: Bucket,
+    pub progress: Bucket,
+    /// `/poll` and `/vote`
+    pub poll: Bucket,
+    pub webhook: Bucket,
+    /// `/record` and `/replay`
+    pub record: Bucket,
+    /// Rejected frames tolerated within `strike_window_secs` before the
+    /// session is disconnected
+    pub max_strikes: u32,
+    pub strike_window_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            session: Bucket::new(20.0, 10.0),
+            msg: Bucket::new(5.0, 1.0),
+            share: Bucket::new(2.0, 0.2),
+            speed: Bucket::new(5.0, 2.0),
+            progress: Bucket::new(10.0, 5.0),
+            poll: Bucket::new(5.0, 0.5),
+            webhook: Bucket::new(3.0, 0.1),
+            record: Bucket::new(2.0, 0.05),
+            max_strikes: 20,
+            strike_window_secs: 60,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn buckets(&self) -> [&Bucket; 8] {
+        [
+            &self.session,
+            &self.msg,
+            &self.share,
+            &self.speed,
+            &self.progress,
+            &self.poll,
+            &self.webhook,
+            &self.record,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    Msg,
+    Share,
+    Speed,
+    Progress,
+    Poll,
+    Webhook,
+    Record,
+}
+
+impl CommandClass {
+    pub fn of(command: &str) -> Option<CommandClass> {
+        match command {
+            "/msg" | "/dm" => Some(CommandClass::Msg),
+            "/share" => Some(CommandClass::Share),
+            "/speed" => Some(CommandClass::Speed),
+            "/progress" => Some(CommandClass::Progress),
+            "/poll" | "/vote" => Some(CommandClass::Poll),
+            "/webhook" => Some(CommandClass::Webhook),
+            "/record" | "/replay" => Some(CommandClass::Record),
+            _ => None,
+        }
+    }
+}
+
+/// Frame rejected because a bucket ran dry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    Session,
+    Command(CommandClass),
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimited::Session => write!(f, "RATE_LIMITED"),
+            RateLimited::Command(class) => {
+                write!(f, "RATE_LIMITED:{}", format!("{class:?}").to_lowercase())
+            }
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Debug)]
+struct TokenBucket {
+    bucket: Bucket,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(bucket: Bucket) -> TokenBucket {
+        TokenBucket {
+            bucket,
+            tokens: bucket.capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bucket.per_second).min(self.bucket.capacity);
+        self.last = now;
+    }
+}
+
+/// Per session limiter state
+#[derive(Debug)]
+pub struct RateLimiter {
+    session: TokenBucket,
+    msg: TokenBucket,
+    share: TokenBucket,
+    speed: TokenBucket,
+    progress: TokenBucket,
+    poll: TokenBucket,
+    webhook: TokenBucket,
+    record: TokenBucket,
+    max_strikes: u32,
+    strike_window: Duration,
+    strikes: u32,
+    window_start: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            session: TokenBucket::new(config.session),
+            msg: TokenBucket::new(config.msg),
+            share: TokenBucket::new(config.share),
+            speed: TokenBucket::new(config.speed),
+            progress: TokenBucket::new(config.progress),
+            poll: TokenBucket::new(config.poll),
+            webhook: TokenBucket::new(config.webhook),
+            record: TokenBucket::new(config.record),
+            max_strikes: config.max_strikes,
+            strike_window: Duration::from_secs(config.strike_window_secs),
+            strikes: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Take a token for `command`, both from the session bucket and from
+    /// the bucket of its class.
+    pub fn check(&mut self, command: &str) -> Result<(), RateLimited> {
+        let class = CommandClass::of(command);
+        self.session.refill();
+        let bucket = class.map(|class| {
+            let bucket = match class {
+                CommandClass::Msg => &mut self.msg,
+                CommandClass::Share => &mut self.share,
+                CommandClass::Speed => &mut self.speed,
+                CommandClass::Progress => &mut self.progress,
+                CommandClass::Poll => &mut self.poll,
+                CommandClass::Webhook => &mut self.webhook,
+                CommandClass::Record => &mut self.record,
+            };
+            bucket.refill();
+            (class, bucket)
+        });
+        let result = if self.session.tokens < 1.0 {
+            Err(RateLimited::Session)
+        } else {
+            match bucket {
+                Some((class, bucket)) if bucket.tokens < 1.0 => Err(RateLimited::Command(class)),
+                Some((_, bucket)) => {
+                    bucket.tokens -= 1.0;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        };
+        match result {
+            Ok(()) => self.session.tokens -= 1.0,
+            Err(_) => self.strike(),
+        }
+        result
+    }
+
+    fn strike(&mut self) {
+        if self.window_start.elapsed() > self.strike_window {
+            self.window_start = Instant::now();
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+    }
+
+    /// Whether the session kept flooding after being rejected
+    pub fn is_offender(&self) -> bool {
+        self.strikes > self.max_strikes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            session: Bucket::new(10.0, 1.0),
+            msg: Bucket::new(2.0, 1.0),
+            max_strikes: 2,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(CommandClass::of("/dm"), Some(CommandClass::Msg));
+        assert_eq!(CommandClass::of("/vote"), Some(CommandClass::Poll));
+        assert_eq!(CommandClass::of("/replay"), Some(CommandClass::Record));
+        assert_eq!(CommandClass::of("/webhook"), Some(CommandClass::Webhook));
+        assert_eq!(CommandClass::of("/join"), None);
+    }
+
+    #[test]
+    fn class_bucket_runs_dry() {
+        let mut limiter = RateLimiter::new(&config());
+        assert!(limiter.check("/msg").is_ok());
+        assert!(limiter.check("/msg").is_ok());
+        assert_eq!(
+            limiter.check("/msg"),
+            Err(RateLimited::Command(CommandClass::Msg))
+        );
+        // other commands still go through the session bucket
+        assert!(limiter.check("/join").is_ok());
+    }
+
+    #[test]
+    fn session_bucket_runs_dry() {
+        let mut limiter = RateLimiter::new(&config());
+        for _ in 0..10 {
+            assert!(limiter.check("/members").is_ok());
+        }
+        assert_eq!(limiter.check("/members"), Err(RateLimited::Session));
+    }
+
+    #[test]
+    fn rejected_frames_take_no_token() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            session: Bucket::new(10.0, 0.0),
+            ..config()
+        });
+        limiter.check("/msg").unwrap();
+        limiter.check("/msg").unwrap();
+        let before = limiter.session.tokens;
+        assert!(limiter.check("/msg").is_err());
+        assert_eq!(limiter.session.tokens, before);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(&config());
+        limiter.check("/msg").unwrap();
+        limiter.check("/msg").unwrap();
+        limiter.msg.last -= Duration::from_millis(1500);
+        assert!(limiter.check("/msg").is_ok());
+        assert!(limiter.check("/msg").is_err());
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let mut bucket = TokenBucket::new(Bucket::new(3.0, 100.0));
+        bucket.last -= Duration::from_secs(60);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn offenders() {
+        let mut limiter = RateLimiter::new(&config());
+        limiter.check("/msg").unwrap();
+        limiter.check("/msg").unwrap();
+        for _ in 0..2 {
+            assert!(limiter.check("/msg").is_err());
+            assert!(!limiter.is_offender());
+        }
+        assert!(limiter.check("/msg").is_err());
+        assert!(limiter.is_offender());
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(RateLimited::Session.to_string(), "RATE_LIMITED");
+        assert_eq!(
+            RateLimited::Command(CommandClass::Progress).to_string(),
+            "RATE_LIMITED:progress"
+        );
+    }
+}
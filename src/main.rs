@@ -1,6 +1,6 @@
 use std::{
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::Instant,
@@ -13,15 +13,30 @@ use actix_web::{
 };
 use actix_web_actors::ws;
 
+mod account;
+mod admin;
+mod audit;
+mod avatar;
+mod broker;
+mod config;
 mod context;
+mod directory;
+mod feed;
+mod filter;
+mod jwt;
+mod lifecycle;
+mod limit;
+mod party;
+mod poll;
+mod recording;
 mod server;
 mod session;
+mod tls;
+mod validate;
+mod webhook;
 
-async fn index() -> impl Responder {
-    let static_path = std::env::var("STATIC").unwrap_or("./static".to_owned());
-    NamedFile::open_async(static_path + "/index.html")
-        .await
-        .unwrap()
+async fn index(config: web::Data<config::Config>) -> impl Responder {
+    NamedFile::open_async(config.static_dir.join("index.html")).await
 }
 
 /// Entry point for our websocket route
@@ -29,17 +44,53 @@ async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
+    shutting_down: web::Data<AtomicBool>,
+    accounts: Option<web::Data<account::Accounts>>,
+    verifier: Option<web::Data<jwt::Verifier>>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
+    if shutting_down.load(Ordering::SeqCst) {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+    let claims = match &verifier {
+        Some(verifier) => verifier
+            .verify(&req, &config.validation)
+            .map_err(actix_web::error::ErrorUnauthorized)?,
+        None => None,
+    };
+    if let Some(claims) = &claims {
+        // registered nicknames stay with their accounts
+        if accounts
+            .as_ref()
+            .is_some_and(|a| a.is_reserved(&claims.name, None))
+        {
+            return Err(actix_web::error::ErrorConflict("NAME_RESERVED"));
+        }
+        log::info!(
+            "websocket for embedded user {} ({})",
+            claims.sub,
+            claims.name
+        );
+    }
+    // signed in users connect with their account, everyone else as a guest
+    let account = accounts.as_ref().and_then(|a| a.signed_in(&req));
+    ws::WsResponseBuilder::new(
         session::WsChatSession {
             id: "".to_string(),
             hb: Instant::now(),
             room: "".to_owned(), //Empty Room
             addr: srv.get_ref().clone(),
+            limiter: limit::RateLimiter::new(&config.rate_limit),
+            config: config.clone().into_inner(),
+            account,
+            accounts,
+            claims,
         },
         &req,
         stream,
     )
+    .frame_size(config.validation.max_frame_bytes)
+    .start()
 }
 
 /// Displays state
@@ -52,28 +103,172 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+    };
+
     // set up applications state
     // keep a count of the number of visitors
     let app_state = Arc::new(AtomicUsize::new(0));
 
+    // banned word list for chat messages, one word per line
+    let words = match &config.filter_words {
+        Some(path) => filter::WordList::load(path)?,
+        None => filter::WordList::default(),
+    };
+
+    let accounts = match &config.accounts {
+        Some(accounts) => Some(web::Data::new(account::Accounts::load(accounts.clone())?)),
+        None => None,
+    };
+
+    let verifier = match &config.jwt {
+        Some(jwt) => Some(web::Data::new(jwt::Verifier::new(jwt)?)),
+        None => None,
+    };
+
+    let parties = party::Schedule::load(&config.parties)?;
+
+    let webhooks =
+        webhook::Webhooks::new(config.webhooks.clone()).map_err(std::io::Error::other)?;
+
+    let audit = audit::AuditLog::open(&config.audit)?;
+
+    let broker = broker::connect(&config.broker).map_err(std::io::Error::other)?;
+
     // start chat server actor
-    let server = server::ChatServer::new(app_state.clone()).start();
-
-    log::info!("starting HTTP server at http://127.0.0.1:8000");
-    let port = std::env::var("PORT").unwrap_or("8000".to_owned()).parse::<u16>().unwrap();
-    HttpServer::new(move || {
-        let static_path = std::env::var("STATIC").unwrap_or("./static".to_owned());
-        App::new()
-            .app_data(web::Data::from(app_state.clone()))
+    let server = server::ChatServer::new(
+        app_state.clone(),
+        Box::new(words),
+        config.state_file.clone(),
+        broker,
+        config.room_max_members,
+        config.room_max_spectators,
+        config.lifecycle.clone(),
+        parties,
+        webhooks,
+        audit,
+        config.recordings.clone(),
+    )
+    .start();
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let reconnect_secs = config.shutdown_reconnect_secs;
+    let (host, port, workers) = (config.host.clone(), config.port, config.workers);
+    let tls = match &config.tls {
+        Some(tls) => {
+            if let Some(redirect_port) = tls.redirect_port {
+                log::info!("redirecting http://{host}:{redirect_port} to HTTPS");
+                let redirect = tls::redirect_server(host.clone(), redirect_port, port)?;
+                actix_web::rt::spawn(redirect);
+            }
+            Some(tls::server_config(tls)?)
+        }
+        None => None,
+    };
+    let admin = config.admin_token.is_some();
+    let avatars = config.avatars.clone();
+    let uploads = web::Data::new(avatar::Uploads::default());
+    let config = web::Data::new(config);
+
+    let chat = server.clone();
+    let flag = shutting_down.clone();
+    let server = HttpServer::new(move || {
+        let mut app = App::new();
+        if let Some(accounts) = &accounts {
+            app = app.app_data(accounts.clone());
+        }
+        if let Some(verifier) = &verifier {
+            app = app.app_data(verifier.clone());
+        }
+        app.app_data(web::Data::from(app_state.clone()))
+            .app_data(web::Data::from(shutting_down.clone()))
             .app_data(web::Data::new(server.clone()))
+            .app_data(config.clone())
             .service(web::resource("/").to(index))
             .route("/count", web::get().to(get_count))
+            .route("/api/rooms", web::get().to(directory::list))
+            .route("/api/rooms/{name}/events", web::get().to(feed::events))
+            .configure(party::routes)
             .route("/ws", web::get().to(chat_route))
-            .service(Files::new("/", static_path))
-            .wrap(Logger::default())
+            .configure(|cfg| {
+                avatar::routes(cfg, &avatars, uploads.clone());
+                if admin {
+                    admin::routes(cfg)
+                }
+                if accounts.is_some() {
+                    account::routes(cfg)
+                }
+            })
+            .service(Files::new("/", &config.static_dir))
+            // path only, query strings may carry tokens
+            .wrap(Logger::new(
+                r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
+            ))
     })
-    .workers(6)
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    .workers(workers)
+    .disable_signals();
+    let server = match tls {
+        Some(tls) => {
+            log::info!("starting HTTPS server at https://{host}:{port}");
+            server.bind_rustls((host, port), tls)?
+        }
+        None => {
+            log::info!("starting HTTP server at http://{host}:{port}");
+            server.bind((host, port))?
+        }
+    };
+    let server = server.run();
+    shutdown_on_signal(server.handle(), chat, flag, reconnect_secs);
+    server.await
+}
+
+/// Run the shutdown sequence on the first SIGINT or SIGTERM
+fn shutdown_on_signal(
+    handle: actix_web::dev::ServerHandle,
+    chat: Addr<server::ChatServer>,
+    shutting_down: Arc<AtomicBool>,
+    reconnect_secs: u64,
+) {
+    use actix_web::rt::{self, signal};
+
+    #[cfg(unix)]
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut term) => {
+            let (handle, chat, shutting_down) =
+                (handle.clone(), chat.clone(), shutting_down.clone());
+            rt::spawn(async move {
+                if term.recv().await.is_some() {
+                    shutdown(handle, chat, shutting_down, reconnect_secs).await;
+                }
+            });
+        }
+        Err(err) => log::error!("cannot listen for SIGTERM: {err}"),
+    }
+    rt::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            shutdown(handle, chat, shutting_down, reconnect_secs).await;
+        }
+    });
+}
+
+/// Refuse new websockets, let the chat server notify and close every
+/// session, then stop the HTTP server.
+async fn shutdown(
+    handle: actix_web::dev::ServerHandle,
+    chat: Addr<server::ChatServer>,
+    shutting_down: Arc<AtomicBool>,
+    reconnect_secs: u64,
+) {
+    if shutting_down.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    log::info!("shutting down");
+    if let Err(err) = chat.send(server::Shutdown { reconnect_secs }).await {
+        log::error!("chat server did not shut down cleanly: {err}");
+    }
+    handle.stop(true).await;
 }
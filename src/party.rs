@@ -0,0 +1,490 @@
+//! Scheduled watch parties. A party names a room, a media link and a start
+//! time; the schedule is kept in a JSON file. `ChatServer` opens the room
+//! `open_before_mins` ahead, keeps everyone in it informed and starts
+//! playback at the scheduled server time.
+
+use std::{fs, io, path::PathBuf, time::Duration};
+
+use actix::Addr;
+use actix_web::{cookie::time::OffsetDateTime, error, web, Error, HttpRequest, HttpResponse};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{account::Accounts, admin, config::Config, server};
+
+/// How far ahead a party may be scheduled
+const MAX_AHEAD: Duration = Duration::from_secs(366 * 24 * 3600);
+
+/// Longest calendar line in octets, longer ones are folded
+const ICS_LINE_OCTETS: usize = 75;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartyConfig {
+    /// Where the schedule is kept, in memory only when unset
+    pub file: Option<PathBuf>,
+    /// How long before the start the room opens
+    pub open_before_mins: u64,
+    /// Upcoming parties one account may have scheduled, the admin is not
+    /// limited
+    pub max_per_organizer: usize,
+}
+
+impl Default for PartyConfig {
+    fn default() -> Self {
+        PartyConfig {
+            file: Some(PathBuf::from("./parties.json")),
+            open_before_mins: 30,
+            max_per_organizer: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Party {
+    pub id: String,
+    pub room: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Shared to the room when it opens
+    pub media: String,
+    /// Unix milliseconds
+    pub start_at: u64,
+    /// Length used for the calendar entry
+    pub duration_mins: u64,
+    /// Listed in the room directory and `GET /api/parties`
+    pub public: bool,
+    /// `admin` or the owner key of the account that scheduled it
+    pub organizer: String,
+    /// The room has been opened by this process
+    #[serde(skip)]
+    pub opened: bool,
+    /// Smallest reminder threshold already announced, in seconds
+    #[serde(skip)]
+    pub announced: Option<u64>,
+    /// The start countdown went out
+    #[serde(skip)]
+    pub counting_down: bool,
+}
+
+/// A party as anyone may see it, without who organized it
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicParty<'a> {
+    pub id: &'a str,
+    pub room: &'a str,
+    pub title: &'a str,
+    pub description: Option<&'a str>,
+    pub media: &'a str,
+    pub start_at: u64,
+    pub duration_mins: u64,
+    pub public: bool,
+}
+
+/// What members of a party room are sent, times are server unix milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct PartyInfo<'a> {
+    pub id: &'a str,
+    pub title: &'a str,
+    pub media: &'a str,
+    pub start_at: u64,
+    pub now: u64,
+}
+
+/// Upcoming parties backed by the schedule file
+#[derive(Debug, Default)]
+pub struct Schedule {
+    file: Option<PathBuf>,
+    pub open_before: Duration,
+    pub max_per_organizer: usize,
+    pub parties: Vec<Party>,
+}
+
+impl Schedule {
+    pub fn load(config: &PartyConfig) -> io::Result<Schedule> {
+        let parties = match &config.file {
+            Some(path) => match fs::read(path) {
+                Ok(json) => serde_json::from_slice(&json)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err),
+            },
+            None => Vec::new(),
+        };
+        Ok(Schedule {
+            file: config.file.clone(),
+            open_before: Duration::from_secs(config.open_before_mins.saturating_mul(60)),
+            max_per_organizer: config.max_per_organizer,
+            parties,
+        })
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.parties)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(err) = result {
+            log::error!("failed to save parties to {}: {err}", path.display());
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Party> {
+        self.parties.iter().find(|p| p.id == id)
+    }
+
+    /// The party a room is waiting for
+    pub fn for_room(&self, room: &str) -> Option<&Party> {
+        self.parties
+            .iter()
+            .filter(|p| p.room == room)
+            .min_by_key(|p| p.start_at)
+    }
+
+    /// Whether `organizer` may schedule another party
+    pub fn allows(&self, organizer: &str) -> bool {
+        organizer == "admin"
+            || self
+                .parties
+                .iter()
+                .filter(|p| p.organizer == organizer)
+                .count()
+                < self.max_per_organizer
+    }
+}
+
+/// iCalendar UTC timestamp
+fn ics_time(millis: u64) -> String {
+    let t = OffsetDateTime::from_unix_timestamp((millis / 1000) as i64)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        t.year(),
+        t.month() as u8,
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+fn ics_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Split a content line into lines of at most 75 octets, continuations
+/// start with a space (RFC 5545, 3.1)
+fn ics_fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > ICS_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+/// Link that opens `room`, the name encoded as a query value
+pub fn join_url(scheme: &str, host: &str, room: &str) -> String {
+    let base = format!("{scheme}://{host}/");
+    match reqwest::Url::parse(&base) {
+        Ok(mut url) => {
+            url.query_pairs_mut().append_pair("room", room);
+            url.into()
+        }
+        Err(_) => base,
+    }
+}
+
+impl Party {
+    pub fn public(&self) -> PublicParty<'_> {
+        PublicParty {
+            id: &self.id,
+            room: &self.room,
+            title: &self.title,
+            description: self.description.as_deref(),
+            media: &self.media,
+            start_at: self.start_at,
+            duration_mins: self.duration_mins,
+            public: self.public,
+        }
+    }
+
+    pub fn info(&self, now: u64) -> PartyInfo<'_> {
+        PartyInfo {
+            id: &self.id,
+            title: &self.title,
+            media: &self.media,
+            start_at: self.start_at,
+            now,
+        }
+    }
+
+    /// Single event calendar, `url` is where the room can be joined
+    pub fn ics(&self, url: &str, now: u64) -> String {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_owned(),
+            "VERSION:2.0".to_owned(),
+            "PRODID:-//Together//Watch Party//EN".to_owned(),
+            "BEGIN:VEVENT".to_owned(),
+            format!("UID:{}@together", self.id),
+            format!("DTSTAMP:{}", ics_time(now)),
+            format!("DTSTART:{}", ics_time(self.start_at)),
+            format!(
+                "DTEND:{}",
+                ics_time(
+                    self.start_at
+                        .saturating_add(self.duration_mins.saturating_mul(60_000))
+                )
+            ),
+            format!("SUMMARY:{}", ics_escape(&self.title)),
+            format!("URL:{url}"),
+        ];
+        if let Some(description) = &self.description {
+            lines.push(format!("DESCRIPTION:{}", ics_escape(description)));
+        }
+        lines.push("END:VEVENT".to_owned());
+        lines.push("END:VCALENDAR".to_owned());
+        let lines: Vec<String> = lines.iter().map(|line| ics_fold(line)).collect();
+        lines.join("\r\n") + "\r\n"
+    }
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/parties")
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{id}.ics", web::get().to(calendar))
+            .route("/{id}", web::get().to(get))
+            .route("/{id}", web::delete().to(cancel)),
+    );
+}
+
+type Server = web::Data<Addr<server::ChatServer>>;
+
+/// `admin` for the admin token, the owner key of a signed in account, or
+/// `None` when the request may not organize parties
+fn organizer(req: &HttpRequest) -> Option<String> {
+    if admin::is_admin(req) {
+        return Some("admin".to_owned());
+    }
+    let accounts = req.app_data::<web::Data<Accounts>>()?;
+    accounts
+        .signed_in(req)
+        .map(|account| format!("account:{}", account.id))
+}
+
+#[derive(Deserialize)]
+struct NewParty {
+    room: String,
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    media: String,
+    /// Unix seconds
+    start_at: u64,
+    #[serde(default = "default_duration")]
+    duration_mins: u64,
+    #[serde(default = "default_public")]
+    public: bool,
+}
+
+fn default_duration() -> u64 {
+    120
+}
+
+fn default_public() -> bool {
+    true
+}
+
+async fn create(
+    req: HttpRequest,
+    srv: Server,
+    config: web::Data<Config>,
+    body: web::Json<NewParty>,
+) -> Result<HttpResponse, Error> {
+    let organizer = organizer(&req).ok_or_else(|| error::ErrorUnauthorized("sign in first"))?;
+    let body = body.into_inner();
+    let limits = &config.validation;
+    let bad = |err: crate::validate::Invalid| error::ErrorBadRequest(err.to_string());
+    let start_at = body.start_at.saturating_mul(1000);
+    let now = server::now_millis();
+    if start_at <= now {
+        return Err(error::ErrorBadRequest("START_IN_PAST"));
+    }
+    if start_at - now > MAX_AHEAD.as_millis() as u64 {
+        return Err(error::ErrorBadRequest("START_TOO_FAR"));
+    }
+    let party = Party {
+        id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+        room: limits.room(&body.room).map_err(bad)?,
+        title: limits.msg(&body.title).map_err(bad)?,
+        description: match body.description {
+            Some(d) => Some(limits.msg(&d).map_err(bad)?),
+            None => None,
+        },
+        media: limits.link(&body.media).map_err(bad)?,
+        start_at,
+        duration_mins: body.duration_mins.clamp(1, 24 * 60),
+        public: body.public,
+        organizer,
+        opened: false,
+        announced: None,
+        counting_down: false,
+    };
+    let party = srv
+        .send(server::ScheduleParty(party))
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|err| match err {
+            server::PartyRejected::RoomTaken => error::ErrorConflict("ROOM_TAKEN"),
+            server::PartyRejected::TooMany => error::ErrorTooManyRequests("TOO_MANY_PARTIES"),
+        })?;
+    Ok(HttpResponse::Created().json(party))
+}
+
+/// Upcoming public parties
+async fn list(srv: Server) -> Result<HttpResponse, Error> {
+    let parties = srv
+        .send(server::ListParties)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let parties: Vec<PublicParty> = parties.iter().map(Party::public).collect();
+    Ok(HttpResponse::Ok().json(parties))
+}
+
+async fn find(srv: &Server, id: String) -> Result<Party, Error> {
+    srv.send(server::GetParty { id })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or_else(|| error::ErrorNotFound("no such party"))
+}
+
+async fn get(srv: Server, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let party = find(&srv, id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(party.public()))
+}
+
+async fn calendar(
+    req: HttpRequest,
+    srv: Server,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let party = find(&srv, id.into_inner()).await?;
+    let info = req.connection_info();
+    let url = join_url(info.scheme(), info.host(), &party.room);
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.ics\"", party.id),
+        ))
+        .body(party.ics(&url, server::now_millis())))
+}
+
+async fn cancel(
+    req: HttpRequest,
+    srv: Server,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let organizer = organizer(&req).ok_or_else(|| error::ErrorUnauthorized("sign in first"))?;
+    let party = find(&srv, id.into_inner()).await?;
+    if organizer != "admin" && organizer != party.organizer {
+        return Err(error::ErrorForbidden("not the organizer"));
+    }
+    srv.send(server::CancelParty { id: party.id })
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn party() -> Party {
+        Party {
+            id: "p1".to_owned(),
+            room: "movie night".to_owned(),
+            title: "Movie, night; part\\2".to_owned(),
+            description: Some("line one\nline two".to_owned()),
+            media: "https://example.com/a.mp4".to_owned(),
+            start_at: 1_700_000_000_000,
+            duration_mins: 90,
+            public: true,
+            organizer: "account:42".to_owned(),
+            opened: false,
+            announced: None,
+            counting_down: false,
+        }
+    }
+
+    #[test]
+    fn calendar_entries_are_escaped() {
+        let ics = party().ics("https://example.com/?room=movie+night", 1_699_000_000_000);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("\r\nUID:p1@together\r\n"));
+        assert!(ics.contains("\r\nDTSTART:20231114T221320Z\r\n"));
+        assert!(ics.contains("\r\nDTEND:20231114T234320Z\r\n"));
+        assert!(ics.contains(r"SUMMARY:Movie\, night\; part\\2"));
+        assert!(ics.contains("\r\nDESCRIPTION:line one\\nline two\r\n"));
+        assert!(!ics.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn long_lines_are_folded() {
+        let mut party = party();
+        party.title = "影".repeat(60);
+        let ics = party.ics("https://example.com/", 0);
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= ICS_LINE_OCTETS, "{line:?}");
+        }
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(&format!("SUMMARY:{}\r\n", "影".repeat(60))));
+        assert_eq!(ics_fold("short"), "short");
+    }
+
+    #[test]
+    fn join_urls_encode_the_room() {
+        assert_eq!(
+            join_url("https", "example.com", "a b&c#d"),
+            "https://example.com/?room=a+b%26c%23d"
+        );
+        assert_eq!(
+            join_url("http", "localhost:8080", "房间"),
+            "http://localhost:8080/?room=%E6%88%BF%E9%97%B4"
+        );
+    }
+
+    #[test]
+    fn public_view_hides_the_organizer() {
+        let json = serde_json::to_value(party().public()).unwrap();
+        assert!(json.get("organizer").is_none());
+        assert_eq!(json["room"], "movie night");
+    }
+
+    #[test]
+    fn organizers_are_limited() {
+        let mut schedule = Schedule {
+            max_per_organizer: 1,
+            ..Schedule::default()
+        };
+        assert!(schedule.allows("account:42"));
+        schedule.parties.push(party());
+        assert!(!schedule.allows("account:42"));
+        assert!(schedule.allows("account:7"));
+        assert!(schedule.allows("admin"));
+    }
+}
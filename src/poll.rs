@@ -0,0 +1,226 @@
+//! Room polls. Generic polls just collect votes, governance polls
+//! (skip, seek, transfer) take effect once enough members vote yes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How long a poll stays open when no duration is given
+pub const DEFAULT_POLL_DURATION: Duration = Duration::from_secs(60);
+
+/// Longest duration a poll can be created with
+pub const MAX_POLL_DURATION: Duration = Duration::from_secs(24 * 3600);
+
+/// Governance polls pass with more than this share of the voters saying yes
+pub const DEFAULT_THRESHOLD: f64 = 0.5;
+
+/// Yes votes every governance poll needs, so a proposer cannot pass one alone
+pub const MIN_YES_VOTES: usize = 2;
+
+/// Governance polls open at once in a room
+pub const MAX_GOVERNANCE_POLLS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type", content = "value")]
+pub enum PollKind {
+    Custom,
+    /// Skip the current queue item
+    Skip,
+    /// Seek to a position in seconds
+    Seek(f64),
+    /// Transfer room ownership to a member
+    Transfer(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub id: u32,
+    pub creator: String,
+    pub kind: PollKind,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Voter id -> option index
+    pub votes: HashMap<String, usize>,
+    /// Unix milliseconds
+    pub deadline: u64,
+}
+
+/// Poll snapshot broadcast with `Code::Poll`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollState {
+    pub id: u32,
+    pub kind: PollKind,
+    pub question: String,
+    pub options: Vec<String>,
+    pub tally: Vec<usize>,
+    pub deadline: u64,
+    pub closed: bool,
+    pub passed: Option<bool>,
+}
+
+impl Poll {
+    pub fn tally(&self) -> Vec<usize> {
+        let mut tally = vec![0; self.options.len()];
+        for option in self.votes.values() {
+            tally[*option] += 1;
+        }
+        tally
+    }
+
+    /// Governance polls pass when more than `threshold` of the voters, and
+    /// at least `MIN_YES_VOTES`, chose "yes" (index 0). Voters are the
+    /// current `members` except the one a transfer would make roomer.
+    pub fn passed(&self, members: &HashSet<String>, threshold: f64) -> bool {
+        let beneficiary = match &self.kind {
+            PollKind::Custom => return false,
+            PollKind::Transfer(to) => Some(to),
+            PollKind::Skip | PollKind::Seek(_) => None,
+        };
+        let may_vote = |id: &String| members.contains(id) && Some(id) != beneficiary;
+        let voters = members.iter().filter(|id| may_vote(id)).count();
+        let yes = self
+            .votes
+            .iter()
+            .filter(|(id, option)| **option == 0 && may_vote(id))
+            .count();
+        let majority = ((voters as f64 * threshold).floor() as usize + 1).min(voters);
+        yes >= majority.max(MIN_YES_VOTES)
+    }
+
+    pub fn state(&self, closed: bool, passed: Option<bool>) -> PollState {
+        PollState {
+            id: self.id,
+            kind: self.kind.clone(),
+            question: self.question.clone(),
+            options: self.options.clone(),
+            tally: self.tally(),
+            deadline: self.deadline,
+            closed,
+            passed,
+        }
+    }
+}
+
+/// Open polls of a room
+#[derive(Debug, Clone)]
+pub struct Polls {
+    pub next_id: u32,
+    pub open: HashMap<u32, Poll>,
+    pub threshold: f64,
+}
+
+impl Default for Polls {
+    fn default() -> Self {
+        Polls {
+            next_id: 1,
+            open: HashMap::new(),
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl Polls {
+    /// Open skip, seek and transfer polls
+    pub fn governance(&self) -> usize {
+        self.open
+            .values()
+            .filter(|poll| poll.kind != PollKind::Custom)
+            .count()
+    }
+
+    pub fn create(
+        &mut self,
+        creator: String,
+        kind: PollKind,
+        question: String,
+        options: Vec<String>,
+        duration: Duration,
+    ) -> &Poll {
+        let id = self.next_id;
+        self.next_id += 1;
+        let deadline = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|d| d.checked_add(duration))
+            .map(|d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+            .unwrap_or_default();
+        self.open.entry(id).or_insert(Poll {
+            id,
+            creator,
+            kind,
+            question,
+            options,
+            votes: HashMap::new(),
+            deadline,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn poll(kind: PollKind, votes: &[(&str, usize)]) -> Poll {
+        let mut polls = Polls::default();
+        let options = vec!["yes".to_owned(), "no".to_owned()];
+        let mut poll = polls
+            .create(
+                "a".to_owned(),
+                kind,
+                "?".to_owned(),
+                options,
+                DEFAULT_POLL_DURATION,
+            )
+            .clone();
+        poll.votes = votes.iter().map(|(id, o)| (id.to_string(), *o)).collect();
+        poll
+    }
+
+    #[test]
+    fn tally_counts_each_option() {
+        let poll = poll(PollKind::Skip, &[("a", 0), ("b", 1), ("c", 0)]);
+        assert_eq!(poll.tally(), [2, 1]);
+        assert_eq!(self::poll(PollKind::Skip, &[]).tally(), [0, 0]);
+    }
+
+    #[test]
+    fn proposer_alone_never_passes() {
+        let two = members(&["a", "b"]);
+        assert!(!poll(PollKind::Skip, &[("a", 0)]).passed(&two, 0.5));
+        assert!(!poll(PollKind::Seek(10.0), &[("a", 0)]).passed(&two, 0.0));
+        assert!(poll(PollKind::Skip, &[("a", 0), ("b", 0)]).passed(&two, 0.5));
+    }
+
+    #[test]
+    fn transfer_beneficiary_does_not_count() {
+        let two = members(&["a", "b"]);
+        let to_self = poll(PollKind::Transfer("b".to_owned()), &[("b", 0), ("a", 1)]);
+        assert!(!to_self.passed(&two, 0.5));
+        let three = members(&["a", "b", "c"]);
+        let to_c = PollKind::Transfer("c".to_owned());
+        assert!(!poll(to_c.clone(), &[("b", 0), ("c", 0)]).passed(&three, 0.5));
+        assert!(poll(to_c, &[("a", 0), ("b", 0), ("c", 1)]).passed(&three, 0.5));
+    }
+
+    #[test]
+    fn strict_majority_of_current_members() {
+        let four = members(&["a", "b", "c", "d"]);
+        // half is not more than half
+        assert!(!poll(PollKind::Skip, &[("a", 0), ("b", 0)]).passed(&four, 0.5));
+        assert!(poll(PollKind::Skip, &[("a", 0), ("b", 0), ("c", 0)]).passed(&four, 0.5));
+        // votes of members who left are ignored
+        let left = poll(PollKind::Skip, &[("a", 0), ("b", 0), ("x", 0)]);
+        assert!(!left.passed(&four, 0.5));
+        // everyone at a threshold of 1
+        let all = poll(PollKind::Skip, &[("a", 0), ("b", 0), ("c", 0), ("d", 0)]);
+        assert!(all.passed(&four, 1.0));
+        assert!(!poll(PollKind::Custom, &[("a", 0), ("b", 0)]).passed(&four, 0.0));
+    }
+}
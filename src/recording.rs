@@ -0,0 +1,293 @@
+//! Room timeline recordings. While a roomer records, playback changes,
+//! shares and chat are kept with their offset from the start; `/replay`
+//! plays a saved timeline into a fresh room on the server clock.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::server::now_millis;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingsConfig {
+    /// One `<id>.json` file per recording
+    pub dir: PathBuf,
+    /// Recordings stop by themselves after this long
+    pub max_duration_mins: u64,
+    pub max_events: usize,
+}
+
+impl Default for RecordingsConfig {
+    fn default() -> Self {
+        RecordingsConfig {
+            dir: PathBuf::from("./recordings"),
+            max_duration_mins: 360,
+            max_events: 50_000,
+        }
+    }
+}
+
+impl RecordingsConfig {
+    pub fn max_duration(&self) -> Duration {
+        Duration::from_secs(self.max_duration_mins.saturating_mul(60))
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| self.dir.join(format!("{id}.json")))
+    }
+
+    pub fn load(&self, id: &str) -> io::Result<Recording> {
+        let path = self
+            .path(id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// Something that happened in the room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Timeline {
+    /// Position and speed, a speed of 0 is a pause
+    Playback {
+        progress: f64,
+        speed: f64,
+    },
+    Speed {
+        speed: String,
+    },
+    Share {
+        link: String,
+    },
+    /// Sent with the session id like live chat, `name` goes out as a
+    /// `Speaker` right before it
+    Chat {
+        #[serde(default)]
+        id: String,
+        name: String,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the recording started
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: Timeline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub id: String,
+    pub room: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Unix milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub events: Vec<Entry>,
+}
+
+/// Who said the replayed chat message that follows, `Code::Speaker`; the
+/// speaker is not in the replay room
+#[derive(Debug, Clone, Serialize)]
+pub struct Speaker {
+    pub id: String,
+    pub name: String,
+}
+
+/// What `/record` and `/replay` report, `Code::Recording`
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingInfo {
+    pub id: String,
+    pub room: String,
+    pub recording: bool,
+    pub duration_ms: u64,
+    pub events: usize,
+}
+
+/// Recording in progress, kept on the room
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    pub id: String,
+    pub started: Instant,
+    pub started_at: u64,
+    pub events: Vec<Entry>,
+}
+
+impl Recorder {
+    pub fn start() -> Recorder {
+        Recorder {
+            id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            started: Instant::now(),
+            started_at: now_millis(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Record `event` as happening `delay` from now
+    pub fn push(&mut self, event: Timeline, delay: Duration) {
+        let at = (self.started.elapsed() + delay).as_millis() as u64;
+        self.events.push(Entry { at, event });
+    }
+
+    pub fn info(&self, room: &str, recording: bool) -> RecordingInfo {
+        RecordingInfo {
+            id: self.id.clone(),
+            room: room.to_owned(),
+            recording,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            events: self.events.len(),
+        }
+    }
+
+    /// Write the timeline to `dir`
+    pub fn save(mut self, dir: &Path, room: &str, title: Option<String>) -> io::Result<()> {
+        // countdown starts are recorded ahead of time
+        self.events.sort_by_key(|e| e.at);
+        let recording = Recording {
+            id: self.id,
+            room: room.to_owned(),
+            title,
+            started_at: self.started_at,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            events: self.events,
+        };
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.json", recording.id));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&recording)?)?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+/// Replay in progress, kept on the room
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub recording: String,
+    pub events: Vec<Entry>,
+    /// Index of the next event to play
+    pub next: usize,
+    pub started: Instant,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Replay {
+        Replay {
+            recording: recording.id,
+            events: recording.events,
+            next: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Events that are due, in order
+    pub fn due(&mut self) -> Vec<Timeline> {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        let mut due = Vec::new();
+        while let Some(entry) = self.events.get(self.next) {
+            if entry.at > elapsed {
+                break;
+            }
+            due.push(entry.event.clone());
+            self.next += 1;
+        }
+        due
+    }
+
+    /// Time until the next event, `None` once everything was played
+    pub fn wait(&self) -> Option<Duration> {
+        let entry = self.events.get(self.next)?;
+        let at = self.started + Duration::from_millis(entry.at);
+        Some(at.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(at: u64) -> Entry {
+        Entry {
+            at,
+            event: Timeline::Speed {
+                speed: at.to_string(),
+            },
+        }
+    }
+
+    fn speeds(events: Vec<Timeline>) -> Vec<String> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                Timeline::Speed { speed } => Some(speed),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_play_what_is_due_in_order() {
+        let mut replay = Replay::new(Recording {
+            id: "ab".to_owned(),
+            room: "r".to_owned(),
+            title: None,
+            started_at: 0,
+            duration_ms: 0,
+            events: vec![entry(0), entry(100), entry(60_000), entry(120_000)],
+        });
+        replay.started = Instant::now() - Duration::from_millis(500);
+        assert_eq!(speeds(replay.due()), ["0", "100"]);
+        assert!(replay.due().is_empty());
+        let wait = replay.wait().unwrap();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+
+        replay.started = Instant::now() - Duration::from_secs(600);
+        assert_eq!(replay.wait(), Some(Duration::ZERO));
+        assert_eq!(speeds(replay.due()), ["60000", "120000"]);
+        assert_eq!(replay.wait(), None);
+    }
+
+    #[test]
+    fn only_hex_ids_have_paths() {
+        let config = RecordingsConfig::default();
+        assert_eq!(
+            config.path("0123abcdef"),
+            Some(config.dir.join("0123abcdef.json"))
+        );
+        for id in ["", "../x", "..", "a/b", "abc.json", "xyz", "ab\\cd"] {
+            assert_eq!(config.path(id), None, "{id:?}");
+        }
+        assert_eq!(
+            config.load("../x").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn saved_timelines_are_sorted() {
+        let dir = std::env::temp_dir().join(format!("together-rec-{}", std::process::id()));
+        let config = RecordingsConfig {
+            dir: dir.clone(),
+            ..RecordingsConfig::default()
+        };
+        let mut recorder = Recorder::start();
+        recorder.push(entry(0).event, Duration::from_secs(3));
+        recorder.push(entry(1).event, Duration::ZERO);
+        let id = recorder.id.clone();
+        recorder.save(&dir, "r", Some("t".to_owned())).unwrap();
+        let recording = config.load(&id).unwrap();
+        assert_eq!(recording.room, "r");
+        let at: Vec<u64> = recording.events.iter().map(|e| e.at).collect();
+        assert!(at[0] < 3000 && at[1] >= 3000, "{at:?}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
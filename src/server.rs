@@ -3,17 +3,33 @@
 //! room through `ChatServer`.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use actix::prelude::*;
 use rand::{self, rngs::ThreadRng, Rng};
 
-use crate::context::{Code, Data};
+use crate::{
+    audit::{Action, AuditActor, AuditEvent, AuditLog},
+    avatar,
+    broker::{Broker, BrokerEvent, Remote, SharedPlayback},
+    context::{Code, Data, MsgData},
+    directory::{DirectoryPage, DirectoryQuery, RoomCommand, RoomMeta, RoomSummary},
+    feed::{self, Access, Feed, FeedStream, Viewer, WatchError},
+    filter::{ContentFilter, Strictness, Verdict},
+    lifecycle::LifecycleConfig,
+    party::{Party, Schedule},
+    poll::{PollKind, Polls, DEFAULT_POLL_DURATION, MAX_GOVERNANCE_POLLS, MAX_POLL_DURATION},
+    recording::{Recorder, RecordingInfo, RecordingsConfig, Replay, Speaker, Timeline},
+    webhook::{new_secret, EventKind, Hook, HookInfo, WebhookCommand, Webhooks},
+};
 
 /// Chat server sends this messages to session
 #[derive(Message)]
@@ -21,13 +37,31 @@ use crate::context::{Code, Data};
 pub struct Message(pub String);
 
 /// Message for chat server communications
-
+///
 /// New chat session is created
 #[derive(Message)]
 #[rtype(String)]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    pub close: Recipient<Close>,
     pub user: (Option<String>, Option<String>),
+    /// Stable owner key of a signed in user, e.g. `account:<id>`
+    pub identity: Option<String>,
+    /// Session id to use instead of a random one, for users identified by
+    /// the embedding site. An older session with the same id is replaced.
+    pub id: Option<String>,
+}
+
+/// Chat server asks the session to close its websocket
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum Close {
+    /// Server is restarting, the client should reconnect
+    Restart,
+    /// Removed by an operator
+    Kicked,
+    /// The same user connected again and took over the session id
+    Replaced,
 }
 
 /// Session is disconnected
@@ -35,6 +69,9 @@ pub struct Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: String,
+    /// The disconnecting session, a session replaced under the same id is
+    /// ignored. `None` removes whoever holds the id.
+    pub close: Option<Recipient<Close>>,
 }
 
 /// Send message to specific room
@@ -61,32 +98,233 @@ impl actix::Message for Count {
     type Result = String;
 }
 
+/// How long a "wait for everyone" room stays paused for a buffering member
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// How long a ready check waits for members to confirm
+const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lead time between the countdown broadcast and the scheduled start
+const COUNTDOWN: Duration = Duration::from_secs(3);
+
+/// Seconds before a watch party when its room is reminded
+const PARTY_REMINDERS: [u64; 7] = [3600, 1800, 600, 300, 60, 30, 10];
+
+/// A playback report further than this from the expected position is a seek
+const SEEK_TOLERANCE: f64 = 2.0;
+
+/// A party whose start was missed by more than this is dropped
+const PARTY_MISSED: Duration = Duration::from_secs(60);
+
+/// Session address with the (name, avatar) pair set by `Login`
+type Session = (Recipient<Message>, (Option<String>, Option<String>));
+
 /// `ChatServer` manages chat rooms and responsible for coordinating chat session.
 ///
 /// Implementation is very naïve.
 #[derive(Debug)]
 pub struct ChatServer {
-    sessions: HashMap<String, (Recipient<Message>, (Option<String>, Option<String>))>,
+    sessions: HashMap<String, Session>,
+    closers: HashMap<String, Recipient<Close>>,
     rooms: HashMap<String, Room>,
+    presence: HashMap<String, Presence>,
     rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
+    filter: Box<dyn ContentFilter>,
+    /// Rooms are written here on shutdown and read back on start
+    state_file: Option<PathBuf>,
+    /// Fan-out to other instances
+    broker: Box<dyn Broker>,
+    /// Capacity of rooms without their own `max_members`
+    max_members: Option<usize>,
+    /// Spectators allowed per room, on top of the members
+    max_spectators: Option<usize>,
+    /// Owner keys of signed in sessions, used for room reservations
+    identities: HashMap<String, String>,
+    lifecycle: LifecycleConfig,
+    /// Upcoming watch parties
+    parties: Schedule,
+    webhooks: Webhooks,
+    audit: AuditLog,
+    recordings: RecordingsConfig,
+    /// Own address, for work finished off the actor thread
+    me: Option<Addr<ChatServer>>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Room {
     pub roomer: String,
     pub members: HashSet<String>,
+    /// Read-only sessions, they receive the broadcasts but never talk,
+    /// control playback or become roomer
+    #[serde(default)]
+    pub spectators: HashSet<String>,
+    /// Content filter strictness for chat messages
+    #[serde(default)]
+    pub filter: Strictness,
+    /// Directory listing, private unless the roomer publishes it
+    #[serde(default)]
+    pub meta: RoomMeta,
+    /// Unix milliseconds
+    #[serde(default = "now_millis")]
+    pub created: u64,
+    /// Overrides the server wide capacity
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    /// Hooks added by the roomer with `/webhook`, their secrets stay out of
+    /// the state file
+    #[serde(skip)]
+    pub webhooks: Vec<Hook>,
+    /// Sessions waiting for a free place, first in first out
+    #[serde(skip)]
+    pub queue: VecDeque<String>,
+    /// Last chat, share or playback report
+    #[serde(skip, default = "Instant::now")]
+    pub last_activity: Instant,
+    /// Set while the room has no members
+    #[serde(skip)]
+    pub empty_since: Option<Instant>,
+    /// Owner key an empty room is held for
+    #[serde(default)]
+    pub reserved_for: Option<String>,
+    #[serde(skip)]
+    pub idle_warned: bool,
+    #[serde(default)]
+    pub playback: Option<Playback>,
+    #[serde(skip)]
+    pub wait: Option<WaitMode>,
+    #[serde(skip)]
+    pub ready_check: Option<ReadyCheck>,
+    #[serde(skip)]
+    pub polls: Polls,
+    /// Timeline being recorded with `/record`
+    #[serde(skip)]
+    pub recorder: Option<Recorder>,
+    /// Recorded timeline being played into this room
+    #[serde(skip)]
+    pub replay: Option<Replay>,
+    /// Broadcasts for `GET /api/rooms/{name}/events`
+    #[serde(skip)]
+    pub feed: Feed,
+}
+
+/// Last playback state reported by the roomer
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(into = "SharedPlayback", from = "SharedPlayback")]
+pub struct Playback {
+    pub progress: f64,
+    pub speed: f64,
+    pub updated: Instant,
+}
+
+impl Playback {
+    /// Current position, extrapolated from the last report
+    pub fn position(&self) -> f64 {
+        self.progress + self.updated.elapsed().as_secs_f64() * self.speed
+    }
+}
+
+impl From<Playback> for SharedPlayback {
+    fn from(playback: Playback) -> Self {
+        SharedPlayback {
+            progress: playback.position(),
+            speed: playback.speed,
+            updated: now_millis(),
+        }
+    }
+}
+
+impl From<SharedPlayback> for Playback {
+    fn from(shared: SharedPlayback) -> Self {
+        Playback {
+            progress: shared.progress
+                + now_millis().saturating_sub(shared.updated) as f64 / 1000.0 * shared.speed,
+            speed: shared.speed,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// "Wait for everyone" mode: pause the room while any member is buffering.
+///
+/// Holding needs a playback position to pause at, so nothing is held until
+/// the roomer has reported one with `/progress`.
+#[derive(Debug, Clone)]
+pub struct WaitMode {
+    pub max_wait: Duration,
+    /// When the room was paused for a buffering member
+    pub held: Option<Instant>,
+    /// Speed before the hold, restored when it ends
+    pub speed: f64,
+    /// Members still buffering when the last hold was given up, ignored
+    /// until they report buffering again
+    pub given_up: HashSet<String>,
+}
+
+/// Ready check in progress, started by the roomer
+#[derive(Debug, Clone)]
+pub struct ReadyCheck {
+    /// Members that have not confirmed yet
+    pub pending: HashSet<String>,
+    pub started: Instant,
+}
+
+/// `Code::Ready` payload: a ready check is running and these members have
+/// not confirmed yet
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReadyStatus {
+    pub pending: Vec<User>,
+}
+
+/// Scheduled synchronized start, times are server unix milliseconds
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Countdown {
+    pub start_at: u64,
+    pub now: u64,
+    pub progress: f64,
 }
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RoomInfo {
     pub roomer: User,
     pub members: Vec<User>,
+    /// Spectators are only counted
+    #[serde(default)]
+    pub spectators: usize,
 }
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct User {
     pub id: String,
     pub name: Option<String>,
     pub avatar: Option<String>,
+    #[serde(default)]
+    pub presence: Presence,
+}
+
+/// What a member is currently doing, reported by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    #[default]
+    Watching,
+    Buffering,
+    Paused,
+    Away,
+    Typing,
+}
+
+impl std::str::FromStr for Presence {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "watching" => Ok(Presence::Watching),
+            "buffering" => Ok(Presence::Buffering),
+            "paused" => Ok(Presence::Paused),
+            "away" => Ok(Presence::Away),
+            "typing" => Ok(Presence::Typing),
+            _ => Err(()),
+        }
+    }
 }
 
 impl Room {
@@ -96,30 +334,90 @@ impl Room {
         Room {
             roomer,
             members: set,
+            spectators: HashSet::new(),
+            filter: Strictness::default(),
+            meta: RoomMeta::default(),
+            created: now_millis(),
+            max_members: None,
+            webhooks: Vec::new(),
+            queue: VecDeque::new(),
+            last_activity: Instant::now(),
+            empty_since: None,
+            reserved_for: None,
+            idle_warned: false,
+            playback: None,
+            wait: None,
+            ready_check: None,
+            polls: Polls::default(),
+            recorder: None,
+            replay: None,
+            feed: Feed::default(),
         }
     }
 }
 
 impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ChatServer {
-        // default room
-        let rooms = HashMap::new();
-        // rooms.insert("main".to_owned(), HashSet::new());
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        filter: Box<dyn ContentFilter>,
+        state_file: Option<PathBuf>,
+        broker: Box<dyn Broker>,
+        max_members: Option<usize>,
+        max_spectators: Option<usize>,
+        lifecycle: LifecycleConfig,
+        parties: Schedule,
+        webhooks: Webhooks,
+        audit: AuditLog,
+        recordings: RecordingsConfig,
+    ) -> ChatServer {
+        let rooms = state_file.as_deref().map(load_rooms).unwrap_or_default();
 
         ChatServer {
             sessions: HashMap::new(),
+            closers: HashMap::new(),
             rooms,
+            presence: HashMap::new(),
             rng: rand::thread_rng(),
             visitor_count,
+            filter,
+            state_file,
+            broker,
+            max_members,
+            max_spectators,
+            identities: HashMap::new(),
+            lifecycle,
+            parties,
+            webhooks,
+            audit,
+            recordings,
+            me: None,
         }
     }
 }
 
 impl ChatServer {
     /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: String) {
-        if let Some(Room { roomer: _, members }) = self.rooms.get(room) {
-            for id in members {
+    fn send_message(&mut self, room: &str, message: &str, skip_id: String) {
+        self.send_local(room, message, &skip_id);
+        self.broker.publish(BrokerEvent::Room {
+            room: room.to_owned(),
+            payload: message.to_owned(),
+            skip: skip_id,
+        });
+    }
+    /// Send message to the users of the room connected to this instance
+    fn send_local(&mut self, room: &str, message: &str, skip_id: &str) {
+        let access = self.feed_access(room);
+        if let Some(Room {
+            members,
+            spectators,
+            feed,
+            ..
+        }) = self.rooms.get_mut(room)
+        {
+            feed.publish(message, &access);
+            for id in members.iter().chain(spectators.iter()) {
                 if *id != skip_id {
                     if let Some((addr, _)) = self.sessions.get(id) {
                         addr.do_send(Message(message.to_owned()));
@@ -132,8 +430,110 @@ impl ChatServer {
     fn send(&self, message: &str, uid: String) {
         if let Some((addr, _)) = self.sessions.get(&uid) {
             addr.do_send(Message(message.to_owned()));
+        } else {
+            self.broker.publish(BrokerEvent::Session {
+                to: uid,
+                payload: message.to_owned(),
+            });
+        }
+    }
+    /// Record the new roomer for every instance
+    fn share_roomer(&self, room: &str, id: &str) {
+        self.broker.set_roomer(room, id);
+        self.broker.publish(BrokerEvent::Roomer {
+            room: room.to_owned(),
+            id: id.to_owned(),
+        });
+    }
+    /// Record the playback of the room for every instance
+    fn share_playback(&self, room: &str) {
+        if let Some(playback) = self.rooms.get(room).and_then(|r| r.playback.clone()) {
+            self.broker.set_playback(room, &playback.into());
+        }
+    }
+    /// Deliver a room event to the global hooks and those of the room
+    fn emit<T: serde::Serialize>(&self, room: &str, kind: EventKind, data: T) {
+        let hooks = self
+            .rooms
+            .get(room)
+            .map(|r| r.webhooks.as_slice())
+            .unwrap_or_default();
+        self.webhooks.emit(hooks, room, kind, data);
+    }
+    /// Run text from a member through the content filter at the strictness
+    /// of the room. Returns the text to deliver, or `None` when rejected.
+    fn screen(&self, room: &str, id: &str, text: String) -> Option<String> {
+        let (strictness, roomer) = match self.rooms.get(room) {
+            Some(room) => (room.filter, room.roomer.clone()),
+            None => return Some(text),
+        };
+        match self.filter.check(&text, strictness) {
+            Verdict::Allow => Some(text),
+            Verdict::Mask(masked) => Some(masked),
+            Verdict::Reject => None,
+            Verdict::Flag => {
+                let user = self.get_user(id.to_owned());
+                log::warn!("flagged message in {}: {:?} {:?}", room, user, text);
+                if roomer != id {
+                    self.send(
+                        &Data::sys(format!(
+                            "[Flagged] {}: {}",
+                            user.name.clone().unwrap_or(user.id.clone()),
+                            text
+                        )),
+                        roomer,
+                    );
+                }
+                Some(text)
+            }
+        }
+    }
+    /// Who may follow the feed of a room, nobody when it is gone
+    fn feed_access(&self, name: &str) -> Access {
+        match self.rooms.get(name) {
+            Some(room) if room.meta.public => Access {
+                public: true,
+                members: Vec::new(),
+            },
+            Some(room) => Access {
+                public: false,
+                members: room
+                    .members
+                    .iter()
+                    .filter_map(|id| self.identities.get(id).cloned())
+                    .collect(),
+            },
+            None => Access::default(),
+        }
+    }
+
+    /// Who is behind a session, for the audit trail
+    fn actor(&self, id: &str) -> AuditActor {
+        AuditActor {
+            id: id.to_owned(),
+            name: self
+                .sessions
+                .get(id)
+                .and_then(|(_, (name, _))| name.clone()),
+            identity: self.identities.get(id).cloned(),
         }
     }
+    /// Append a state change to the audit trail
+    fn audit(
+        &mut self,
+        action: Action,
+        actor: AuditActor,
+        room: Option<&str>,
+        detail: serde_json::Value,
+    ) {
+        self.audit.record(&AuditEvent {
+            at: now_millis(),
+            action,
+            actor,
+            room: room.map(str::to_owned),
+            detail,
+        });
+    }
 }
 
 /// Make actor from `ChatServer`
@@ -141,6 +541,57 @@ impl Actor for ChatServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.me = Some(ctx.address());
+        self.broker.subscribe(ctx.address().recipient());
+        ctx.run_interval(self.lifecycle.check_interval(), |act, _| act.sweep());
+        ctx.run_interval(Duration::from_secs(1), |act, _| act.tick_parties());
+        ctx.run_interval(feed::KEEPALIVE, |act, _| {
+            let names: Vec<String> = act.rooms.keys().cloned().collect();
+            for name in names {
+                let access = act.feed_access(&name);
+                if let Some(room) = act.rooms.get_mut(&name) {
+                    room.feed.keepalive(&access);
+                }
+            }
+        });
+    }
+}
+
+/// Deliver events published by other instances to local sessions
+impl Handler<Remote> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, Remote(event): Remote, _: &mut Context<Self>) {
+        match event {
+            BrokerEvent::Room {
+                room,
+                payload,
+                skip,
+            } => self.send_local(&room, &payload, &skip),
+            BrokerEvent::Session { to, payload } => {
+                if let Some((addr, _)) = self.sessions.get(&to) {
+                    addr.do_send(Message(payload));
+                }
+            }
+            BrokerEvent::Everyone { payload } => {
+                for (addr, _) in self.sessions.values() {
+                    addr.do_send(Message(payload.clone()));
+                }
+            }
+            BrokerEvent::Roomer { room: name, id } => {
+                let Some(room) = self.rooms.get_mut(&name) else {
+                    return;
+                };
+                if room.roomer != id {
+                    let old = std::mem::replace(&mut room.roomer, id.clone());
+                    let moved = serde_json::json!({ "from": old, "to": id, "via": "remote" });
+                    self.audit(Action::Transfer, AuditActor::system(), Some(&name), moved);
+                }
+            }
+        }
+    }
 }
 
 /// Handler for Connect message.
@@ -149,15 +600,40 @@ impl Actor for ChatServer {
 impl Handler<Connect> for ChatServer {
     type Result = String;
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
         println!("{:?} joined", msg.addr);
 
         // notify all users in same room
         // self.send_message("main", format!("{:?} joined",msg.addr).as_str(), 0);
 
-        // register session with random id
-        let id = self.rng.gen::<u8>().to_string();
+        // register session with random id, prefixed with the instance so
+        // ids stay unique across instances
+        let id = if let Some(id) = msg.id {
+            let id = format!("{}{id}", self.broker.instance());
+            // the old session is gone before the new one is registered,
+            // anything it still sends under the id is ignored
+            if let Some(close) = self.closers.get(&id).cloned() {
+                let old = Disconnect {
+                    id: id.clone(),
+                    close: None,
+                };
+                self.handle(old, ctx);
+                close.do_send(Close::Replaced);
+            }
+            id
+        } else {
+            loop {
+                let id = format!("{}{}", self.broker.instance(), self.rng.gen::<u32>());
+                if !self.sessions.contains_key(&id) {
+                    break id;
+                }
+            }
+        };
         self.sessions.insert(id.clone(), (msg.addr, msg.user));
+        self.closers.insert(id.clone(), msg.close);
+        if let Some(identity) = msg.identity {
+            self.identities.insert(id.clone(), identity);
+        }
 
         // auto join session to main room
         // self.rooms
@@ -186,7 +662,13 @@ impl Handler<Login> for ChatServer {
                 *name = msg.1;
                 *avatar = msg.2;
             });
-        println!("User Login:{:?}", self.get_user(msg.0))
+        let user = self.get_user(msg.0.clone());
+        for (name, room) in &self.rooms {
+            if room.members.contains(&msg.0) {
+                self.broker.join(name, &user);
+            }
+        }
+        println!("User Login:{:?}", user)
     }
 }
 
@@ -194,40 +676,78 @@ impl Handler<Login> for ChatServer {
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        if let Some(close) = &msg.close {
+            if self
+                .closers
+                .get(&msg.id)
+                .is_some_and(|current| current != close)
+            {
+                return;
+            }
+        }
         let user = self.get_user(msg.id.clone());
         println!("{:?} disconnected", user.name.clone());
 
         let mut rooms: Vec<String> = Vec::new();
         let mut empty_rooms: Vec<String> = Vec::new();
-        let mut new_roomer: Option<_> = None;
+        let mut new_roomer = Vec::new();
+        let owner = self.owner_key(&msg.id);
+        let actor = self.actor(&msg.id);
+        self.identities.remove(&msg.id);
+        self.presence.remove(&msg.id);
+        self.closers.remove(&msg.id);
+        self.dequeue(&msg.id);
+        self.stop_spectating(&msg.id);
         // remove address
         if self.sessions.remove(&msg.id).is_some() {
             // remove session from all rooms
-            for (name, Room { roomer, members }) in &mut self.rooms {
+            for (
+                name,
+                Room {
+                    roomer, members, ..
+                },
+            ) in &mut self.rooms
+            {
                 if members.remove(&msg.id) {
                     rooms.push(name.to_owned());
+                    self.broker.leave(name, &msg.id);
                 }
                 if roomer == &msg.id {
-                    // 转让房主
+                    // 转让房主，旁观者不参与
                     // 后期根据房间设置确认是否转让
-                    if let Some(u) = members.clone().into_iter().next() {
+                    let remote = || {
+                        let shared = self.broker.room(name);
+                        shared.members.into_keys().find(|id| *id != msg.id)
+                    };
+                    if let Some(u) = members.clone().into_iter().next().or_else(remote) {
                         *roomer = u.clone();
-                        new_roomer = Some(u);
-                    } else {
-                        // 空房间，删除
-                        empty_rooms.push(name.to_owned());
+                        new_roomer.push((name.to_owned(), u));
                     }
                 }
+                if members.is_empty() {
+                    // 空房间，为房主保留一段时间
+                    empty_rooms.push(name.to_owned());
+                }
             }
         }
         // 给新房主发消息
-        if let Some(roomer) = new_roomer {
+        for (room, roomer) in new_roomer {
+            let moved = serde_json::json!({ "from": msg.id, "to": roomer, "via": "disconnect" });
+            self.audit(Action::Transfer, AuditActor::system(), Some(&room), moved);
+            self.share_roomer(&room, &roomer);
             self.send(Data::full(Code::Roomer, true).as_str(), roomer);
         }
         self.visitor_count.fetch_min(1, Ordering::SeqCst);
         // send message to other users
         for room in rooms {
+            self.audit(
+                Action::Leave,
+                actor.clone(),
+                Some(&room),
+                serde_json::Value::Null,
+            );
+            self.emit(&room, EventKind::MemberLeft, &user);
             self.send_message(
                 &room,
                 &Data::sys(format!(
@@ -236,9 +756,14 @@ impl Handler<Disconnect> for ChatServer {
                 )),
                 "".to_string(),
             );
+            self.update_hold(&room, ctx);
+            self.update_ready(&room);
+            self.admit_waiting(&room);
         }
         for room in empty_rooms {
-            self.rooms.remove(room.as_str());
+            if self.rooms.get(&room).is_some_and(|r| r.members.is_empty()) {
+                self.reserve(&room, &msg.id, owner.clone());
+            }
         }
     }
 }
@@ -248,15 +773,119 @@ impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
+        match self.rooms.get(&msg.room) {
+            Some(room) if room.members.contains(&msg.id) => {}
+            Some(room) if room.spectators.contains(&msg.id) => {
+                self.send(&Data::sys("SPECTATOR"), msg.id);
+                return;
+            }
+            _ => return,
+        };
         let user = self.get_user(msg.id.clone());
-        self.send_message(
-            &msg.room,
-            &Data::msg(
-                user.id.to_string(),
-                msg.msg,
-            ),
-            msg.id,
-        );
+        let text = match self.screen(&msg.room, &msg.id, msg.msg) {
+            Some(text) => text,
+            None => {
+                self.send(&Data::sys("MESSAGE_REJECTED"), msg.id);
+                return;
+            }
+        };
+        self.touch(&msg.room);
+        let chat = Timeline::Chat {
+            id: user.id.clone(),
+            name: user.name.clone().unwrap_or(user.id.clone()),
+            text: text.clone(),
+        };
+        self.record(&msg.room, chat, Duration::ZERO);
+        self.send_message(&msg.room, &Data::msg(user.id.to_string(), text), msg.id);
+    }
+}
+
+/// Set the content filter strictness of a room, roomer only
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct SetFilter {
+    /// Client ID
+    pub id: String,
+    /// Room name
+    pub room: String,
+    pub strictness: Strictness,
+}
+impl Handler<SetFilter> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: SetFilter, _: &mut Context<Self>) -> Self::Result {
+        match self.rooms.get_mut(&msg.room) {
+            Some(room) if room.roomer == msg.id => {
+                room.filter = msg.strictness;
+                let filter = serde_json::json!({ "filter": msg.strictness });
+                self.audit(Action::Filter, self.actor(&msg.id), Some(&msg.room), filter);
+                None
+            }
+            Some(_) => Some((Code::Sys, "NOT_ROOMER".to_string())),
+            None => Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        }
+    }
+}
+
+/// Send private message to a member of the same room
+#[derive(Message)]
+#[rtype(result = "(Code, String)")]
+pub struct DirectMessage {
+    /// Id of the client session
+    pub id: String,
+    /// Target user id or name
+    pub to: String,
+    /// Peer message
+    pub msg: String,
+    /// Room name
+    pub room: String,
+}
+/// Handler for DirectMessage message.
+///
+/// The target is matched by id first and then by nickname, and the message
+/// goes to every live session of the same user: those sharing its account
+/// or JWT identity. The sender is acknowledged with `DELIVERED`,
+/// `USER_OFFLINE` or `AMBIGUOUS_USER` when the name belongs to several
+/// users.
+impl Handler<DirectMessage> for ChatServer {
+    type Result = MessageResult<DirectMessage>;
+
+    fn handle(&mut self, msg: DirectMessage, _: &mut Context<Self>) -> Self::Result {
+        let members = match self.rooms.get(&msg.room) {
+            Some(room) if room.members.contains(&msg.id) => &room.members,
+            _ => return MessageResult((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        let target = if msg.to != msg.id && members.contains(&msg.to) {
+            msg.to.clone()
+        } else {
+            let named: Vec<&String> = members
+                .iter()
+                .filter(|id| **id != msg.id)
+                .filter(|id| self.get_user(id.to_string()).name.as_deref() == Some(msg.to.as_str()))
+                .collect();
+            let owners: HashSet<String> = named
+                .iter()
+                .map(|id| self.owner_key(id).unwrap_or(id.to_string()))
+                .collect();
+            match (named.first(), owners.len()) {
+                (None, _) => return MessageResult((Code::Sys, "USER_OFFLINE".to_string())),
+                (Some(id), 1) => id.to_string(),
+                _ => return MessageResult((Code::Sys, "AMBIGUOUS_USER".to_string())),
+            }
+        };
+        let sessions = self.live_sessions(&target);
+        if sessions.is_empty() {
+            return MessageResult((Code::Sys, "USER_OFFLINE".to_string()));
+        }
+        let text = match self.screen(&msg.room, &msg.id, msg.msg) {
+            Some(text) => text,
+            None => return MessageResult((Code::Sys, "MESSAGE_REJECTED".to_string())),
+        };
+        let data = Data::full(Code::Direct, MsgData(msg.id, text));
+        for session in sessions {
+            self.send(&data, session);
+        }
+        MessageResult((Code::Sys, "DELIVERED".to_string()))
     }
 }
 
@@ -277,6 +906,33 @@ impl Handler<FullMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: FullMessage, _: &mut Context<Self>) {
+        let is_member = self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.members.contains(&msg.id));
+        if !is_member {
+            return;
+        }
+        self.touch(&msg.room);
+        if matches!(msg.code, Code::Share) {
+            let user = self.get_user(msg.id.clone());
+            let shared = serde_json::json!({ "user": user, "link": msg.msg });
+            self.emit(&msg.room, EventKind::MediaShared, shared);
+            let link = serde_json::json!({ "link": msg.msg });
+            self.audit(Action::Share, self.actor(&msg.id), Some(&msg.room), link);
+        }
+        let event = match msg.code {
+            Code::Share => Some(Timeline::Share {
+                link: msg.msg.clone(),
+            }),
+            Code::Speed => Some(Timeline::Speed {
+                speed: msg.msg.clone(),
+            }),
+            _ => None,
+        };
+        if let Some(event) = event {
+            self.record(&msg.room, event, Duration::ZERO);
+        }
         self.send_message(&msg.room, &Data::full(msg.code, msg.msg), msg.id);
     }
 }
@@ -297,10 +953,72 @@ impl Handler<Progress> for ChatServer {
     type Result = Option<(Code, String)>;
 
     fn handle(&mut self, msg: Progress, _: &mut Context<Self>) -> Self::Result {
-        match self.rooms.get(msg.room.as_str()) {
+        match self.rooms.get_mut(msg.room.as_str()) {
             Some(v) => {
-                if v.members.len() > 0 {
+                if !v.members.is_empty() {
                     if v.roomer == msg.id {
+                        if v.replay.is_some() {
+                            return Some((Code::Sys, "REPLAYING".to_string()));
+                        }
+                        // 房主手动同步进度时解除等待
+                        if let Some(wait) = &mut v.wait {
+                            wait.held = None;
+                        }
+                        if let (Ok(progress), Ok(speed)) =
+                            (msg.progress.parse::<f64>(), msg.speed.parse::<f64>())
+                        {
+                            let started =
+                                speed != 0.0 && v.playback.as_ref().is_none_or(|p| p.speed == 0.0);
+                            let mut changes = Vec::new();
+                            match &v.playback {
+                                Some(p) => {
+                                    let expected = if p.speed == 0.0 {
+                                        p.progress
+                                    } else {
+                                        p.position()
+                                    };
+                                    if (progress - expected).abs() > SEEK_TOLERANCE {
+                                        let seek =
+                                            serde_json::json!({ "from": expected, "to": progress });
+                                        changes.push((Action::Seek, seek));
+                                    }
+                                    if p.speed != speed {
+                                        let change =
+                                            serde_json::json!({ "from": p.speed, "to": speed });
+                                        changes.push((Action::Speed, change));
+                                    }
+                                }
+                                None => {
+                                    let change = serde_json::json!({ "from": null, "to": speed });
+                                    changes.push((Action::Speed, change));
+                                }
+                            }
+                            v.playback = Some(Playback {
+                                progress,
+                                speed,
+                                updated: Instant::now(),
+                            });
+                            v.last_activity = Instant::now();
+                            v.idle_warned = false;
+                            self.broker.set_playback(
+                                &msg.room,
+                                &SharedPlayback {
+                                    progress,
+                                    speed,
+                                    updated: now_millis(),
+                                },
+                            );
+                            if started {
+                                let playback =
+                                    serde_json::json!({ "progress": progress, "speed": speed });
+                                self.emit(&msg.room, EventKind::PlaybackStarted, playback);
+                            }
+                            for (action, detail) in changes {
+                                self.audit(action, self.actor(&msg.id), Some(&msg.room), detail);
+                            }
+                            let playback = Timeline::Playback { progress, speed };
+                            self.record(&msg.room, playback, Duration::ZERO);
+                        }
                         // 房主,允许广播进度
                         self.send_message(
                             &msg.room,
@@ -320,128 +1038,3127 @@ impl Handler<Progress> for ChatServer {
     }
 }
 
-/// Handler for `ListRooms` message.
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
-
-    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+/// Member reports a new presence state
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetPresence {
+    /// Client ID
+    pub id: String,
+    /// Room name
+    pub room: String,
+    pub presence: Presence,
+}
+/// Store presence and broadcast the change to the rest of the room
+impl Handler<SetPresence> for ChatServer {
+    type Result = ();
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
+    fn handle(&mut self, msg: SetPresence, ctx: &mut Context<Self>) {
+        let is_member = self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.members.contains(&msg.id));
+        if !is_member {
+            return;
         }
-
-        MessageResult(rooms)
+        // a new report re-arms a member whose hold was given up
+        let rearmed = self
+            .rooms
+            .get_mut(&msg.room)
+            .and_then(|room| room.wait.as_mut())
+            .is_some_and(|wait| wait.given_up.remove(&msg.id));
+        if self.presence.insert(msg.id.clone(), msg.presence) == Some(msg.presence) {
+            if rearmed {
+                self.update_hold(&msg.room, ctx);
+            }
+            return;
+        }
+        self.send_message(
+            &msg.room,
+            &Data::full(Code::Presence, (msg.id.clone(), msg.presence)),
+            msg.id,
+        );
+        self.update_hold(&msg.room, ctx);
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "Option<RoomInfo>")]
-pub struct ListMembers {
-    pub room_id: String,
+pub enum WaitCommand {
+    /// Enable with an optional maximum wait in seconds
+    On(Option<u64>),
+    Off,
+    /// Roomer override: resume now even if someone is still buffering
+    Resume,
 }
 
-/// Handler for `ListRooms` message.
-impl Handler<ListMembers> for ChatServer {
-    type Result = Option<RoomInfo>;
+/// Configure "wait for everyone" mode, roomer only
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct Wait {
+    /// Client ID
+    pub id: String,
+    /// Room name
+    pub room: String,
+    pub cmd: WaitCommand,
+}
+impl Handler<Wait> for ChatServer {
+    type Result = Option<(Code, String)>;
 
-    fn handle(
-        &mut self,
-        ListMembers { room_id }: ListMembers,
-        _: &mut Context<Self>,
-    ) -> Self::Result {
-        if let Some(room) = self.rooms.get(&room_id) {
-            Some(RoomInfo {
-                roomer: self.get_user(room.roomer.clone()),
-                members: room.members.iter().map(|id| self.get_user(id.clone())).collect(),
-            })
-        } else {
-            None
+    fn handle(&mut self, msg: Wait, ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) => room,
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        if room.roomer != msg.id {
+            return Some((Code::Sys, "NOT_ROOMER".to_string()));
+        }
+        match msg.cmd {
+            WaitCommand::On(secs) => {
+                let max_wait = secs.map(Duration::from_secs).unwrap_or(DEFAULT_MAX_WAIT);
+                match &mut room.wait {
+                    Some(wait) => wait.max_wait = max_wait,
+                    None => {
+                        room.wait = Some(WaitMode {
+                            max_wait,
+                            held: None,
+                            speed: 0.0,
+                            given_up: HashSet::new(),
+                        })
+                    }
+                }
+                self.send_message(
+                    &msg.room,
+                    &Data::sys("已开启等待所有人模式"),
+                    "".to_string(),
+                );
+                self.update_hold(&msg.room, ctx);
+            }
+            WaitCommand::Off => {
+                let held = room.wait.as_ref().and_then(|w| w.held);
+                if held.is_some() {
+                    self.release_hold(&msg.room, held);
+                }
+                if let Some(room) = self.rooms.get_mut(&msg.room) {
+                    room.wait = None;
+                }
+                self.send_message(
+                    &msg.room,
+                    &Data::sys("已关闭等待所有人模式"),
+                    "".to_string(),
+                );
+            }
+            WaitCommand::Resume => {
+                let held = room.wait.as_ref().and_then(|w| w.held);
+                if held.is_some() {
+                    self.give_up_hold(&msg.room, held);
+                }
+            }
         }
+        None
     }
 }
 
 impl ChatServer {
-    fn get_user(&self, id: String) -> User {
-        if let Some((_, (name, avatar))) = self.sessions.get(&id) {
-            User {
-                id,
-                name: name.to_owned(),
-                avatar: avatar.to_owned(),
-            }
-        } else {
-            User {
-                id,
-                name: None,
-                avatar: None,
+    /// Pause a "wait for everyone" room while any member is buffering,
+    /// resume it once nobody is.
+    fn update_hold(&mut self, name: &str, ctx: &mut Context<Self>) {
+        let Some(room) = self.rooms.get(name) else {
+            return;
+        };
+        let Some(wait) = &room.wait else {
+            return;
+        };
+        let buffering = room.members.iter().any(|id| {
+            self.presence.get(id) == Some(&Presence::Buffering) && !wait.given_up.contains(id)
+        });
+        match (wait.held, buffering) {
+            (None, true) => self.hold(name, ctx),
+            (Some(held), false) => self.release_hold(name, Some(held)),
+            _ => {}
+        }
+    }
+
+    fn hold(&mut self, name: &str, ctx: &mut Context<Self>) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let (Some(playback), Some(wait)) = (&mut room.playback, &mut room.wait) else {
+            return;
+        };
+        if playback.speed == 0.0 {
+            // already paused, nothing to hold
+            return;
+        }
+        let now = Instant::now();
+        playback.progress = playback.position();
+        playback.updated = now;
+        wait.held = Some(now);
+        wait.speed = std::mem::replace(&mut playback.speed, 0.0);
+        let progress = playback.progress;
+        let max_wait = wait.max_wait;
+        let paused = Timeline::Playback {
+            progress,
+            speed: 0.0,
+        };
+        self.share_playback(name);
+        self.record(name, paused, Duration::ZERO);
+        self.send_message(
+            name,
+            &Data::progress((progress.to_string(), "0".to_string())),
+            "".to_string(),
+        );
+        self.send_message(name, &Data::sys("等待其他成员缓冲"), "".to_string());
+        let name = name.to_owned();
+        ctx.run_later(max_wait, move |act, _| {
+            act.give_up_hold(&name, Some(now));
+        });
+    }
+
+    /// Resume without waiting for the members still buffering; they hold the
+    /// room again on their next buffering report
+    fn give_up_hold(&mut self, name: &str, since: Option<Instant>) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let Some(wait) = &mut room.wait else {
+            return;
+        };
+        if wait.held.is_none() || wait.held != since {
+            return;
+        }
+        for id in &room.members {
+            if self.presence.get(id) == Some(&Presence::Buffering) {
+                wait.given_up.insert(id.clone());
             }
         }
+        self.release_hold(name, since);
     }
-}
-/// Handler for `ListRooms` message.
-impl Handler<Count> for ChatServer {
-    type Result = MessageResult<Count>;
 
-    fn handle(&mut self, _: Count, _: &mut Context<Self>) -> Self::Result {
-        MessageResult(format!("{:?}", self.visitor_count))
+    /// Resume playback if the room is still held since `since`
+    fn release_hold(&mut self, name: &str, since: Option<Instant>) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let (Some(playback), Some(wait)) = (&mut room.playback, &mut room.wait) else {
+            return;
+        };
+        if wait.held.is_none() || wait.held != since {
+            return;
+        }
+        wait.held = None;
+        playback.updated = Instant::now();
+        playback.speed = wait.speed;
+        let (progress, speed) = (playback.progress, playback.speed);
+        let data = Data::progress((progress.to_string(), speed.to_string()));
+        self.share_playback(name);
+        self.send_message(name, &data, "".to_string());
+        self.record(name, Timeline::Playback { progress, speed }, Duration::ZERO);
     }
 }
 
-/// Join room, if room does not exists create new one.
+pub enum ReadyCommand {
+    /// Roomer starts a ready check with an optional timeout in seconds
+    Check(Option<u64>),
+    /// Member confirms the media is loaded
+    Confirm,
+}
+
+/// Ready check before a synchronized start
 #[derive(Message)]
-#[rtype(result = "bool")]
-pub struct Join {
+#[rtype(result = "Option<(Code,String)>")]
+pub struct Ready {
     /// Client ID
     pub id: String,
-
     /// Room name
-    pub name: String,
+    pub room: String,
+    pub cmd: ReadyCommand,
 }
-/// Join room, send disconnect message to old room
-/// send join message to new room
-impl Handler<Join> for ChatServer {
-    type Result = bool;
+impl Handler<Ready> for ChatServer {
+    type Result = Option<(Code, String)>;
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
-        let Join { id, name } = msg;
-        let user = self.get_user(id.clone());
-        let mut rooms = Vec::new();
-        // remove session from all rooms
-        for (n, Room { roomer: _, members }) in &mut self.rooms {
-            if members.remove(&id) {
-                rooms.push(n.to_owned());
+    fn handle(&mut self, msg: Ready, ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) => room,
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        match msg.cmd {
+            ReadyCommand::Check(timeout) => {
+                if room.roomer != msg.id {
+                    return Some((Code::Sys, "NOT_ROOMER".to_string()));
+                }
+                let started = Instant::now();
+                let mut pending = room.members.clone();
+                pending.remove(&msg.id);
+                let status = ReadyStatus {
+                    pending: pending.iter().map(|id| self.get_user(id.clone())).collect(),
+                };
+                if let Some(room) = self.rooms.get_mut(&msg.room) {
+                    room.ready_check = Some(ReadyCheck { pending, started });
+                }
+                self.send_message(&msg.room, &Data::full(Code::Ready, status), msg.id);
+                let timeout = timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_READY_TIMEOUT);
+                let name = msg.room.clone();
+                ctx.run_later(timeout, move |act, _| {
+                    let current = act
+                        .rooms
+                        .get(&name)
+                        .and_then(|room| room.ready_check.as_ref())
+                        .map(|check| check.started);
+                    if current == Some(started) {
+                        act.start_countdown(&name);
+                    }
+                });
             }
+            ReadyCommand::Confirm => match &mut room.ready_check {
+                Some(check) => {
+                    check.pending.remove(&msg.id);
+                }
+                None => return Some((Code::Sys, "NO_READY_CHECK".to_string())),
+            },
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(
-                &room,
-                &Data::sys(format!(
-                    "{} 退出房间",
-                    user.name.clone().unwrap_or(id.to_string())
-                )),
-                "".to_string(),
-            );
+        self.update_ready(&msg.room);
+        None
+    }
+}
+
+impl ChatServer {
+    /// Drop members that left, report pending members to the roomer and
+    /// start the countdown once everybody is ready.
+    fn update_ready(&mut self, name: &str) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let Some(check) = &mut room.ready_check else {
+            return;
+        };
+        check.pending.retain(|id| room.members.contains(id));
+        if check.pending.is_empty() {
+            self.start_countdown(name);
+            return;
         }
-        let mut roomer = false;
-        self.rooms
-            .entry(name.clone())
-            .and_modify(|Room { roomer: _, members }| {
-                members.insert(id.clone());
-            })
-            .or_insert_with(|| {
-                roomer = true;
-                Room::new(id.clone())
-            });
+        let roomer = room.roomer.clone();
+        let pending: Vec<String> = check.pending.iter().cloned().collect();
+        let status = ReadyStatus {
+            pending: pending.into_iter().map(|id| self.get_user(id)).collect(),
+        };
+        self.send(&Data::full(Code::Ready, status), roomer);
+    }
 
+    /// Schedule a synchronized start and broadcast it to the room
+    fn start_countdown(&mut self, name: &str) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        room.ready_check = None;
+        let progress = room.playback.as_ref().map_or(0.0, |p| {
+            if p.speed == 0.0 {
+                p.progress
+            } else {
+                p.position()
+            }
+        });
+        let speed = room
+            .playback
+            .as_ref()
+            .map(|p| p.speed)
+            .filter(|speed| *speed != 0.0)
+            .unwrap_or(1.0);
+        room.playback = Some(Playback {
+            progress,
+            speed,
+            updated: Instant::now() + COUNTDOWN,
+        });
+        let now = now_millis();
+        let countdown = Countdown {
+            start_at: now + COUNTDOWN.as_millis() as u64,
+            now,
+            progress,
+        };
+        // other instances start at the same instant
+        let shared = SharedPlayback {
+            progress,
+            speed,
+            updated: countdown.start_at,
+        };
+        self.broker.set_playback(name, &shared);
+        self.emit(name, EventKind::PlaybackStarted, &countdown);
+        self.record(name, Timeline::Playback { progress, speed }, COUNTDOWN);
         self.send_message(
-            &name,
-            &Data::sys(format!(
-                "{} 进入房间",
-                user.name.clone().unwrap_or(id.clone())
-            )),
+            name,
+            &Data::full(Code::Countdown, countdown),
+            "".to_string(),
+        );
+    }
+}
+
+/// Server wall clock in unix milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub enum PollCommand {
+    /// Generic poll: duration in seconds, question and options
+    Create(Option<u64>, String, Vec<String>),
+    /// Governance poll with yes/no options
+    Propose(PollKind),
+    /// Poll id and option index
+    Vote(u32, usize),
+    Close(u32),
+    /// Share of members needed to pass a governance poll, roomer only
+    Threshold(f64),
+}
+
+/// Create, vote on and close room polls
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct RoomPoll {
+    /// Client ID
+    pub id: String,
+    /// Room name
+    pub room: String,
+    pub cmd: PollCommand,
+}
+impl Handler<RoomPoll> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: RoomPoll, ctx: &mut Context<Self>) -> Self::Result {
+        if !self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.members.contains(&msg.id))
+        {
+            return Some((Code::Sys, "ROOM_NOT_EXIST".to_string()));
+        }
+        let cmd = match msg.cmd {
+            PollCommand::Create(duration, question, options) => {
+                let screened = std::iter::once(question)
+                    .chain(options)
+                    .map(|text| self.screen(&msg.room, &msg.id, text))
+                    .collect::<Option<Vec<_>>>();
+                match screened {
+                    Some(mut texts) => {
+                        let question = texts.remove(0);
+                        PollCommand::Create(duration, question, texts)
+                    }
+                    None => return Some((Code::Sys, "MESSAGE_REJECTED".to_string())),
+                }
+            }
+            cmd => cmd,
+        };
+        let room = self.rooms.get_mut(&msg.room)?;
+        match cmd {
+            PollCommand::Create(duration, question, options) => {
+                let duration = duration
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_POLL_DURATION)
+                    .min(MAX_POLL_DURATION);
+                let poll = room
+                    .polls
+                    .create(msg.id, PollKind::Custom, question, options, duration);
+                let data = Data::full(Code::Poll, poll.state(false, None));
+                let poll_id = poll.id;
+                self.send_message(&msg.room, &data, "".to_string());
+                self.schedule_poll_close(&msg.room, poll_id, duration, ctx);
+            }
+            PollCommand::Propose(kind) => {
+                if room.polls.governance() >= MAX_GOVERNANCE_POLLS {
+                    return Some((Code::Sys, "TOO_MANY_POLLS".to_string()));
+                }
+                let question = match &kind {
+                    PollKind::Transfer(to) if !room.members.contains(to) => {
+                        return Some((Code::Sys, "USER_OFFLINE".to_string()))
+                    }
+                    PollKind::Transfer(to) => format!("转让房主给 {to}"),
+                    PollKind::Seek(to) if !to.is_finite() || *to < 0.0 => {
+                        return Some((Code::Sys, "INVALID_POLL".to_string()))
+                    }
+                    PollKind::Seek(to) => format!("跳转到 {to}"),
+                    PollKind::Skip => "跳过当前视频".to_string(),
+                    PollKind::Custom => return Some((Code::Sys, "INVALID_POLL".to_string())),
+                };
+                let options = vec!["yes".to_string(), "no".to_string()];
+                let poll = room.polls.create(
+                    msg.id.clone(),
+                    kind,
+                    question,
+                    options,
+                    DEFAULT_POLL_DURATION,
+                );
+                let poll_id = poll.id;
+                let data = Data::full(Code::Poll, poll.state(false, None));
+                self.send_message(&msg.room, &data, "".to_string());
+                self.schedule_poll_close(&msg.room, poll_id, DEFAULT_POLL_DURATION, ctx);
+                // the proposer agrees with their own proposal
+                return self.handle(
+                    RoomPoll {
+                        id: msg.id,
+                        room: msg.room,
+                        cmd: PollCommand::Vote(poll_id, 0),
+                    },
+                    ctx,
+                );
+            }
+            PollCommand::Vote(poll_id, option) => {
+                let members = &room.members;
+                let threshold = room.polls.threshold;
+                let poll = match room.polls.open.get_mut(&poll_id) {
+                    Some(poll) => poll,
+                    None => return Some((Code::Sys, "POLL_NOT_EXIST".to_string())),
+                };
+                if option >= poll.options.len() {
+                    return Some((Code::Sys, "INVALID_OPTION".to_string()));
+                }
+                poll.votes.insert(msg.id, option);
+                if poll.passed(members, threshold) {
+                    self.close_poll(&msg.room, poll_id);
+                } else {
+                    let data = Data::full(Code::Poll, poll.state(false, None));
+                    self.send_message(&msg.room, &data, "".to_string());
+                }
+            }
+            PollCommand::Close(poll_id) => {
+                let creator = match room.polls.open.get(&poll_id) {
+                    Some(poll) => poll.creator.clone(),
+                    None => return Some((Code::Sys, "POLL_NOT_EXIST".to_string())),
+                };
+                if creator != msg.id && room.roomer != msg.id {
+                    return Some((Code::Sys, "NOT_ROOMER".to_string()));
+                }
+                self.close_poll(&msg.room, poll_id);
+            }
+            PollCommand::Threshold(threshold) => {
+                if room.roomer != msg.id {
+                    return Some((Code::Sys, "NOT_ROOMER".to_string()));
+                }
+                if !threshold.is_finite() {
+                    return Some((Code::Sys, "INVALID_THRESHOLD".to_string()));
+                }
+                room.polls.threshold = threshold.clamp(0.0, 1.0);
+            }
+        }
+        None
+    }
+}
+
+impl ChatServer {
+    /// Close the poll when its time is up. Poll ids restart with every room,
+    /// so the timer only fires for the room it was scheduled in.
+    fn schedule_poll_close(
+        &self,
+        room: &str,
+        poll_id: u32,
+        duration: Duration,
+        ctx: &mut Context<Self>,
+    ) {
+        let Some(created) = self.rooms.get(room).map(|r| r.created) else {
+            return;
+        };
+        let room = room.to_owned();
+        ctx.run_later(duration, move |act, _| {
+            if act.rooms.get(&room).is_some_and(|r| r.created == created) {
+                act.close_poll(&room, poll_id);
+            }
+        });
+    }
+
+    /// Close a poll, broadcast the final tally and apply governance results
+    fn close_poll(&mut self, name: &str, poll_id: u32) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let Some(poll) = room.polls.open.remove(&poll_id) else {
+            return;
+        };
+        let passed = match poll.kind {
+            PollKind::Custom => None,
+            _ => Some(poll.passed(&room.members, room.polls.threshold)),
+        };
+        self.send_message(
+            name,
+            &Data::full(Code::Poll, poll.state(true, passed)),
+            "".to_string(),
+        );
+        if passed != Some(true) {
+            return;
+        }
+        match poll.kind {
+            PollKind::Seek(to) => {
+                let Some(room) = self.rooms.get_mut(name) else {
+                    return;
+                };
+                let speed = room.playback.as_ref().map(|p| p.speed).unwrap_or(1.0);
+                room.playback = Some(Playback {
+                    progress: to,
+                    speed,
+                    updated: Instant::now(),
+                });
+                self.send_message(
+                    name,
+                    &Data::progress((to.to_string(), speed.to_string())),
+                    "".to_string(),
+                );
+                let seek = Timeline::Playback {
+                    progress: to,
+                    speed,
+                };
+                self.record(name, seek, Duration::ZERO);
+            }
+            PollKind::Transfer(to) => {
+                let actor = self.actor(&poll.creator);
+                self.transfer_roomer(name, to, actor, "poll")
+            }
+            // the queue lives on the clients, the closed poll is the signal
+            PollKind::Skip | PollKind::Custom => {}
+        }
+    }
+
+    /// Hand the room over to another member
+    fn transfer_roomer(&mut self, name: &str, to: String, by: AuditActor, via: &str) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        let is_member =
+            room.members.contains(&to) || self.broker.room(name).members.contains_key(&to);
+        if !is_member || room.roomer == to {
+            return;
+        }
+        let old = std::mem::replace(&mut room.roomer, to.clone());
+        let moved = serde_json::json!({ "from": old, "to": to, "via": via });
+        self.audit(Action::Transfer, by, Some(name), moved);
+        self.share_roomer(name, &to);
+        self.send(Data::full(Code::Roomer, false).as_str(), old);
+        self.send(Data::full(Code::Roomer, true).as_str(), to.clone());
+        let user = self.get_user(to.clone());
+        self.send_message(
+            name,
+            &Data::sys(format!("{} 成为房主", user.name.unwrap_or(to))),
+            "".to_string(),
+        );
+    }
+}
+
+/// Server is going down: notify everyone, flush rooms and close sessions
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    /// Seconds clients should wait before reconnecting
+    pub reconnect_secs: u64,
+}
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) {
+        let notice = Data::full(Code::Shutdown, msg.reconnect_secs);
+        let sys = Data::sys(format!(
+            "服务器重启中，请在 {} 秒后重新连接",
+            msg.reconnect_secs
+        ));
+        for (addr, _) in self.sessions.values() {
+            addr.do_send(Message(notice.clone()));
+            addr.do_send(Message(sys.clone()));
+        }
+        self.flush();
+        self.audit.sync();
+        for close in self.closers.values() {
+            close.do_send(Close::Restart);
+        }
+    }
+}
+
+/// Rooms flushed by the previous run. Their sessions are gone, so each one
+/// comes back empty and held for its last roomer until the grace period of
+/// empty rooms runs out.
+fn load_rooms(path: &Path) -> HashMap<String, Room> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            log::error!("failed to read rooms from {}: {err}", path.display());
+            return HashMap::new();
+        }
+    };
+    let mut rooms: HashMap<String, Room> = match serde_json::from_slice(&json) {
+        Ok(rooms) => rooms,
+        Err(err) => {
+            log::error!("failed to load rooms from {}: {err}", path.display());
+            return HashMap::new();
+        }
+    };
+    for room in rooms.values_mut() {
+        room.members.clear();
+        room.spectators.clear();
+        room.empty_since = Some(Instant::now());
+    }
+    log::info!("loaded {} rooms from {}", rooms.len(), path.display());
+    rooms
+}
+
+impl ChatServer {
+    /// Write all rooms to the state file, if one is configured. Occupied
+    /// rooms are saved as held for their roomer, like any room they leave.
+    fn flush(&mut self) {
+        let Some(path) = self.state_file.clone() else {
+            return;
+        };
+        let occupied: Vec<(String, String)> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| !room.members.is_empty())
+            .map(|(name, room)| (name.clone(), room.roomer.clone()))
+            .collect();
+        for (name, roomer) in occupied {
+            let owner = self.owner_key(&roomer);
+            let party = self.parties.for_room(&name).is_some();
+            if let Some(room) = self.rooms.get_mut(&name) {
+                room.reserved_for = owner.filter(|_| !party);
+            }
+        }
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.rooms)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, &path));
+        match result {
+            Ok(()) => log::info!("flushed {} rooms to {}", self.rooms.len(), path.display()),
+            Err(err) => log::error!("failed to flush rooms to {}: {err}", path.display()),
+        }
+    }
+}
+
+/// Session as seen by operators
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionInfo {
+    pub user: User,
+    pub rooms: Vec<String>,
+}
+
+/// List all sessions connected to this instance. Sessions of other
+/// instances are not included, ask each instance for its own
+#[derive(Message)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct AdminSessions;
+impl Handler<AdminSessions> for ChatServer {
+    type Result = MessageResult<AdminSessions>;
+
+    fn handle(&mut self, _: AdminSessions, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.sessions
+                .keys()
+                .map(|id| SessionInfo {
+                    user: self.get_user(id.clone()),
+                    rooms: self
+                        .rooms
+                        .iter()
+                        .filter(|(_, room)| room.members.contains(id))
+                        .map(|(name, _)| name.clone())
+                        .collect(),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// System message to every connected session, on all instances
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Announce {
+    pub text: String,
+}
+impl Handler<Announce> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Announce, _: &mut Context<Self>) {
+        let sys = Data::sys(format!("系统公告：{}", msg.text));
+        for (addr, _) in self.sessions.values() {
+            addr.do_send(Message(sys.clone()));
+        }
+        self.broker.publish(BrokerEvent::Everyone { payload: sys });
+    }
+}
+
+/// Close a room, its members stay connected to the server; false if there is
+/// no such room
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CloseRoom {
+    pub room: String,
+}
+impl Handler<CloseRoom> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: CloseRoom, _: &mut Context<Self>) -> Self::Result {
+        if !self.rooms.contains_key(&msg.room) {
+            return false;
+        }
+        self.send_message(
+            &msg.room,
+            &Data::sys("房间已被管理员关闭".to_owned()),
+            "".to_string(),
+        );
+        self.emit(
+            &msg.room,
+            EventKind::RoomClosed,
+            serde_json::json!({ "reason": "admin" }),
+        );
+        self.audit(
+            Action::CloseRoom,
+            AuditActor::admin(),
+            Some(&msg.room),
+            serde_json::Value::Null,
+        );
+        self.remove_room(&msg.room);
+        log::info!("room {} closed by admin", msg.room);
+        true
+    }
+}
+
+/// Disconnect a session, false if it is not connected here
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Kick {
+    pub id: String,
+}
+impl Handler<Kick> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) -> Self::Result {
+        let Some(close) = self.closers.get(&msg.id) else {
+            return false;
+        };
+        self.send(
+            &Data::sys("你已被管理员断开连接".to_owned()),
+            msg.id.clone(),
+        );
+        close.do_send(Close::Kicked);
+        log::info!("session {} disconnected by admin", msg.id);
+        let target = serde_json::to_value(self.actor(&msg.id)).unwrap_or_default();
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(&msg.id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if rooms.is_empty() {
+            self.audit(Action::Kick, AuditActor::admin(), None, target);
+        } else {
+            for room in rooms {
+                self.audit(
+                    Action::Kick,
+                    AuditActor::admin(),
+                    Some(&room),
+                    target.clone(),
+                );
+            }
+        }
+        true
+    }
+}
+
+/// Hand a room to one of its members, false if either does not exist
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct AdminTransfer {
+    pub room: String,
+    pub to: String,
+}
+impl Handler<AdminTransfer> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: AdminTransfer, _: &mut Context<Self>) -> Self::Result {
+        let Some(room) = self.rooms.get(&msg.room) else {
+            return false;
+        };
+        if !room.members.contains(&msg.to)
+            && !self.broker.room(&msg.room).members.contains_key(&msg.to)
+        {
+            return false;
+        }
+        self.transfer_roomer(&msg.room, msg.to, AuditActor::admin(), "admin");
+        true
+    }
+}
+
+/// Handler for `ListRooms` message.
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let mut rooms = Vec::new();
+
+        for (key, room) in &self.rooms {
+            if room.meta.public {
+                rooms.push(key.to_owned())
+            }
+        }
+
+        MessageResult(rooms)
+    }
+}
+
+/// Change the directory metadata of a room, roomer only
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct SetRoomMeta {
+    pub id: String,
+    pub room: String,
+    pub command: RoomCommand,
+}
+impl Handler<SetRoomMeta> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: SetRoomMeta, _: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) if room.roomer == msg.id => room,
+            Some(_) => return Some((Code::Sys, "NOT_ROOMER".to_string())),
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        if let Err(err) = msg.command.apply(&mut room.meta) {
+            return Some((Code::Sys, err));
+        }
+        let meta = serde_json::to_value(&room.meta).unwrap_or_default();
+        self.audit(Action::RoomMeta, self.actor(&msg.id), Some(&msg.room), meta);
+        let summary = Data::full(Code::Directory, self.summary(&msg.room));
+        self.send_message(&msg.room, &summary, "".to_string());
+        None
+    }
+}
+
+/// One page of public rooms
+#[derive(Message)]
+#[rtype(result = "DirectoryPage")]
+pub struct Directory {
+    pub query: DirectoryQuery,
+}
+impl Handler<Directory> for ChatServer {
+    type Result = MessageResult<Directory>;
+
+    fn handle(&mut self, msg: Directory, _: &mut Context<Self>) -> Self::Result {
+        let rooms = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.meta.public)
+            .filter_map(|(name, _)| self.summary(name))
+            .collect();
+        MessageResult(msg.query.page(rooms))
+    }
+}
+
+impl ChatServer {
+    fn summary(&self, name: &str) -> Option<RoomSummary> {
+        let room = self.rooms.get(name)?;
+        Some(RoomSummary {
+            name: name.to_owned(),
+            meta: room.meta.clone(),
+            members: room.members.len(),
+            spectators: room.spectators.len(),
+            created: room.created,
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<RoomInfo>")]
+pub struct ListMembers {
+    pub room_id: String,
+}
+
+/// Handler for `ListRooms` message.
+impl Handler<ListMembers> for ChatServer {
+    type Result = Option<RoomInfo>;
+
+    fn handle(
+        &mut self,
+        ListMembers { room_id }: ListMembers,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        let room = self.rooms.get(&room_id)?;
+        // members on other instances are only known to the broker
+        let mut remote = self.broker.room(&room_id).members;
+        let mut members: Vec<User> = room
+            .members
+            .iter()
+            .map(|id| {
+                remote.remove(id);
+                self.get_user(id.clone())
+            })
+            .collect();
+        let roomer = match remote.get(&room.roomer) {
+            Some(user) => user.clone(),
+            None => self.get_user(room.roomer.clone()),
+        };
+        members.extend(remote.into_values());
+        Some(RoomInfo {
+            roomer,
+            members,
+            spectators: room.spectators.len(),
+        })
+    }
+}
+
+impl ChatServer {
+    fn get_user(&self, id: String) -> User {
+        let presence = self.presence.get(&id).copied().unwrap_or_default();
+        let (name, avatar) = match self.sessions.get(&id) {
+            Some((_, (name, avatar))) => (name.to_owned(), avatar.to_owned()),
+            None => (None, None),
+        };
+        // only avatars we serve, everyone else gets an identicon
+        let avatar = avatar
+            .filter(|a| avatar::is_ours(a))
+            .unwrap_or_else(|| avatar::identicon_url(&id));
+        User {
             id,
+            name,
+            avatar: Some(avatar),
+            presence,
+        }
+    }
+}
+/// Handler for `ListRooms` message.
+impl Handler<Count> for ChatServer {
+    type Result = MessageResult<Count>;
+
+    fn handle(&mut self, _: Count, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(format!("{:?}", self.visitor_count))
+    }
+}
+
+/// Capacity and waiting queue commands, roomer only
+#[derive(Debug, Clone)]
+pub enum QueueCommand {
+    /// Room specific capacity, `None` falls back to the server default
+    Capacity(Option<usize>),
+    /// Let a queued user (id or name) in regardless of capacity, the head of
+    /// the queue when `None`
+    Admit(Option<String>),
+    /// Send the queue to the roomer
+    List,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct RoomQueue {
+    pub id: String,
+    pub room: String,
+    pub command: QueueCommand,
+}
+impl Handler<RoomQueue> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: RoomQueue, _: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) if room.roomer == msg.id => room,
+            Some(_) => return Some((Code::Sys, "NOT_ROOMER".to_string())),
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        match msg.command {
+            QueueCommand::Capacity(max) => {
+                room.max_members = max;
+                let capacity = serde_json::json!({ "max_members": max });
+                self.audit(
+                    Action::Capacity,
+                    self.actor(&msg.id),
+                    Some(&msg.room),
+                    capacity,
+                );
+                self.admit_waiting(&msg.room);
+            }
+            QueueCommand::Admit(user) => {
+                let queue = room.queue.clone();
+                let target = match user {
+                    None => queue.front().cloned(),
+                    Some(user) => queue.into_iter().find(|id| {
+                        *id == user || self.get_user(id.clone()).name.as_deref() == Some(&user)
+                    }),
+                };
+                match target {
+                    Some(id) => {
+                        let admitted = serde_json::to_value(self.actor(&id)).unwrap_or_default();
+                        self.audit(
+                            Action::Admit,
+                            self.actor(&msg.id),
+                            Some(&msg.room),
+                            admitted,
+                        );
+                        self.admit(&msg.room, id)
+                    }
+                    None => return Some((Code::Sys, "NOT_QUEUED".to_string())),
+                }
+            }
+            QueueCommand::List => {
+                let waiting: Vec<User> = room
+                    .queue
+                    .clone()
+                    .into_iter()
+                    .map(|id| self.get_user(id))
+                    .collect();
+                self.send(&Data::full(Code::Waiting, waiting), msg.id);
+            }
+        }
+        None
+    }
+}
+
+/// Outcome of `Join`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Joined {
+    Member {
+        roomer: bool,
+    },
+    /// Room is full, 1-based position in its waiting queue
+    Queued(usize),
+    /// Empty room held for its previous owner
+    Reserved,
+    /// Following the room read-only
+    Spectator,
+    /// No place left for another spectator
+    SpectatorsFull,
+    /// Spectators cannot create rooms
+    NoSuchRoom,
+}
+
+/// Join room, if room does not exists create new one.
+#[derive(Message)]
+#[rtype(result = "Joined")]
+pub struct Join {
+    /// Client ID
+    pub id: String,
+
+    /// Room name
+    pub name: String,
+
+    /// Follow the room read-only
+    pub spectator: bool,
+}
+/// Join room, send disconnect message to old room
+/// send join message to new room
+impl Handler<Join> for ChatServer {
+    type Result = MessageResult<Join>;
+
+    fn handle(&mut self, msg: Join, ctx: &mut Context<Self>) -> Self::Result {
+        let Join {
+            id,
+            name,
+            spectator,
+        } = msg;
+        if spectator {
+            match self.rooms.get(&name) {
+                None => return MessageResult(Joined::NoSuchRoom),
+                Some(room) if room.spectators.contains(&id) => {
+                    return MessageResult(Joined::Spectator)
+                }
+                Some(room) => {
+                    let full = self
+                        .max_spectators
+                        .is_some_and(|max| room.spectators.len() >= max);
+                    if full {
+                        return MessageResult(Joined::SpectatorsFull);
+                    }
+                }
+            }
+        }
+        let user = self.get_user(id.clone());
+        let owner = self.owner_key(&id);
+        let reserved = self.rooms.get(&name).is_some_and(|room| {
+            room.members.is_empty() && room.reserved_for.is_some() && room.reserved_for != owner
+        });
+        if reserved && !spectator {
+            return MessageResult(Joined::Reserved);
+        }
+        let mut rooms = Vec::new();
+        self.dequeue(&id);
+        self.stop_spectating(&id);
+        // remove session from all rooms
+        for (n, Room { members, .. }) in &mut self.rooms {
+            if members.remove(&id) {
+                rooms.push(n.to_owned());
+                self.broker.leave(n, &id);
+            }
+        }
+        // send message to other users
+        for room in rooms {
+            self.audit(
+                Action::Leave,
+                self.actor(&id),
+                Some(&room),
+                serde_json::Value::Null,
+            );
+            self.emit(&room, EventKind::MemberLeft, &user);
+            self.send_message(
+                &room,
+                &Data::sys(format!(
+                    "{} 退出房间",
+                    user.name.clone().unwrap_or(id.to_string())
+                )),
+                "".to_string(),
+            );
+            self.update_hold(&room, ctx);
+            self.update_ready(&room);
+            self.admit_waiting(&room);
+            if self.rooms.get(&room).is_some_and(|r| r.members.is_empty()) {
+                self.reserve(&room, &id, owner.clone());
+            }
+        }
+        if spectator {
+            self.spectate(&name, id);
+            return MessageResult(Joined::Spectator);
+        }
+        if let Some(room) = self.rooms.get_mut(&name) {
+            let full = room
+                .max_members
+                .or(self.max_members)
+                .is_some_and(|max| room.members.len() >= max);
+            if full {
+                room.queue.push_back(id);
+                return MessageResult(Joined::Queued(room.queue.len()));
+            }
+        }
+        MessageResult(Joined::Member {
+            roomer: self.enter(&name, id),
+        })
+    }
+}
+
+impl ChatServer {
+    /// Add a session to a room, creating it if needed. Returns whether the
+    /// session became roomer.
+    fn enter(&mut self, name: &str, id: String) -> bool {
+        let name = name.to_owned();
+        let user = self.get_user(id.clone());
+        self.presence.insert(id.clone(), Presence::Watching);
+        let mut roomer = false;
+        if let Some(room) = self.rooms.get_mut(&name) {
+            let mut old = None;
+            // first one back into an empty room takes it over
+            if room.members.is_empty() {
+                old = Some(std::mem::replace(&mut room.roomer, id.clone())).filter(|o| *o != id);
+                room.empty_since = None;
+                room.reserved_for = None;
+                roomer = true;
+            }
+            room.members.insert(id.clone());
+            if let Some(old) = old {
+                let moved = serde_json::json!({ "from": old, "to": id, "via": "empty_room" });
+                self.audit(Action::Transfer, AuditActor::system(), Some(&name), moved);
+            }
+            if roomer {
+                self.share_roomer(&name, &id);
+            }
+        } else {
+            // the room may already live on another instance
+            let shared = self.broker.room(&name);
+            let mut room = Room::new(id.clone());
+            match shared.roomer.filter(|r| shared.members.contains_key(r)) {
+                Some(remote) => room.roomer = remote,
+                None => {
+                    roomer = true;
+                    self.share_roomer(&name, &id);
+                    self.webhooks
+                        .emit(&[], &name, EventKind::RoomCreated, &user);
+                }
+            }
+            room.playback = shared.playback.map(Playback::from);
+            self.rooms.insert(name.clone(), room);
+        }
+        self.broker.join(&name, &self.get_user(id.clone()));
+        self.emit(&name, EventKind::MemberJoined, &user);
+        let joined = serde_json::json!({ "roomer": roomer });
+        self.audit(Action::Join, self.actor(&id), Some(&name), joined);
+
+        self.send_message(
+            &name,
+            &Data::sys(format!(
+                "{} 进入房间",
+                user.name.clone().unwrap_or(id.clone())
+            )),
+            id.clone(),
+        );
+        self.catch_up(&name, &id);
+        roomer
+    }
+
+    /// Follow a room read-only, unseen by the members
+    fn spectate(&mut self, name: &str, id: String) {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        room.spectators.insert(id.clone());
+        let joined = serde_json::json!({ "spectator": true });
+        self.audit(Action::Join, self.actor(&id), Some(name), joined);
+        self.catch_up(name, &id);
+    }
+
+    /// Stop following every room as a spectator
+    fn stop_spectating(&mut self, id: &str) {
+        let mut left = Vec::new();
+        for (name, room) in &mut self.rooms {
+            if room.spectators.remove(id) {
+                left.push(name.clone());
+            }
+        }
+        for name in left {
+            let detail = serde_json::json!({ "spectator": true });
+            self.audit(Action::Leave, self.actor(id), Some(&name), detail);
+        }
+    }
+
+    /// Bring a newcomer up to date with a replay or watch party
+    fn catch_up(&self, name: &str, id: &str) {
+        let replaying = self
+            .rooms
+            .get(name)
+            .filter(|room| room.replay.is_some())
+            .and_then(|room| room.playback.as_ref());
+        if let Some(playback) = replaying {
+            let position = if playback.speed == 0.0 {
+                playback.progress
+            } else {
+                playback.position()
+            };
+            let data = Data::progress((position.to_string(), playback.speed.to_string()));
+            self.send(&data, id.to_owned());
+        }
+        if let Some(party) = self.parties.for_room(name).filter(|p| p.opened) {
+            self.send(
+                &Data::full(Code::Party, party.info(now_millis())),
+                id.to_owned(),
+            );
+            self.send(&Data::full(Code::Share, &party.media), id.to_owned());
+        }
+    }
+
+    /// Mark a room as just emptied, held for `owner` when `id` was its roomer
+    fn reserve(&mut self, name: &str, id: &str, owner: Option<String>) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.empty_since = Some(Instant::now());
+            // party rooms are open to whoever arrives first
+            let party = self.parties.for_room(name).is_some();
+            room.reserved_for = if room.roomer == id && !party {
+                owner
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Stable key of the person behind a session, only signed in users have
+    /// one: a guest nickname can be taken by anyone
+    fn owner_key(&self, id: &str) -> Option<String> {
+        self.identities.get(id).cloned()
+    }
+
+    /// Sessions of the user behind `id`: every session sharing its owner key,
+    /// or `id` alone for guests
+    fn live_sessions(&self, id: &str) -> Vec<String> {
+        let Some(key) = self.owner_key(id) else {
+            // sessions of other instances are reached through the broker
+            let live = self.sessions.contains_key(id) || !id.starts_with(self.broker.instance());
+            return live.then(|| id.to_owned()).into_iter().collect();
+        };
+        let mut sessions: Vec<String> = self
+            .identities
+            .iter()
+            .filter(|(session, owner)| **owner == key && self.sessions.contains_key(*session))
+            .map(|(session, _)| session.clone())
+            .collect();
+        sessions.sort();
+        sessions
+    }
+
+    fn touch(&mut self, name: &str) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.last_activity = Instant::now();
+            room.idle_warned = false;
+        }
+    }
+
+    /// Apply the lifecycle rules to every room
+    fn sweep(&mut self) {
+        let grace = self.lifecycle.empty_grace();
+        let expiry = self.lifecycle.idle_expiry();
+        let warning = self.lifecycle.warning();
+        let mut expired = Vec::new();
+        let mut warn = Vec::new();
+        for (name, room) in &mut self.rooms {
+            // kept open for a party about to start even when empty or
+            // quiet, parties further ahead open the room again when due
+            if self.parties.for_room(name).is_some_and(|p| p.opened) {
+                continue;
+            }
+            if room.members.is_empty() {
+                let since = *room.empty_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= grace && room.queue.is_empty() {
+                    expired.push((name.clone(), false));
+                }
+                continue;
+            }
+            let Some(expiry) = expiry else {
+                continue;
+            };
+            let idle = room.last_activity.elapsed();
+            if idle >= expiry {
+                expired.push((name.clone(), true));
+            } else if idle + warning >= expiry && !room.idle_warned {
+                room.idle_warned = true;
+                warn.push((name.clone(), (expiry - idle).as_secs()));
+            }
+        }
+        for (name, secs) in warn {
+            self.send_message(&name, &Data::full(Code::Expiring, secs), "".to_string());
+            self.send_message(
+                &name,
+                &Data::sys(format!("房间长时间无活动，将在 {} 秒后关闭", secs)),
+                "".to_string(),
+            );
+        }
+        for (name, idle) in expired {
+            if idle {
+                self.send_message(&name, &Data::full(Code::Expiring, 0), "".to_string());
+                self.send_message(
+                    &name,
+                    &Data::sys("房间因长时间无活动已关闭"),
+                    "".to_string(),
+                );
+                log::info!("room {name} closed after being idle");
+            }
+            let reason = serde_json::json!({ "reason": if idle { "idle" } else { "empty" } });
+            self.emit(&name, EventKind::RoomClosed, &reason);
+            self.audit(Action::CloseRoom, AuditActor::system(), Some(&name), reason);
+            self.remove_room(&name);
+        }
+    }
+
+    /// Drop a closed room, telling whoever waits for or follows it
+    fn remove_room(&mut self, name: &str) {
+        self.stop_recording(name);
+        let Some(room) = self.rooms.remove(name) else {
+            return;
+        };
+        for id in &room.members {
+            self.broker.leave(name, id);
+        }
+        for id in room.queue {
+            self.send(&Data::sys("等待的房间已关闭"), id);
+        }
+        for id in room.spectators {
+            self.send(&Data::sys("旁观的房间已关闭"), id);
+        }
+    }
+
+    /// Drop a session from every waiting queue
+    fn dequeue(&mut self, id: &str) {
+        let mut moved = Vec::new();
+        for (name, room) in &mut self.rooms {
+            let before = room.queue.len();
+            room.queue.retain(|queued| queued != id);
+            if room.queue.len() != before {
+                moved.push(name.clone());
+            }
+        }
+        for name in moved {
+            self.notify_queue(&name);
+        }
+    }
+
+    /// Tell everyone waiting for `name` their position
+    fn notify_queue(&self, name: &str) {
+        if let Some(room) = self.rooms.get(name) {
+            for (i, id) in room.queue.iter().enumerate() {
+                self.send(&Data::full(Code::Queue, i + 1), id.clone());
+            }
+        }
+    }
+
+    /// Fill free places from the head of the queue
+    fn admit_waiting(&mut self, name: &str) {
+        loop {
+            let Some(room) = self.rooms.get_mut(name) else {
+                return;
+            };
+            let full = room
+                .max_members
+                .or(self.max_members)
+                .is_some_and(|max| room.members.len() >= max);
+            if full {
+                return;
+            }
+            let Some(id) = room.queue.pop_front() else {
+                return;
+            };
+            self.admit(name, id);
+        }
+    }
+
+    /// Move a queued session into the room
+    fn admit(&mut self, name: &str, id: String) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.queue.retain(|queued| *queued != id);
+        }
+        let roomer = self.enter(name, id.clone());
+        self.send(&Data::full(Code::Queue, 0), id.clone());
+        self.send(&Data::full(Code::Roomer, roomer), id.clone());
+        self.send(&Data::sys("您已加入房间"), id);
+        self.notify_queue(name);
+    }
+}
+
+/// Why a party was not scheduled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartyRejected {
+    /// The room belongs to someone other than the organizer
+    RoomTaken,
+    /// The organizer has too many upcoming parties
+    TooMany,
+}
+
+/// Add a party to the schedule
+#[derive(Message)]
+#[rtype(result = "Result<Party, PartyRejected>")]
+pub struct ScheduleParty(pub Party);
+
+impl Handler<ScheduleParty> for ChatServer {
+    type Result = Result<Party, PartyRejected>;
+
+    fn handle(
+        &mut self,
+        ScheduleParty(party): ScheduleParty,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        if !self.parties.allows(&party.organizer) {
+            return Err(PartyRejected::TooMany);
+        }
+        if !self.may_organize(&party.room, &party.organizer) {
+            return Err(PartyRejected::RoomTaken);
+        }
+        log::info!("party {} scheduled in room {}", party.id, party.room);
+        self.parties.parties.push(party.clone());
+        self.parties.save();
+        self.tick_parties();
+        Ok(party)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CancelParty {
+    pub id: String,
+}
+
+impl Handler<CancelParty> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: CancelParty, _: &mut Context<Self>) -> Self::Result {
+        let Some(i) = self.parties.parties.iter().position(|p| p.id == msg.id) else {
+            return false;
+        };
+        let party = self.parties.parties.remove(i);
+        self.parties.save();
+        if party.opened {
+            self.send_message(
+                &party.room,
+                &Data::sys(format!("观影派对「{}」已取消", party.title)),
+                "".to_string(),
+            );
+        }
+        true
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<Party>")]
+pub struct GetParty {
+    pub id: String,
+}
+
+impl Handler<GetParty> for ChatServer {
+    type Result = Option<Party>;
+
+    fn handle(&mut self, msg: GetParty, _: &mut Context<Self>) -> Self::Result {
+        self.parties.get(&msg.id).cloned()
+    }
+}
+
+/// Upcoming public parties, soonest first
+#[derive(Message)]
+#[rtype(result = "Vec<Party>")]
+pub struct ListParties;
+
+impl Handler<ListParties> for ChatServer {
+    type Result = MessageResult<ListParties>;
+
+    fn handle(&mut self, _: ListParties, _: &mut Context<Self>) -> Self::Result {
+        let mut parties: Vec<Party> = self
+            .parties
+            .parties
+            .iter()
+            .filter(|p| p.public)
+            .cloned()
+            .collect();
+        parties.sort_by_key(|p| p.start_at);
+        MessageResult(parties)
+    }
+}
+
+impl ChatServer {
+    /// Whether `organizer` may hold a party in `room`: the admin always, and
+    /// anyone else if the room does not exist yet, is theirs, or was opened
+    /// for another of their parties
+    fn may_organize(&self, room: &str, organizer: &str) -> bool {
+        let Some(existing) = self.rooms.get(room) else {
+            return true;
+        };
+        organizer == "admin"
+            || existing.reserved_for.as_deref() == Some(organizer)
+            || existing.members.contains(&existing.roomer)
+                && self.owner_key(&existing.roomer).as_deref() == Some(organizer)
+            || self
+                .parties
+                .parties
+                .iter()
+                .any(|p| p.room == room && p.opened && p.organizer == organizer)
+    }
+
+    /// Open party rooms, send reminders and start playback on time
+    fn tick_parties(&mut self) {
+        let now = now_millis();
+        let open_before = self.parties.open_before.as_millis() as u64;
+        let mut missed = Vec::new();
+        let mut taken = Vec::new();
+        for i in 0..self.parties.parties.len() {
+            let party = &self.parties.parties[i];
+            if now
+                > party
+                    .start_at
+                    .saturating_add(PARTY_MISSED.as_millis() as u64)
+            {
+                missed.push(party.id.clone());
+                continue;
+            }
+            if !party.opened && now.saturating_add(open_before) >= party.start_at {
+                // someone else may have taken the room since it was scheduled
+                if !self.may_organize(&party.room, &party.organizer) {
+                    taken.push(party.id.clone());
+                    continue;
+                }
+                self.open_party(i);
+            }
+            let party = &mut self.parties.parties[i];
+            if !party.opened {
+                continue;
+            }
+            let remaining = party.start_at.saturating_sub(now);
+            if remaining <= COUNTDOWN.as_millis() as u64 {
+                if !party.counting_down {
+                    party.counting_down = true;
+                    let party = party.clone();
+                    self.start_party(&party, now);
+                }
+                continue;
+            }
+            let secs = remaining.div_ceil(1000);
+            let due = PARTY_REMINDERS.iter().copied().filter(|t| secs <= *t).min();
+            if due.is_some() && (party.announced.is_none() || due < party.announced) {
+                party.announced = due;
+                let (room, info) = (party.room.clone(), party.info(now));
+                let info = Data::full(Code::Party, info);
+                let notice =
+                    Data::sys(format!("观影派对「{}」将在 {} 秒后开始", party.title, secs));
+                self.send_message(&room, &info, "".to_string());
+                self.send_message(&room, &notice, "".to_string());
+            }
+        }
+        // started parties are done once their countdown has run out
+        let before = self.parties.parties.len();
+        self.parties.parties.retain(|p| {
+            let started = p.counting_down && p.start_at <= now;
+            !started && !missed.contains(&p.id) && !taken.contains(&p.id)
+        });
+        for id in missed {
+            log::warn!("party {id} dropped, its start was missed");
+        }
+        for id in taken {
+            log::warn!("party {id} dropped, its room belongs to someone else");
+        }
+        if self.parties.parties.len() != before {
+            self.parties.save();
+        }
+    }
+
+    /// Create the room of a party ahead of its start, or dress up an
+    /// existing one, and share the media with whoever is there
+    fn open_party(&mut self, i: usize) {
+        let party = &mut self.parties.parties[i];
+        party.opened = true;
+        let party = party.clone();
+        log::info!("opening room {} for party {}", party.room, party.id);
+        if !self.rooms.contains_key(&party.room) {
+            let created = serde_json::json!({ "party": party.id });
+            self.webhooks
+                .emit(&[], &party.room, EventKind::RoomCreated, created);
+        }
+        let room = self.rooms.entry(party.room.clone()).or_insert_with(|| {
+            let mut room = Room::new(String::new());
+            room.members.clear();
+            room.empty_since = Some(Instant::now());
+            room
+        });
+        room.meta.public = party.public;
+        room.reserved_for = None;
+        // over long values are simply left out of the directory
+        let _ = RoomCommand::Title(Some(party.title.clone())).apply(&mut room.meta);
+        let _ = RoomCommand::Media(Some(party.media.clone())).apply(&mut room.meta);
+        if let Some(description) = &party.description {
+            let _ = RoomCommand::Description(Some(description.clone())).apply(&mut room.meta);
+        }
+        let shared = serde_json::json!({ "party": party.id, "link": party.media });
+        self.emit(&party.room, EventKind::MediaShared, shared);
+        let link = party.media.clone();
+        self.record(&party.room, Timeline::Share { link }, Duration::ZERO);
+        self.send_message(
+            &party.room,
+            &Data::full(Code::Share, &party.media),
+            "".to_string(),
+        );
+    }
+
+    /// Broadcast the start countdown, playback begins at `start_at`
+    fn start_party(&mut self, party: &Party, now: u64) {
+        let Some(room) = self.rooms.get_mut(&party.room) else {
+            return;
+        };
+        let start_at = party.start_at.max(now);
+        room.ready_check = None;
+        room.last_activity = Instant::now();
+        room.playback = Some(Playback {
+            progress: 0.0,
+            speed: 1.0,
+            updated: Instant::now() + Duration::from_millis(start_at - now),
+        });
+        let shared = SharedPlayback {
+            progress: 0.0,
+            speed: 1.0,
+            updated: start_at,
+        };
+        self.broker.set_playback(&party.room, &shared);
+        let countdown = Countdown {
+            start_at,
+            now,
+            progress: 0.0,
+        };
+        let started =
+            serde_json::json!({ "party": party.id, "title": party.title, "start_at": start_at });
+        self.emit(&party.room, EventKind::PlaybackStarted, started);
+        let playback = Timeline::Playback {
+            progress: 0.0,
+            speed: 1.0,
+        };
+        self.record(&party.room, playback, Duration::from_millis(start_at - now));
+        self.send_message(
+            &party.room,
+            &Data::full(Code::Countdown, countdown),
+            "".to_string(),
+        );
+        self.send_message(
+            &party.room,
+            &Data::sys(format!("观影派对「{}」即将开始", party.title)),
+            "".to_string(),
+        );
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct RoomWebhook {
+    pub id: String,
+    pub room: String,
+    pub command: WebhookCommand,
+}
+impl Handler<RoomWebhook> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: RoomWebhook, _: &mut Context<Self>) -> Self::Result {
+        if !self.webhooks.room_hooks() {
+            return Some((Code::Sys, "WEBHOOKS_DISABLED".to_string()));
+        }
+        let max = self.webhooks.max_room_hooks();
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) if room.roomer == msg.id => room,
+            Some(_) => return Some((Code::Sys, "NOT_ROOMER".to_string())),
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        let hooks = match msg.command {
+            WebhookCommand::Add(url, events) => {
+                if !url.starts_with("https://") {
+                    return Some((Code::Sys, "INVALID_SCHEME:webhook".to_string()));
+                }
+                if room.webhooks.iter().any(|hook| hook.url == url) {
+                    return Some((Code::Sys, "WEBHOOK_EXISTS".to_string()));
+                }
+                if room.webhooks.len() >= max {
+                    return Some((Code::Sys, "TOO_MANY:webhooks".to_string()));
+                }
+                let hook = Hook {
+                    url,
+                    secret: new_secret(),
+                    events,
+                    rooms: Vec::new(),
+                };
+                // the only time the secret is shown
+                let mut info = HookInfo::from(&hook);
+                info.secret = Some(hook.secret.clone());
+                room.webhooks.push(hook);
+                vec![info]
+            }
+            WebhookCommand::Remove(url) => {
+                let before = room.webhooks.len();
+                room.webhooks.retain(|hook| hook.url != url);
+                if room.webhooks.len() == before {
+                    return Some((Code::Sys, "NO_SUCH_WEBHOOK".to_string()));
+                }
+                room.webhooks.iter().map(HookInfo::from).collect()
+            }
+            WebhookCommand::List => room.webhooks.iter().map(HookInfo::from).collect(),
+        };
+        self.send(&Data::full(Code::Webhooks, hooks), msg.id);
+        None
+    }
+}
+
+pub enum RecordCommand {
+    Start,
+    Stop,
+}
+
+/// Start or stop recording the room timeline, roomer only
+#[derive(Message)]
+#[rtype(result = "Option<(Code,String)>")]
+pub struct Record {
+    pub id: String,
+    pub room: String,
+    pub command: RecordCommand,
+}
+impl Handler<Record> for ChatServer {
+    type Result = Option<(Code, String)>;
+
+    fn handle(&mut self, msg: Record, ctx: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(room) if room.roomer == msg.id => room,
+            Some(_) => return Some((Code::Sys, "NOT_ROOMER".to_string())),
+            None => return Some((Code::Sys, "ROOM_NOT_EXIST".to_string())),
+        };
+        match msg.command {
+            RecordCommand::Start => {
+                if room.recorder.is_some() {
+                    return Some((Code::Sys, "ALREADY_RECORDING".to_string()));
+                }
+                if room.replay.is_some() {
+                    return Some((Code::Sys, "REPLAYING".to_string()));
+                }
+                let mut recorder = Recorder::start();
+                // start from where the room is
+                if let Some(p) = &room.playback {
+                    let progress = if p.speed == 0.0 {
+                        p.progress
+                    } else {
+                        p.position()
+                    };
+                    let speed = p.speed;
+                    recorder.push(Timeline::Playback { progress, speed }, Duration::ZERO);
+                }
+                let info = recorder.info(&msg.room, true);
+                let (name, id) = (msg.room.clone(), recorder.id.clone());
+                ctx.run_later(self.recordings.max_duration(), move |act, _| {
+                    let current = act.rooms.get(&name).and_then(|r| r.recorder.as_ref());
+                    if current.is_some_and(|r| r.id == id) {
+                        act.stop_recording(&name);
+                    }
+                });
+                room.recorder = Some(recorder);
+                self.send_message(
+                    &msg.room,
+                    &Data::full(Code::Recording, info),
+                    "".to_string(),
+                );
+                self.send_message(&msg.room, &Data::sys("房间开始录制"), "".to_string());
+                None
+            }
+            RecordCommand::Stop => match self.stop_recording(&msg.room) {
+                true => None,
+                false => Some((Code::Sys, "NOT_RECORDING".to_string())),
+            },
+        }
+    }
+}
+
+/// A stopped recording was written, or failed to be
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordingSaved {
+    pub room: String,
+    pub info: RecordingInfo,
+    pub saved: Result<(), String>,
+}
+impl Handler<RecordingSaved> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordingSaved, _: &mut Context<Self>) {
+        let name = &msg.room;
+        if let Err(err) = msg.saved {
+            log::error!("failed to save recording {}: {err}", msg.info.id);
+            self.send_message(name, &Data::sys("录制保存失败"), "".to_string());
+            return;
+        }
+        let notice = Data::sys(format!("录制已结束，使用 /replay {} 回放", msg.info.id));
+        self.send_message(name, &Data::full(Code::Recording, msg.info), "".to_string());
+        self.send_message(name, &notice, "".to_string());
+    }
+}
+
+/// Replay a recording into a new room, returns the room name or an error
+#[derive(Message)]
+#[rtype(result = "Result<String, String>")]
+pub struct StartReplay {
+    pub id: String,
+    pub recording: String,
+}
+impl Handler<StartReplay> for ChatServer {
+    type Result = Result<String, String>;
+
+    fn handle(&mut self, msg: StartReplay, ctx: &mut Context<Self>) -> Self::Result {
+        let recording = self.recordings.load(&msg.recording).map_err(|err| {
+            log::info!("recording {} not loaded: {err}", msg.recording);
+            "NO_SUCH_RECORDING".to_string()
+        })?;
+        let base = format!("replay-{}", recording.id);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.rooms.contains_key(&name) {
+            n += 1;
+            name = format!("{base}-{n}");
+        }
+        let mut room = Room::new(String::new());
+        room.members.clear();
+        room.empty_since = Some(Instant::now());
+        let title = recording.title.clone().unwrap_or(recording.room.clone());
+        room.meta.title = Some(format!("回放：{title}"));
+        room.replay = Some(Replay::new(recording));
+        self.rooms.insert(name.clone(), room);
+        log::info!("session {} replays {} in {name}", msg.id, msg.recording);
+        self.schedule_replay(&name, ctx);
+        Ok(name)
+    }
+}
+
+impl ChatServer {
+    /// Add to the timeline if the room is being recorded, `delay` for
+    /// events that take effect later
+    fn record(&mut self, name: &str, event: Timeline, delay: Duration) {
+        let max_events = self.recordings.max_events;
+        let max_duration = self.recordings.max_duration();
+        let Some(recorder) = self.rooms.get_mut(name).and_then(|r| r.recorder.as_mut()) else {
+            return;
+        };
+        recorder.push(event, delay);
+        if recorder.events.len() >= max_events || recorder.started.elapsed() >= max_duration {
+            self.stop_recording(name);
+        }
+    }
+
+    /// Save the timeline off the actor thread, the room learns where to
+    /// find it once it is written; false if the room was not being recorded
+    fn stop_recording(&mut self, name: &str) -> bool {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return false;
+        };
+        let Some(recorder) = room.recorder.take() else {
+            return false;
+        };
+        let info = recorder.info(name, false);
+        let title = room.meta.title.clone();
+        let (dir, room, me) = (
+            self.recordings.dir.clone(),
+            name.to_owned(),
+            self.me.clone(),
         );
-        roomer
+        actix::spawn(async move {
+            let saving = room.clone();
+            let saved =
+                actix_web::rt::task::spawn_blocking(move || recorder.save(&dir, &saving, title))
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|saved| saved.map_err(|err| err.to_string()));
+            if let Some(me) = me {
+                me.do_send(RecordingSaved { room, info, saved });
+            }
+        });
+        true
+    }
+
+    /// Wake up for the next replayed event
+    fn schedule_replay(&mut self, name: &str, ctx: &mut Context<Self>) {
+        let Some(replay) = self.rooms.get(name).and_then(|r| r.replay.as_ref()) else {
+            return;
+        };
+        let started = replay.started;
+        match replay.wait() {
+            Some(wait) => {
+                let name = name.to_owned();
+                ctx.run_later(wait, move |act, ctx| act.replay_step(&name, started, ctx));
+            }
+            None => {
+                let done = Data::sys(format!("回放 {} 已结束", replay.recording));
+                if let Some(room) = self.rooms.get_mut(name) {
+                    room.replay = None;
+                }
+                self.send_message(name, &done, "".to_string());
+            }
+        }
+    }
+
+    /// Play the due events of the replay started at `started`
+    fn replay_step(&mut self, name: &str, started: Instant, ctx: &mut Context<Self>) {
+        let Some(replay) = self
+            .rooms
+            .get_mut(name)
+            .and_then(|r| r.replay.as_mut())
+            .filter(|r| r.started == started)
+        else {
+            return;
+        };
+        for event in replay.due() {
+            self.play(name, event);
+        }
+        self.schedule_replay(name, ctx);
+    }
+
+    fn play(&mut self, name: &str, event: Timeline) {
+        let data = match event {
+            Timeline::Playback { progress, speed } => {
+                if let Some(room) = self.rooms.get_mut(name) {
+                    room.playback = Some(Playback {
+                        progress,
+                        speed,
+                        updated: Instant::now(),
+                    });
+                }
+                Data::progress((progress.to_string(), speed.to_string()))
+            }
+            Timeline::Speed { speed } => Data::full(Code::Speed, speed),
+            Timeline::Share { link } => Data::full(Code::Share, link),
+            Timeline::Chat {
+                id,
+                name: speaker,
+                text,
+            } => {
+                // older recordings only kept the name
+                let id = if id.is_empty() { speaker.clone() } else { id };
+                let speaker = Speaker {
+                    id: id.clone(),
+                    name: speaker,
+                };
+                let speaker = Data::full(Code::Speaker, speaker);
+                self.send_message(name, &speaker, "".to_string());
+                Data::msg(id, text)
+            }
+        };
+        self.touch(name);
+        self.send_message(name, &data, "".to_string());
+    }
+}
+
+/// Follow the broadcasts of a room, see `feed::events`
+#[derive(Message)]
+#[rtype(result = "Result<FeedStream, WatchError>")]
+pub struct Watch {
+    pub room: String,
+    pub viewer: Viewer,
+    /// `Last-Event-ID` of a reconnecting client
+    pub last_seen: Option<u64>,
+}
+impl Handler<Watch> for ChatServer {
+    type Result = Result<FeedStream, WatchError>;
+
+    fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
+        if !self.rooms.contains_key(&msg.room) {
+            return Err(WatchError::NoSuchRoom);
+        }
+        if !self.feed_access(&msg.room).allows(&msg.viewer) {
+            return Err(WatchError::Forbidden);
+        }
+        let room = self
+            .rooms
+            .get_mut(&msg.room)
+            .ok_or(WatchError::NoSuchRoom)?;
+        room.feed
+            .watch(msg.viewer, msg.last_seen)
+            .ok_or(WatchError::TooMany)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicU64, Mutex};
+
+    use super::*;
+    use crate::{
+        audit::AuditConfig, broker::SharedRoom, filter::WordList, webhook::WebhooksConfig,
+    };
+
+    /// Keeps what the server shares with other instances
+    #[derive(Debug, Default, Clone)]
+    struct TestBroker {
+        playback: Arc<Mutex<HashMap<String, SharedPlayback>>>,
+        published: Arc<Mutex<Vec<BrokerEvent>>>,
+    }
+
+    impl Broker for TestBroker {
+        fn instance(&self) -> &str {
+            ""
+        }
+        fn publish(&self, event: BrokerEvent) {
+            self.published.lock().unwrap().push(event);
+        }
+        fn subscribe(&self, _: Recipient<Remote>) {}
+        fn join(&self, _: &str, _: &User) {}
+        fn leave(&self, _: &str, _: &str) {}
+        fn room(&self, _: &str) -> SharedRoom {
+            SharedRoom::default()
+        }
+        fn set_roomer(&self, _: &str, _: &str) {}
+        fn set_playback(&self, room: &str, playback: &SharedPlayback) {
+            let mut shared = self.playback.lock().unwrap();
+            shared.insert(room.to_owned(), playback.clone());
+        }
+    }
+
+    /// Stands in for a websocket session, keeps what it is sent
+    struct Probe(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<Close> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, msg: Close, _: &mut Context<Self>) {
+            let reason = match msg {
+                Close::Restart => "restart",
+                Close::Kicked => "kicked",
+                Close::Replaced => "replaced",
+            };
+            self.0.lock().unwrap().push(format!("close:{reason}"));
+        }
+    }
+
+    /// Fresh path in the temp directory
+    fn temp(name: &str) -> PathBuf {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("together-{}-{n}-{name}", std::process::id()))
+    }
+
+    /// A `ChatServer` driven by calling its handlers directly. Timers are
+    /// never run, tests call what they would.
+    struct Harness {
+        server: ChatServer,
+        ctx: Context<ChatServer>,
+        seen: HashMap<String, Arc<Mutex<Vec<String>>>>,
+        broker: TestBroker,
+    }
+
+    impl Harness {
+        /// "badword" is the banned word. Must run inside an actix system.
+        fn new() -> Harness {
+            let broker = TestBroker::default();
+            let audit = AuditLog::open(&AuditConfig {
+                file: temp("audit.jsonl"),
+            })
+            .unwrap();
+            let webhooks = Webhooks::new(WebhooksConfig {
+                dead_letter: None,
+                ..WebhooksConfig::default()
+            })
+            .unwrap();
+            let server = ChatServer::new(
+                Arc::new(AtomicUsize::new(0)),
+                Box::new(WordList::new(["badword".to_owned()])),
+                None,
+                Box::new(broker.clone()),
+                None,
+                None,
+                LifecycleConfig::default(),
+                Schedule::default(),
+                webhooks,
+                audit,
+                RecordingsConfig {
+                    dir: temp("recordings"),
+                    ..RecordingsConfig::default()
+                },
+            );
+            Harness {
+                server,
+                ctx: Context::new(),
+                seen: HashMap::new(),
+                broker,
+            }
+        }
+
+        fn handle<M>(&mut self, msg: M) -> <ChatServer as Handler<M>>::Result
+        where
+            M: actix::Message,
+            ChatServer: Handler<M>,
+        {
+            self.server.handle(msg, &mut self.ctx)
+        }
+
+        /// A signed in session when `identity` is given
+        fn connect(&mut self, name: &str, identity: Option<&str>) -> String {
+            self.connect_as(name, identity, None)
+        }
+
+        fn connect_as(&mut self, name: &str, identity: Option<&str>, id: Option<&str>) -> String {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let probe = Probe(seen.clone()).start();
+            let id = self.handle(Connect {
+                addr: probe.clone().recipient(),
+                close: probe.recipient(),
+                user: (Some(name.to_owned()), None),
+                identity: identity.map(str::to_owned),
+                id: id.map(str::to_owned),
+            });
+            self.seen.insert(id.clone(), seen);
+            id
+        }
+
+        fn join(&mut self, id: &str, room: &str) -> Joined {
+            self.handle(Join {
+                id: id.to_owned(),
+                name: room.to_owned(),
+                spectator: false,
+            })
+            .0
+        }
+
+        /// Everything `id` was sent since the last call
+        async fn seen(&self, id: &str) -> Vec<String> {
+            actix::clock::sleep(Duration::from_millis(20)).await;
+            std::mem::take(&mut *self.seen[id].lock().unwrap())
+        }
+
+        /// Payloads with `code` that `id` was sent since the last call
+        async fn seen_code(&self, id: &str, code: Code) -> Vec<serde_json::Value> {
+            let code = code.code();
+            self.seen(id)
+                .await
+                .iter()
+                .filter_map(|m| serde_json::from_str::<(i32, serde_json::Value)>(m).ok())
+                .filter(|(c, _)| *c == code)
+                .map(|(_, payload)| payload)
+                .collect()
+        }
+    }
+
+    fn direct(from: &str, to: &str, room: &str, text: &str) -> DirectMessage {
+        DirectMessage {
+            id: from.to_owned(),
+            to: to.to_owned(),
+            msg: text.to_owned(),
+            room: room.to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_reach_every_session_of_the_user() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", Some("account:1"));
+        let bob = h.connect("bob", Some("account:2"));
+        let bob_phone = h.connect("bob", Some("account:2"));
+        let carol = h.connect("carol", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.join(&carol, "r");
+        h.seen(&bob_phone).await;
+        h.seen(&bob).await;
+        h.seen(&carol).await;
+
+        let ack = h.handle(direct(&alice, "bob", "r", "hi")).0;
+        assert_eq!(ack.1, "DELIVERED");
+        let expected = Data::full(Code::Direct, MsgData(alice.clone(), "hi".to_owned()));
+        assert_eq!(h.seen(&bob).await, vec![expected.clone()]);
+        assert_eq!(h.seen(&bob_phone).await, [expected]);
+        assert!(h.seen(&carol).await.is_empty());
+
+        // by id as well, to the same sessions
+        let ack = h.handle(direct(&alice, &bob, "r", "again")).0;
+        assert_eq!(ack.1, "DELIVERED");
+        assert_eq!(h.seen(&bob).await.len(), 1);
+        assert_eq!(h.seen(&bob_phone).await.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_to_missing_or_shared_names() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", Some("account:1"));
+        let bob = h.connect("bob", None);
+        let other_bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.join(&other_bob, "r");
+        let ack = h.handle(direct(&alice, "dave", "r", "hi")).0;
+        assert_eq!(ack.1, "USER_OFFLINE");
+        let ack = h.handle(direct(&alice, "bob", "r", "hi")).0;
+        assert_eq!(ack.1, "AMBIGUOUS_USER");
+
+        // still a member, but the session is gone
+        h.server.sessions.remove(&bob);
+        let ack = h.handle(direct(&alice, &bob, "r", "hi")).0;
+        assert_eq!(ack.1, "USER_OFFLINE");
+        assert!(h.seen(&other_bob).await.iter().all(|m| !m.contains("hi")));
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_are_filtered() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.seen(&bob).await;
+        h.handle(direct(&alice, "bob", "r", "a badword here"));
+        let masked = Data::full(
+            Code::Direct,
+            MsgData(alice.clone(), "a ******* here".to_owned()),
+        );
+        assert_eq!(h.seen(&bob).await, [masked]);
+
+        h.server.rooms.get_mut("r").unwrap().filter = Strictness::Block;
+        let ack = h.handle(direct(&alice, "bob", "r", "a badword here")).0;
+        assert_eq!(ack.1, "MESSAGE_REJECTED");
+        assert!(h.seen(&bob).await.is_empty());
+    }
+
+    fn presence(id: &str, room: &str, presence: Presence) -> SetPresence {
+        SetPresence {
+            id: id.to_owned(),
+            room: room.to_owned(),
+            presence,
+        }
+    }
+
+    #[actix_web::test]
+    async fn presence_changes_are_broadcast_once() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.seen(&alice).await;
+        h.seen(&bob).await;
+
+        h.handle(presence(&bob, "r", Presence::Away));
+        let away = Data::full(Code::Presence, (bob.clone(), Presence::Away));
+        assert_eq!(h.seen(&alice).await, [away]);
+        assert!(h.seen(&bob).await.is_empty());
+
+        // the same state again is not news
+        h.handle(presence(&bob, "r", Presence::Away));
+        assert!(h.seen(&alice).await.is_empty());
+
+        let info = h
+            .handle(ListMembers {
+                room_id: "r".to_owned(),
+            })
+            .unwrap();
+        let bob_info = info.members.iter().find(|u| u.id == bob).unwrap();
+        assert_eq!(bob_info.presence, Presence::Away);
+        assert_eq!(info.roomer.presence, Presence::Watching);
+    }
+
+    #[actix_web::test]
+    async fn presence_of_outsiders_is_ignored() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let mallory = h.connect("mallory", None);
+        h.join(&alice, "r");
+        h.join(&mallory, "elsewhere");
+        h.seen(&alice).await;
+        h.handle(presence(&mallory, "r", Presence::Buffering));
+        assert!(h.seen(&alice).await.is_empty());
+        assert_eq!(h.server.presence[&mallory], Presence::Watching);
+    }
+
+    fn progress(id: &str, room: &str, progress: f64, speed: f64) -> Progress {
+        Progress {
+            id: id.to_owned(),
+            room: room.to_owned(),
+            progress: progress.to_string(),
+            speed: speed.to_string(),
+        }
+    }
+
+    /// Alice plays "r" at normal speed in "wait for everyone" mode, with Bob
+    fn waiting_room(h: &mut Harness) -> (String, String) {
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.handle(Wait {
+            id: alice.clone(),
+            room: "r".to_owned(),
+            cmd: WaitCommand::On(Some(30)),
+        });
+        assert!(h.handle(progress(&alice, "r", 10.0, 1.0)).is_none());
+        (alice, bob)
+    }
+
+    #[actix_web::test]
+    async fn held_rooms_stay_put() {
+        let mut h = Harness::new();
+        let (_, bob) = waiting_room(&mut h);
+        h.handle(presence(&bob, "r", Presence::Buffering));
+        let room = &h.server.rooms["r"];
+        let held_at = room.playback.as_ref().unwrap().position();
+        assert_eq!(room.playback.as_ref().unwrap().speed, 0.0);
+        assert!(room.wait.as_ref().unwrap().held.is_some());
+
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        let playback = h.server.rooms["r"].playback.clone().unwrap();
+        assert_eq!(playback.position(), held_at);
+        assert_eq!(SharedPlayback::from(playback).progress, held_at);
+
+        h.handle(presence(&bob, "r", Presence::Watching));
+        let room = &h.server.rooms["r"];
+        assert!(room.wait.as_ref().unwrap().held.is_none());
+        assert_eq!(room.playback.as_ref().unwrap().speed, 1.0);
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        assert!(h.server.rooms["r"].playback.as_ref().unwrap().position() > held_at);
+    }
+
+    #[actix_web::test]
+    async fn roomer_override_restores_the_speed() {
+        let mut h = Harness::new();
+        let (alice, bob) = waiting_room(&mut h);
+        h.handle(progress(&alice, "r", 10.0, 1.5));
+        h.handle(presence(&bob, "r", Presence::Buffering));
+        h.handle(Wait {
+            id: alice.clone(),
+            room: "r".to_owned(),
+            cmd: WaitCommand::Resume,
+        });
+        let room = &h.server.rooms["r"];
+        assert_eq!(room.playback.as_ref().unwrap().speed, 1.5);
+        let wait = room.wait.as_ref().unwrap();
+        assert!(wait.held.is_none());
+        assert!(wait.given_up.contains(&bob));
+        // a fresh report from Bob holds the room again
+        h.handle(presence(&bob, "r", Presence::Watching));
+        h.handle(presence(&bob, "r", Presence::Buffering));
+        let room = &h.server.rooms["r"];
+        assert_eq!(room.playback.as_ref().unwrap().speed, 0.0);
+        assert_eq!(room.wait.as_ref().unwrap().speed, 1.5);
+    }
+
+    fn ready(id: &str, cmd: ReadyCommand) -> Ready {
+        Ready {
+            id: id.to_owned(),
+            room: "r".to_owned(),
+            cmd,
+        }
+    }
+
+    fn pending(status: &serde_json::Value) -> Vec<String> {
+        let mut ids: Vec<String> = status["pending"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| u["id"].as_str().unwrap().to_owned())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[actix_web::test]
+    async fn ready_check_counts_down_once_everyone_confirmed() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        for id in [&alice, &bob, &carol] {
+            h.join(id, "r");
+        }
+        let reply = h.handle(ready(&bob, ReadyCommand::Check(None))).unwrap();
+        assert_eq!(reply.1, "NOT_ROOMER");
+        let reply = h.handle(ready(&bob, ReadyCommand::Confirm)).unwrap();
+        assert_eq!(reply.1, "NO_READY_CHECK");
+        h.seen(&alice).await;
+        h.seen(&bob).await;
+
+        assert!(h.handle(ready(&alice, ReadyCommand::Check(None))).is_none());
+        let mut both = vec![bob.clone(), carol.clone()];
+        both.sort();
+        // members are told a check started, the roomer who is pending
+        let to_bob = h.seen_code(&bob, Code::Ready).await;
+        assert_eq!(to_bob.len(), 1);
+        assert_eq!(pending(&to_bob[0]), both);
+        let to_alice = h.seen_code(&alice, Code::Ready).await;
+        assert_eq!(to_alice.len(), 1);
+        assert_eq!(pending(&to_alice[0]), both);
+
+        h.handle(ready(&bob, ReadyCommand::Confirm));
+        let to_alice = h.seen_code(&alice, Code::Ready).await;
+        assert_eq!(pending(&to_alice[0]), vec![carol.clone()]);
+        assert!(h.server.rooms["r"].ready_check.is_some());
+
+        h.handle(ready(&carol, ReadyCommand::Confirm));
+        assert!(h.server.rooms["r"].ready_check.is_none());
+        for id in [&alice, &bob, &carol] {
+            let countdown = h.seen_code(id, Code::Countdown).await;
+            assert_eq!(countdown.len(), 1, "{id}");
+            let start_at = countdown[0]["start_at"].as_u64().unwrap();
+            let now = countdown[0]["now"].as_u64().unwrap();
+            assert_eq!(start_at - now, COUNTDOWN.as_millis() as u64);
+        }
+        let playback = h.server.rooms["r"].playback.clone().unwrap();
+        assert_eq!((playback.progress, playback.speed), (0.0, 1.0));
+        let shared = h.broker.playback.lock().unwrap()["r"].clone();
+        assert_eq!((shared.progress, shared.speed), (0.0, 1.0));
+        assert!(shared.updated >= now_millis() + COUNTDOWN.as_millis() as u64 - 1000);
+    }
+
+    #[actix_web::test]
+    async fn ready_check_skips_members_who_leave() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        h.handle(progress(&alice, "r", 42.0, 0.0));
+        h.handle(ready(&alice, ReadyCommand::Check(Some(60))));
+        h.seen(&alice).await;
+        h.handle(Disconnect {
+            id: bob.clone(),
+            close: None,
+        });
+        let countdown = h.seen_code(&alice, Code::Countdown).await;
+        assert_eq!(countdown.len(), 1);
+        assert_eq!(countdown[0]["progress"], 42.0);
+        assert!(h.server.rooms["r"].ready_check.is_none());
+    }
+
+    fn propose(id: &str, kind: PollKind) -> RoomPoll {
+        RoomPoll {
+            id: id.to_owned(),
+            room: "r".to_owned(),
+            cmd: PollCommand::Propose(kind),
+        }
+    }
+
+    #[actix_web::test]
+    async fn members_cannot_vote_themselves_roomer() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        assert!(h
+            .handle(propose(&bob, PollKind::Transfer(bob.clone())))
+            .is_none());
+        assert_eq!(h.server.rooms["r"].roomer, alice);
+        assert!(h.handle(propose(&bob, PollKind::Seek(30.0))).is_none());
+        assert!(h.server.rooms["r"].playback.is_none());
+        // the roomer agreeing passes the seek
+        h.handle(RoomPoll {
+            id: alice.clone(),
+            room: "r".to_owned(),
+            cmd: PollCommand::Vote(2, 0),
+        });
+        assert_eq!(
+            h.server.rooms["r"].playback.as_ref().unwrap().progress,
+            30.0
+        );
+    }
+
+    #[actix_web::test]
+    async fn governance_polls_are_capped() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.join(&bob, "r");
+        for _ in 0..MAX_GOVERNANCE_POLLS {
+            assert!(h.handle(propose(&bob, PollKind::Skip)).is_none());
+        }
+        let reply = h.handle(propose(&bob, PollKind::Skip)).unwrap();
+        assert_eq!(reply.1, "TOO_MANY_POLLS");
+    }
+
+    #[actix_web::test]
+    async fn announcements_reach_every_instance() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        h.handle(Announce {
+            text: "bye".to_owned(),
+        });
+        let sys = Data::sys("系统公告：bye".to_owned());
+        assert_eq!(h.seen(&alice).await, vec![sys.clone()]);
+        let published = h.broker.published.lock().unwrap().pop();
+        assert!(matches!(
+            published,
+            Some(BrokerEvent::Everyone { payload }) if payload == sys
+        ));
+        // and announcements from other instances reach ours
+        let bob = h.connect("bob", None);
+        h.handle(Remote(BrokerEvent::Everyone {
+            payload: "x".to_owned(),
+        }));
+        assert_eq!(h.seen(&bob).await, vec!["x".to_owned()]);
+    }
+
+    #[actix_web::test]
+    async fn replaced_sessions_are_gone_at_once() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let old = h.connect_as("bob", Some("jwt:42"), Some("jwt:42"));
+        h.join(&alice, "r");
+        h.join(&old, "r");
+        let old_seen = h.seen[&old].clone();
+        let old_probe = h.server.closers[&old].clone();
+
+        let new = h.connect_as("bob", Some("jwt:42"), Some("jwt:42"));
+        assert_eq!(new, old);
+        assert!(!h.server.rooms["r"].members.contains(&new));
+        assert!(h.server.closers[&new] != old_probe);
+        actix::clock::sleep(Duration::from_millis(20)).await;
+        assert!(old_seen
+            .lock()
+            .unwrap()
+            .contains(&"close:replaced".to_owned()));
+
+        // the old session disconnecting late leaves the new one alone
+        h.join(&new, "r");
+        h.handle(Disconnect {
+            id: new.clone(),
+            close: Some(old_probe),
+        });
+        assert!(h.server.sessions.contains_key(&new));
+        assert!(h.server.rooms["r"].members.contains(&new));
+    }
+
+    #[actix_web::test]
+    async fn private_rooms_are_never_listed() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        h.join(&alice, "open");
+        h.join(&alice, "secret");
+        h.handle(SetRoomMeta {
+            id: alice.clone(),
+            room: "open".to_owned(),
+            command: RoomCommand::Public(true),
+        });
+        h.handle(SetRoomMeta {
+            id: alice.clone(),
+            room: "secret".to_owned(),
+            command: RoomCommand::Title(Some("open secret".to_owned())),
+        });
+        assert_eq!(h.handle(ListRooms).0, vec!["open".to_owned()]);
+        let names = |page: DirectoryPage| -> Vec<String> {
+            page.rooms.into_iter().map(|r| r.name).collect()
+        };
+        let all = h.handle(Directory {
+            query: DirectoryQuery::default(),
+        });
+        assert_eq!(names(all.0), vec!["open".to_owned()]);
+        let search = h.handle(Directory {
+            query: DirectoryQuery::parse("secret"),
+        });
+        assert!(names(search.0).is_empty());
+    }
+
+    fn room_queue(id: &str, command: QueueCommand) -> RoomQueue {
+        RoomQueue {
+            id: id.to_owned(),
+            room: "r".to_owned(),
+            command,
+        }
+    }
+
+    #[actix_web::test]
+    async fn full_rooms_queue_and_admit_in_order() {
+        let mut h = Harness::new();
+        h.server.max_members = Some(1);
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        assert_eq!(h.join(&alice, "r"), Joined::Member { roomer: true });
+        assert_eq!(h.join(&bob, "r"), Joined::Queued(1));
+        assert_eq!(h.join(&carol, "r"), Joined::Queued(2));
+        let reply = h.handle(room_queue(&bob, QueueCommand::Capacity(Some(5))));
+        assert_eq!(reply.unwrap().1, "NOT_ROOMER");
+
+        // a room capacity overrides the server default
+        h.handle(room_queue(&alice, QueueCommand::Capacity(Some(2))));
+        assert!(h.server.rooms["r"].members.contains(&bob));
+        assert_eq!(h.server.rooms["r"].queue, vec![carol.clone()]);
+        assert_eq!(
+            h.seen_code(&bob, Code::Queue).await,
+            vec![serde_json::json!(0)]
+        );
+        assert_eq!(
+            h.seen_code(&carol, Code::Queue).await,
+            vec![serde_json::json!(1)]
+        );
+
+        // back to the default nobody is removed, but nobody gets in either
+        h.handle(room_queue(&alice, QueueCommand::Capacity(None)));
+        assert_eq!(h.server.rooms["r"].members.len(), 2);
+        assert_eq!(h.server.rooms["r"].queue.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn roomers_admit_queued_users_by_name() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        h.join(&alice, "r");
+        h.handle(room_queue(&alice, QueueCommand::Capacity(Some(1))));
+        h.join(&bob, "r");
+        h.join(&carol, "r");
+        h.seen(&bob).await;
+
+        h.handle(room_queue(&alice, QueueCommand::List));
+        let waiting = h.seen_code(&alice, Code::Waiting).await;
+        let names: Vec<&str> = waiting[0]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|user| user["name"].as_str())
+            .collect();
+        assert_eq!(names, ["bob", "carol"]);
+
+        let admit = |user: &str| room_queue(&alice, QueueCommand::Admit(Some(user.to_owned())));
+        assert!(h.handle(admit("carol")).is_none());
+        assert!(h.server.rooms["r"].members.contains(&carol));
+        // bob moves up
+        assert_eq!(
+            h.seen_code(&bob, Code::Queue).await,
+            vec![serde_json::json!(1)]
+        );
+        assert_eq!(h.handle(admit("dave")).unwrap().1, "NOT_QUEUED");
+
+        // leaving the server leaves the queue
+        h.handle(Disconnect {
+            id: bob.clone(),
+            close: None,
+        });
+        assert!(h.server.rooms["r"].queue.is_empty());
+        let reply = h.handle(room_queue(&alice, QueueCommand::Admit(None)));
+        assert_eq!(reply.unwrap().1, "NOT_QUEUED");
+    }
+
+    fn spectate(h: &mut Harness, id: &str, room: &str) -> Joined {
+        h.handle(Join {
+            id: id.to_owned(),
+            name: room.to_owned(),
+            spectator: true,
+        })
+        .0
+    }
+
+    /// alice in "r", bob waiting for it and carol spectating
+    fn full_room(h: &mut Harness) -> (String, String, String) {
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        h.join(&alice, "r");
+        h.handle(room_queue(&alice, QueueCommand::Capacity(Some(1))));
+        assert_eq!(h.join(&bob, "r"), Joined::Queued(1));
+        assert_eq!(spectate(h, &carol, "r"), Joined::Spectator);
+        (alice, bob, carol)
+    }
+
+    #[actix_web::test]
+    async fn empty_rooms_are_held_for_the_grace_period() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", Some("account:a"));
+        let bob = h.connect("bob", None);
+        h.join(&alice, "r");
+        h.handle(Disconnect {
+            id: alice.clone(),
+            close: None,
+        });
+        assert_eq!(h.join(&bob, "r"), Joined::Reserved);
+        h.server.sweep();
+        assert!(h.server.rooms.contains_key("r"));
+
+        let grace = h.server.lifecycle.empty_grace() + Duration::from_secs(1);
+        h.server.rooms.get_mut("r").unwrap().empty_since = Some(Instant::now() - grace);
+        h.server.sweep();
+        assert!(!h.server.rooms.contains_key("r"));
+        // the name is free again
+        assert_eq!(h.join(&bob, "r"), Joined::Member { roomer: true });
+    }
+
+    #[actix_web::test]
+    async fn idle_rooms_are_warned_then_closed() {
+        let mut h = Harness::new();
+        let (alice, bob, carol) = full_room(&mut h);
+        let expiry = h.server.lifecycle.idle_expiry().unwrap();
+        let warning = h.server.lifecycle.warning();
+        let idle = |h: &mut Harness, idle: Duration| {
+            h.server.rooms.get_mut("r").unwrap().last_activity = Instant::now() - idle;
+        };
+
+        idle(&mut h, expiry - warning - Duration::from_secs(10));
+        h.server.sweep();
+        assert!(h.seen_code(&alice, Code::Expiring).await.is_empty());
+
+        idle(&mut h, expiry - Duration::from_secs(100));
+        h.server.sweep();
+        h.server.sweep();
+        let warned = h.seen_code(&alice, Code::Expiring).await;
+        assert_eq!(warned.len(), 1);
+        assert!(warned[0]
+            .as_u64()
+            .is_some_and(|secs| (99..=100).contains(&secs)));
+
+        idle(&mut h, expiry + Duration::from_secs(1));
+        h.server.sweep();
+        assert!(!h.server.rooms.contains_key("r"));
+        assert_eq!(
+            h.seen_code(&alice, Code::Expiring).await,
+            vec![serde_json::json!(0)]
+        );
+        assert!(h.seen(&bob).await.contains(&Data::sys("等待的房间已关闭")));
+        assert!(h
+            .seen(&carol)
+            .await
+            .contains(&Data::sys("旁观的房间已关闭")));
+    }
+
+    #[actix_web::test]
+    async fn admin_closing_a_room_keeps_everyone_connected() {
+        let mut h = Harness::new();
+        let (alice, bob, carol) = full_room(&mut h);
+        let close = |room: &str| CloseRoom {
+            room: room.to_owned(),
+        };
+        assert!(h.handle(close("r")));
+        assert!(!h.server.rooms.contains_key("r"));
+        let alice_seen = h.seen(&alice).await;
+        assert!(alice_seen.contains(&Data::sys("房间已被管理员关闭")));
+        assert!(h.seen(&bob).await.contains(&Data::sys("等待的房间已关闭")));
+        assert!(h
+            .seen(&carol)
+            .await
+            .contains(&Data::sys("旁观的房间已关闭")));
+        for id in [&alice, &bob, &carol] {
+            assert!(h.server.sessions.contains_key(id));
+        }
+        assert!(!alice_seen.iter().any(|seen| seen.starts_with("close:")));
+        // and they can go on elsewhere
+        assert_eq!(h.join(&alice, "s"), Joined::Member { roomer: true });
+        assert!(!h.handle(close("r")));
+    }
+
+    fn party(id: &str, room: &str, organizer: &str, start_in: i64) -> Party {
+        Party {
+            id: id.to_owned(),
+            room: room.to_owned(),
+            title: "movie".to_owned(),
+            description: None,
+            media: "https://example.com/a.mp4".to_owned(),
+            start_at: (now_millis() as i64 + start_in) as u64,
+            duration_mins: 90,
+            public: true,
+            organizer: organizer.to_owned(),
+            opened: false,
+            announced: None,
+            counting_down: false,
+        }
+    }
+
+    fn parties(h: &mut Harness) {
+        h.server.parties.open_before = Duration::from_secs(30 * 60);
+        h.server.parties.max_per_organizer = 1;
+    }
+
+    #[actix_web::test]
+    async fn parties_open_remind_and_start() {
+        let mut h = Harness::new();
+        parties(&mut h);
+        let alice = h.connect("alice", Some("account:a"));
+        let scheduled = h.handle(ScheduleParty(party("p1", "p", "account:a", 20 * 60_000)));
+        assert!(scheduled.is_ok());
+        let again = h.handle(ScheduleParty(party("p2", "q", "account:a", 20 * 60_000)));
+        assert_eq!(again.unwrap_err(), PartyRejected::TooMany);
+        // opened ahead of the start
+        assert!(h.server.rooms["p"].meta.public);
+        assert_eq!(h.join(&alice, "p"), Joined::Member { roomer: true });
+        // newcomers are told about the party
+        let joined = h.seen_code(&alice, Code::Party).await;
+        assert_eq!(joined[0]["title"], "movie");
+
+        h.server.parties.parties[0].start_at = now_millis() + 25_000;
+        h.server.tick_parties();
+        h.server.tick_parties();
+        let info = h.seen_code(&alice, Code::Party).await;
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0]["id"], "p1");
+        assert_eq!(h.server.parties.parties[0].announced, Some(30));
+
+        let start_at = now_millis() + 2_000;
+        h.server.parties.parties[0].start_at = start_at;
+        h.server.tick_parties();
+        let countdown = h.seen_code(&alice, Code::Countdown).await;
+        assert_eq!(countdown[0]["start_at"], start_at);
+        assert_eq!(h.server.rooms["p"].playback.as_ref().unwrap().speed, 1.0);
+        assert_eq!(h.broker.playback.lock().unwrap()["p"].updated, start_at);
+
+        // done once started
+        h.server.parties.parties[0].start_at = now_millis() - 1;
+        h.server.tick_parties();
+        assert!(h.server.parties.parties.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn missed_and_taken_parties_are_dropped() {
+        let mut h = Harness::new();
+        parties(&mut h);
+        let bob = h.connect("bob", Some("account:b"));
+        h.join(&bob, "taken");
+        h.server.parties.parties = vec![
+            party("missed", "m", "admin", -5 * 60_000),
+            party("taken", "taken", "account:a", 60_000),
+        ];
+        h.server.tick_parties();
+        assert!(h.server.parties.parties.is_empty());
+        assert!(!h.server.rooms.contains_key("m"));
+        assert_eq!(h.server.rooms["taken"].roomer, bob);
+    }
+
+    #[actix_web::test]
+    async fn only_parties_about_to_start_keep_rooms_open() {
+        let mut h = Harness::new();
+        parties(&mut h);
+        let alice = h.connect("alice", None);
+        h.join(&alice, "soon");
+        h.join(&alice, "later");
+        h.handle(Disconnect {
+            id: alice.clone(),
+            close: None,
+        });
+        h.server.parties.parties = vec![
+            party("soon", "soon", "admin", 10 * 60_000),
+            party("later", "later", "admin", 90 * 24 * 3_600_000),
+        ];
+        h.server.tick_parties();
+        let grace = h.server.lifecycle.empty_grace() + Duration::from_secs(1);
+        for room in h.server.rooms.values_mut() {
+            room.empty_since = Some(Instant::now() - grace);
+        }
+        h.server.sweep();
+        assert!(h.server.rooms.contains_key("soon"));
+        assert!(!h.server.rooms.contains_key("later"));
+    }
+
+    fn record(id: &str, command: RecordCommand) -> Record {
+        Record {
+            id: id.to_owned(),
+            room: "r".to_owned(),
+            command,
+        }
+    }
+
+    /// Wait for the recording `id` to be written
+    async fn saved(h: &Harness, id: &str) -> crate::recording::Recording {
+        for _ in 0..100 {
+            if let Ok(recording) = h.server.recordings.load(id) {
+                return recording;
+            }
+            actix::clock::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("recording {id} was not saved");
+    }
+
+    #[actix_web::test]
+    async fn recordings_stop_at_their_limits() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        h.join(&alice, "r");
+        h.server.recordings.max_events = 2;
+        assert!(h.handle(record(&alice, RecordCommand::Start)).is_none());
+        let started = h.seen_code(&alice, Code::Recording).await;
+        let id = started[0]["id"].as_str().unwrap().to_owned();
+        let share = |link: &str| Timeline::Share {
+            link: link.to_owned(),
+        };
+        h.server.record("r", share("a"), Duration::ZERO);
+        assert!(h.server.rooms["r"].recorder.is_some());
+        h.server.record("r", share("b"), Duration::ZERO);
+        assert!(h.server.rooms["r"].recorder.is_none());
+        assert_eq!(saved(&h, &id).await.events.len(), 2);
+        let reply = h.handle(record(&alice, RecordCommand::Stop));
+        assert_eq!(reply.unwrap().1, "NOT_RECORDING");
+
+        h.server.recordings.max_events = 100;
+        h.server.recordings.max_duration_mins = 0;
+        h.handle(record(&alice, RecordCommand::Start));
+        h.server.record("r", share("c"), Duration::ZERO);
+        assert!(h.server.rooms["r"].recorder.is_none());
+    }
+
+    #[actix_web::test]
+    async fn saved_recordings_are_announced() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        h.join(&alice, "r");
+        h.handle(record(&alice, RecordCommand::Start));
+        let info = h.server.rooms["r"]
+            .recorder
+            .as_ref()
+            .unwrap()
+            .info("r", false);
+        h.seen(&alice).await;
+        h.handle(RecordingSaved {
+            room: "r".to_owned(),
+            info: info.clone(),
+            saved: Err("disk full".to_owned()),
+        });
+        assert_eq!(h.seen(&alice).await, vec![Data::sys("录制保存失败")]);
+        h.handle(RecordingSaved {
+            room: "r".to_owned(),
+            info,
+            saved: Ok(()),
+        });
+        let done = h.seen_code(&alice, Code::Recording).await;
+        assert_eq!(done[0]["recording"], false);
+    }
+
+    #[actix_web::test]
+    async fn replayed_chat_looks_like_live_chat() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        h.join(&alice, "r");
+        h.handle(record(&alice, RecordCommand::Start));
+        let id = h.server.rooms["r"].recorder.as_ref().unwrap().id.clone();
+        let chat = |id: &str, name: &str| Timeline::Chat {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            text: "hi".to_owned(),
+        };
+        h.server.record("r", chat("42", "bob"), Duration::ZERO);
+        h.server.record("r", chat("", "carol"), Duration::ZERO);
+        h.handle(record(&alice, RecordCommand::Stop));
+        let recording = saved(&h, &id).await;
+
+        let room = h
+            .handle(StartReplay {
+                id: alice.clone(),
+                recording: id,
+            })
+            .unwrap();
+        h.join(&alice, &room);
+        h.seen(&alice).await;
+        for entry in recording.events {
+            h.server.play(&room, entry.event);
+        }
+        let seen = h.seen(&alice).await;
+        let expected = [
+            Data::full(
+                Code::Speaker,
+                serde_json::json!({ "id": "42", "name": "bob" }),
+            ),
+            Data::msg("42".to_owned(), "hi".to_owned()),
+            Data::full(
+                Code::Speaker,
+                serde_json::json!({ "id": "carol", "name": "carol" }),
+            ),
+            Data::msg("carol".to_owned(), "hi".to_owned()),
+        ];
+        assert_eq!(seen, expected);
+    }
+
+    #[actix_web::test]
+    async fn spectators_cannot_chat() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let carol = h.connect("carol", None);
+        h.join(&alice, "r");
+        spectate(&mut h, &carol, "r");
+        h.seen(&alice).await;
+        h.seen(&carol).await;
+        h.handle(ClientMessage {
+            id: carol.clone(),
+            msg: "hello".to_owned(),
+            room: "r".to_owned(),
+        });
+        assert_eq!(h.seen(&carol).await, vec![Data::sys("SPECTATOR")]);
+        assert!(h.seen(&alice).await.is_empty());
+        let info = h
+            .handle(ListMembers {
+                room_id: "r".to_owned(),
+            })
+            .unwrap();
+        assert_eq!(info.members.len(), 1);
+        assert_eq!(info.spectators, 1);
+    }
+
+    #[actix_web::test]
+    async fn spectators_never_become_roomer() {
+        let mut h = Harness::new();
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        h.join(&alice, "r");
+        spectate(&mut h, &carol, "r");
+        h.join(&bob, "r");
+        let leave = |id: &String| Disconnect {
+            id: id.clone(),
+            close: None,
+        };
+        h.handle(leave(&alice));
+        assert_eq!(h.server.rooms["r"].roomer, bob);
+        h.handle(leave(&bob));
+        // the room stays empty for the grace period, carol still follows it
+        let room = &h.server.rooms["r"];
+        assert_ne!(room.roomer, carol);
+        assert!(room.members.is_empty());
+        assert!(room.spectators.contains(&carol));
+    }
+
+    #[actix_web::test]
+    async fn spectators_have_their_own_limit() {
+        let mut h = Harness::new();
+        h.server.max_members = Some(1);
+        h.server.max_spectators = Some(1);
+        let alice = h.connect("alice", None);
+        let bob = h.connect("bob", None);
+        let carol = h.connect("carol", None);
+        let dave = h.connect("dave", None);
+        assert_eq!(spectate(&mut h, &carol, "r"), Joined::NoSuchRoom);
+        h.join(&alice, "r");
+        assert_eq!(spectate(&mut h, &carol, "r"), Joined::Spectator);
+        assert_eq!(spectate(&mut h, &carol, "r"), Joined::Spectator);
+        assert_eq!(spectate(&mut h, &dave, "r"), Joined::SpectatorsFull);
+        // members are limited separately
+        assert_eq!(h.join(&bob, "r"), Joined::Queued(1));
+        h.handle(Disconnect {
+            id: carol.clone(),
+            close: None,
+        });
+        assert_eq!(spectate(&mut h, &dave, "r"), Joined::Spectator);
     }
 }
@@ -1,25 +1,32 @@
-use std::time::{Duration, Instant};
+use std::{sync::Arc, time::Instant};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
 
+use actix_web::web;
+
 use crate::{
+    account::{Account, Accounts},
+    config::Config,
     context::{Code, Msg},
-    server::{self, Login},
+    directory::{DirectoryQuery, RoomCommand},
+    filter::Strictness,
+    jwt::Claims,
+    limit::RateLimiter,
+    poll::{PollKind, MAX_POLL_DURATION},
+    server::{
+        self, Joined, Login, PollCommand, Presence, QueueCommand, ReadyCommand, RecordCommand,
+        WaitCommand,
+    },
+    webhook::{self, WebhookCommand},
 };
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-
 #[derive(Debug)]
 pub struct WsChatSession {
     /// unique session id
     pub id: String,
 
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
+    /// Client must send ping at least once per `client_timeout_secs`,
     /// otherwise we drop connection.
     pub hb: Instant,
 
@@ -28,21 +35,39 @@ pub struct WsChatSession {
 
     /// Chat server
     pub addr: Addr<server::ChatServer>,
+
+    /// Command rate limits of this session
+    pub limiter: RateLimiter,
+
+    /// Server configuration shared by all sessions
+    pub config: Arc<Config>,
+
+    /// Signed in account, `None` for guests
+    pub account: Option<Account>,
+
+    /// Account store, used to protect registered nicknames
+    pub accounts: Option<web::Data<Accounts>>,
+
+    /// Identity from the embedding site's JWT, replaces `/login`
+    pub claims: Option<Claims>,
 }
 
 impl WsChatSession {
-    /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
+    /// helper method that sends ping to client every `heartbeat_interval_secs`.
     ///
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.config.heartbeat_interval(), |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.config.client_timeout() {
                 // heartbeat timed out
                 println!("Websocket Client heartbeat failed, disconnecting!");
 
                 // notify chat server
-                act.addr.do_send(server::Disconnect { id: act.id.clone() });
+                act.addr.do_send(server::Disconnect {
+                    id: act.id.clone(),
+                    close: Some(ctx.address().recipient()),
+                });
 
                 // stop actor
                 ctx.stop();
@@ -54,6 +79,69 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Join or create room `name`, queued when it is full. Spectators follow
+    /// an existing room read-only.
+    fn join_room(&mut self, name: String, spectator: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.claims.as_ref().is_some_and(|c| !c.may_join(&name)) {
+            ctx.sys("ROOM_FORBIDDEN".to_owned());
+            return;
+        }
+        self.room = name;
+        self.addr
+            .send(server::Join {
+                id: self.id.clone(),
+                name: self.room.clone(),
+                spectator,
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                match res {
+                    Ok(Joined::Member { roomer }) => {
+                        ctx.sys("您已加入房间".to_owned());
+                        ctx.full(Code::Roomer, roomer);
+                    }
+                    Ok(Joined::Queued(position)) => {
+                        ctx.sys(format!("房间已满，正在排队，第 {} 位", position));
+                        ctx.full(Code::Queue, position);
+                    }
+                    Ok(Joined::Reserved) => {
+                        ctx.sys("ROOM_RESERVED".to_owned());
+                    }
+                    Ok(Joined::Spectator) => {
+                        ctx.sys("您正在旁观房间".to_owned());
+                        ctx.full(Code::Roomer, false);
+                    }
+                    Ok(Joined::SpectatorsFull) => {
+                        ctx.sys("SPECTATORS_FULL".to_owned());
+                    }
+                    Ok(Joined::NoSuchRoom) => {
+                        ctx.sys("ROOM_NOT_EXIST".to_owned());
+                    }
+                    Err(_) => {}
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/webhook` in the joined room, added URLs have been checked already
+    fn room_webhook(&self, command: WebhookCommand, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .send(server::RoomWebhook {
+                id: self.id.clone(),
+                room: self.room.clone(),
+                command,
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                if let Ok(Some(v)) = res {
+                    ctx.full(v.0, v.1);
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
 }
 
 impl Actor for WsChatSession {
@@ -73,8 +161,21 @@ impl Actor for WsChatSession {
         let addr = ctx.address();
         self.addr
             .send(server::Connect {
-                addr: addr.recipient(),
-                user: (None, None),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
+                user: match &self.claims {
+                    Some(claims) => (Some(claims.name.clone()), claims.avatar.clone()),
+                    None => (self.account.as_ref().map(|a| a.name.clone()), None),
+                },
+                identity: match (&self.claims, &self.account) {
+                    (Some(claims), _) => Some(format!("jwt:{}", claims.sub)),
+                    (None, Some(account)) => Some(format!("account:{}", account.id)),
+                    (None, None) => None,
+                },
+                id: self
+                    .claims
+                    .as_ref()
+                    .map(|claims| format!("jwt:{}", claims.sub)),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -88,9 +189,12 @@ impl Actor for WsChatSession {
             .wait(ctx);
     }
 
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         // notify chat server
-        self.addr.do_send(server::Disconnect { id: self.id.clone() });
+        self.addr.do_send(server::Disconnect {
+            id: self.id.clone(),
+            close: Some(ctx.address().recipient()),
+        });
         Running::Stop
     }
 }
@@ -104,6 +208,26 @@ impl Handler<server::Message> for WsChatSession {
     }
 }
 
+/// Server closes the websocket: "service restart" on shutdown, "policy
+/// violation" when removed by an operator
+impl Handler<server::Close> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Close, ctx: &mut Self::Context) {
+        let code = match msg {
+            server::Close::Restart => ws::CloseCode::Restart,
+            server::Close::Kicked => ws::CloseCode::Policy,
+            server::Close::Replaced => {
+                // the id belongs to the new session now, leave it alone
+                self.id.clear();
+                ws::CloseCode::Policy
+            }
+        };
+        ctx.close(Some(code.into()));
+        ctx.stop();
+    }
+}
+
 /// WebSocket message handler
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
@@ -130,6 +254,27 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 println!("{}", m);
                 if m.starts_with('/') {
                     let v: Vec<&str> = m.splitn(2, ' ').collect();
+                    if let Err(err) = self.limiter.check(v[0]) {
+                        ctx.sys(err.to_string());
+                        if self.limiter.is_offender() {
+                            log::warn!("session {} is flooding, disconnecting", self.id);
+                            ctx.close(Some(ws::CloseCode::Policy.into()));
+                            ctx.stop();
+                        }
+                        return;
+                    }
+                    let arg = match v
+                        .get(1)
+                        .map(|arg| self.config.validation.command(v[0], arg))
+                    {
+                        Some(Ok(arg)) => Some(arg),
+                        Some(Err(err)) => {
+                            ctx.sys(err.to_string());
+                            return;
+                        }
+                        None => None,
+                    };
+                    let v: Vec<&str> = std::iter::once(v[0]).chain(arg.as_deref()).collect();
                     match v[0] {
                         "/list" => {
                             // Send ListRooms message to chat server and wait for
@@ -153,21 +298,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         }
                         "/join" => {
                             if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr
-                                    .send(server::Join {
-                                        id: self.id.clone(),
-                                        name: self.room.clone(),
-                                    })
-                                    .into_actor(self)
-                                    .then(|res, _, ctx| {
-                                        if let Ok(v) = res {
-                                            ctx.full(Code::Roomer, v);
-                                        }
-                                        fut::ready(())
-                                    })
-                                    .wait(ctx);
-                                ctx.sys("您已加入房间".to_owned());
+                                self.join_room(v[1].to_owned(), false, ctx);
+                            } else {
+                                ctx.sys("!!! room name is required".to_owned());
+                            }
+                        }
+                        "/spectate" => {
+                            if v.len() == 2 {
+                                self.join_room(v[1].to_owned(), true, ctx);
                             } else {
                                 ctx.sys("!!! room name is required".to_owned());
                             }
@@ -228,7 +366,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         "/login" => {
                             if v.len() == 2 {
                                 let value: Vec<&str> = v[1].splitn(2, "\n").collect();
-                                if value.len() == 2 {
+                                let reserved = self.accounts.as_ref().is_some_and(|a| {
+                                    let id = self.account.as_ref().map(|a| a.id.as_str());
+                                    a.is_reserved(value[0], id)
+                                });
+                                if self.claims.is_some() {
+                                    ctx.sys("LOGIN_DISABLED".to_owned());
+                                } else if reserved {
+                                    ctx.sys("NAME_RESERVED".to_owned());
+                                } else if value.len() == 2 {
                                     let name = Some(value[0].to_owned());
                                     let avatar = Some(value[1].to_owned());
                                     self.addr.do_send(Login(self.id.clone(), name, avatar));
@@ -241,7 +387,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         }
                         "/share" => {
                             if v.len() == 2 {
-                                let msg = Some(v[1].to_owned()).unwrap();
+                                let msg = v[1].to_owned();
                                 // send message to chat server
                                 self.addr.do_send(server::FullMessage {
                                     id: self.id.clone(),
@@ -255,7 +401,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         }
                         "/speed" => {
                             if v.len() == 2 {
-                                let msg = Some(v[1].to_owned()).unwrap();
+                                let msg = v[1].to_owned();
                                 // send message to chat server
                                 self.addr.do_send(server::FullMessage {
                                     id: self.id.clone(),
@@ -269,7 +415,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         }
                         "/msg" => {
                             if v.len() == 2 {
-                                let msg = Some(v[1].to_owned()).unwrap();
+                                let msg = v[1].to_owned();
                                 // send message to chat server
                                 self.addr.do_send(server::ClientMessage {
                                     id: self.id.clone(),
@@ -280,6 +426,338 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                                 ctx.sys("消息不能为空".to_owned());
                             }
                         }
+                        "/dm" => {
+                            if v.len() == 2 {
+                                let value: Vec<&str> = v[1].splitn(2, '\n').collect();
+                                if value.len() == 2 {
+                                    self.addr
+                                        .send(server::DirectMessage {
+                                            id: self.id.clone(),
+                                            to: value[0].to_owned(),
+                                            msg: value[1].to_owned(),
+                                            room: self.room.clone(),
+                                        })
+                                        .into_actor(self)
+                                        .then(|res, _, ctx| {
+                                            if let Ok(v) = res {
+                                                ctx.full(v.0, v.1);
+                                            }
+                                            fut::ready(())
+                                        })
+                                        .wait(ctx);
+                                } else {
+                                    ctx.sys("消息不能为空".to_owned());
+                                }
+                            } else {
+                                ctx.sys("私信对象不能为空".to_owned());
+                            }
+                        }
+                        "/presence" => {
+                            if v.len() == 2 {
+                                match v[1].parse::<Presence>() {
+                                    Ok(presence) => self.addr.do_send(server::SetPresence {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        presence,
+                                    }),
+                                    Err(_) => ctx.sys(format!("!!! unknown presence: {:?}", v[1])),
+                                }
+                            } else {
+                                ctx.sys("状态值不能为空".to_owned());
+                            }
+                        }
+                        "/wait" => {
+                            let arg: Vec<&str> =
+                                v.get(1).unwrap_or(&"").split_whitespace().collect();
+                            let cmd = match arg.as_slice() {
+                                ["on"] => Some(WaitCommand::On(None)),
+                                ["on", secs] => secs.parse().ok().map(|s| WaitCommand::On(Some(s))),
+                                ["off"] => Some(WaitCommand::Off),
+                                ["resume"] => Some(WaitCommand::Resume),
+                                _ => None,
+                            };
+                            match cmd {
+                                Some(cmd) => self
+                                    .addr
+                                    .send(server::Wait {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        cmd,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, _, ctx| {
+                                        if let Ok(Some(v)) = res {
+                                            ctx.full(v.0, v.1);
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx),
+                                None => ctx
+                                    .sys("!!! usage: /wait on [seconds] | off | resume".to_owned()),
+                            }
+                        }
+                        "/ready" => {
+                            let arg: Vec<&str> =
+                                v.get(1).unwrap_or(&"").split_whitespace().collect();
+                            let cmd = match arg.as_slice() {
+                                [] => Some(ReadyCommand::Confirm),
+                                ["check"] => Some(ReadyCommand::Check(None)),
+                                ["check", secs] => {
+                                    secs.parse().ok().map(|s| ReadyCommand::Check(Some(s)))
+                                }
+                                _ => None,
+                            };
+                            match cmd {
+                                Some(cmd) => self
+                                    .addr
+                                    .send(server::Ready {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        cmd,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, _, ctx| {
+                                        if let Ok(Some(v)) = res {
+                                            ctx.full(v.0, v.1);
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx),
+                                None => ctx.sys("!!! usage: /ready [check [seconds]]".to_owned()),
+                            }
+                        }
+                        "/capacity" | "/admit" | "/queue" => {
+                            let arg = v.get(1).map(|a| a.trim()).unwrap_or("");
+                            let command = match (v[0], arg) {
+                                ("/capacity", "off") => Some(QueueCommand::Capacity(None)),
+                                ("/capacity", n) => n
+                                    .parse()
+                                    .ok()
+                                    .filter(|n| *n > 0)
+                                    .map(|n| QueueCommand::Capacity(Some(n))),
+                                ("/admit", "") => Some(QueueCommand::Admit(None)),
+                                ("/admit", user) => {
+                                    Some(QueueCommand::Admit(Some(user.to_owned())))
+                                }
+                                _ => Some(QueueCommand::List),
+                            };
+                            match command {
+                                Some(command) => self
+                                    .addr
+                                    .send(server::RoomQueue {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        command,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, _, ctx| {
+                                        if let Ok(Some(v)) = res {
+                                            ctx.full(v.0, v.1);
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx),
+                                None => ctx.sys("!!! usage: /capacity number|off".to_owned()),
+                            }
+                        }
+                        "/poll" | "/vote" => {
+                            let arg = v.get(1).unwrap_or(&"");
+                            let cmd = if v[0] == "/vote" {
+                                let arg: Vec<&str> = arg.split_whitespace().collect();
+                                match arg.as_slice() {
+                                    [poll, option] => poll
+                                        .parse()
+                                        .ok()
+                                        .zip(option.parse().ok())
+                                        .map(|(p, o)| PollCommand::Vote(p, o)),
+                                    _ => None,
+                                }
+                            } else {
+                                let mut lines = arg.split('\n');
+                                let head: Vec<&str> =
+                                    lines.next().unwrap_or("").split_whitespace().collect();
+                                match head.as_slice() {
+                                    ["create"] | ["create", _] => {
+                                        let duration = head
+                                            .get(1)
+                                            .and_then(|d| d.parse::<u64>().ok())
+                                            .map(|d| d.min(MAX_POLL_DURATION.as_secs()));
+                                        let question = lines.next().unwrap_or("").to_owned();
+                                        let options: Vec<String> =
+                                            lines.map(|o| o.to_owned()).collect();
+                                        if question.is_empty() || options.len() < 2 {
+                                            None
+                                        } else {
+                                            Some(PollCommand::Create(duration, question, options))
+                                        }
+                                    }
+                                    ["skip"] => Some(PollCommand::Propose(PollKind::Skip)),
+                                    ["seek", to] => to
+                                        .parse::<f64>()
+                                        .ok()
+                                        .filter(|to| to.is_finite() && *to >= 0.0)
+                                        .map(|to| PollCommand::Propose(PollKind::Seek(to))),
+                                    ["transfer", to] => Some(PollCommand::Propose(
+                                        PollKind::Transfer(to.to_string()),
+                                    )),
+                                    ["close", poll] => poll.parse().ok().map(PollCommand::Close),
+                                    ["threshold", t] => t
+                                        .parse::<f64>()
+                                        .ok()
+                                        .filter(|t| t.is_finite())
+                                        .map(PollCommand::Threshold),
+                                    _ => None,
+                                }
+                            };
+                            match cmd {
+                                Some(cmd) => self
+                                    .addr
+                                    .send(server::RoomPoll {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        cmd,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, _, ctx| {
+                                        if let Ok(Some(v)) = res {
+                                            ctx.full(v.0, v.1);
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx),
+                                None => ctx.sys(format!("!!! invalid poll command: {m:?}")),
+                            }
+                        }
+                        "/room" => match v.get(1).and_then(|arg| RoomCommand::parse(arg)) {
+                            Some(command) => self
+                                .addr
+                                .send(server::SetRoomMeta {
+                                    id: self.id.clone(),
+                                    room: self.room.clone(),
+                                    command,
+                                })
+                                .into_actor(self)
+                                .then(|res, _, ctx| {
+                                    if let Ok(Some(v)) = res {
+                                        ctx.full(v.0, v.1);
+                                    }
+                                    fut::ready(())
+                                })
+                                .wait(ctx),
+                            None => ctx.sys(
+                                "!!! usage: /room public|private|title|description|tags|media"
+                                    .to_owned(),
+                            ),
+                        },
+                        "/webhook" => {
+                            let arg = v.get(1).copied().unwrap_or("");
+                            match WebhookCommand::parse(arg) {
+                                Some(WebhookCommand::Add(url, events)) => {
+                                    let checked = url.clone();
+                                    async move { webhook::public_addr(&checked).await }
+                                        .into_actor(self)
+                                        .then(|res, act, ctx| {
+                                            match res {
+                                                Ok(_) => act.room_webhook(
+                                                    WebhookCommand::Add(url, events),
+                                                    ctx,
+                                                ),
+                                                Err(err) => ctx.full(Code::Sys, err.to_owned()),
+                                            }
+                                            fut::ready(())
+                                        })
+                                        .wait(ctx)
+                                }
+                                Some(command) => self.room_webhook(command, ctx),
+                                None => ctx.sys(
+                                    "!!! usage: /webhook add url [event,...] | remove url | list"
+                                        .to_owned(),
+                                ),
+                            }
+                        }
+                        "/record" => {
+                            let command = match v.get(1).copied() {
+                                Some("start") => Some(RecordCommand::Start),
+                                Some("stop") => Some(RecordCommand::Stop),
+                                _ => None,
+                            };
+                            match command {
+                                Some(command) => self
+                                    .addr
+                                    .send(server::Record {
+                                        id: self.id.clone(),
+                                        room: self.room.clone(),
+                                        command,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, _, ctx| {
+                                        if let Ok(Some(v)) = res {
+                                            ctx.full(v.0, v.1);
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx),
+                                None => ctx.sys("!!! usage: /record start|stop".to_owned()),
+                            }
+                        }
+                        "/replay" => {
+                            if v.len() == 2 {
+                                self.addr
+                                    .send(server::StartReplay {
+                                        id: self.id.clone(),
+                                        recording: v[1].to_owned(),
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, act, ctx| {
+                                        match res {
+                                            Ok(Ok(room)) => act.join_room(room, false, ctx),
+                                            Ok(Err(err)) => ctx.sys(err),
+                                            Err(_) => {}
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx);
+                            } else {
+                                ctx.sys("!!! recording id is required".to_owned());
+                            }
+                        }
+                        "/directory" => {
+                            let query = DirectoryQuery::parse(v.get(1).unwrap_or(&""));
+                            self.addr
+                                .send(server::Directory { query })
+                                .into_actor(self)
+                                .then(|res, _, ctx| {
+                                    if let Ok(page) = res {
+                                        ctx.full(Code::Directory, page);
+                                    }
+                                    fut::ready(())
+                                })
+                                .wait(ctx)
+                        }
+                        "/filter" => {
+                            if v.len() == 2 {
+                                match v[1].parse::<Strictness>() {
+                                    Ok(strictness) => self
+                                        .addr
+                                        .send(server::SetFilter {
+                                            id: self.id.clone(),
+                                            room: self.room.clone(),
+                                            strictness,
+                                        })
+                                        .into_actor(self)
+                                        .then(|res, _, ctx| {
+                                            if let Ok(Some(v)) = res {
+                                                ctx.full(v.0, v.1);
+                                            }
+                                            fut::ready(())
+                                        })
+                                        .wait(ctx),
+                                    Err(_) => ctx.sys(format!("!!! unknown filter: {:?}", v[1])),
+                                }
+                            } else {
+                                ctx.sys("过滤级别不能为空".to_owned());
+                            }
+                        }
                         _ => ctx.sys(format!("!!! unknown command: {m:?}")),
                     }
                 }
@@ -0,0 +1,153 @@
+//! Optional built-in TLS. Certificates are served through a resolver so a
+//! renewed cert/key pair on disk is picked up without a restart.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+    /// Serve plain HTTP redirects to HTTPS on this port
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+    /// How often cert and key files are checked for changes
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    10
+}
+
+/// Hands out the currently loaded certificate
+pub struct CertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.key.read().ok().map(|key| key.clone())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> io::Result<CertifiedKey> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", cert.display())));
+    }
+    let der = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key in {}", key.display())))?;
+    let signing_key = sign::any_supported_type(&PrivateKey(der))
+        .map_err(|err| invalid(format!("unsupported key {}: {err}", key.display())))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(tls: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&tls.cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&tls.key).and_then(|m| m.modified()).ok()?;
+    Some((cert, key))
+}
+
+/// Build the rustls config and start watching the cert files
+pub fn server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let resolver = Arc::new(CertResolver {
+        key: RwLock::new(Arc::new(load_certified_key(&tls.cert, &tls.key)?)),
+    });
+    watch(tls.clone(), resolver.clone());
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Reload the certificate whenever cert or key file changes on disk
+fn watch(tls: TlsConfig, resolver: Arc<CertResolver>) {
+    let interval = Duration::from_secs(tls.reload_interval_secs.max(1));
+    thread::spawn(move || {
+        let mut last = modified(&tls);
+        loop {
+            thread::sleep(interval);
+            let current = modified(&tls);
+            if current.is_none() || current == last {
+                continue;
+            }
+            match load_certified_key(&tls.cert, &tls.key) {
+                Ok(key) => {
+                    if let Ok(mut slot) = resolver.key.write() {
+                        *slot = Arc::new(key);
+                    }
+                    log::info!("reloaded TLS certificate {}", tls.cert.display());
+                    last = current;
+                }
+                // cert and key may be mid-update, retry on the next tick
+                Err(err) => log::warn!("failed to reload TLS certificate: {err}"),
+            }
+        }
+    });
+}
+
+async fn redirect(req: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let info = req.connection_info();
+    let host = info.host();
+    let host = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.parse::<u16>().is_ok())
+        .map_or(host, |(host, _)| host);
+    let location = match **https_port {
+        443 => format!("https://{host}{}", req.uri()),
+        port => format!("https://{host}:{port}{}", req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+/// Plain HTTP server that sends every request to the HTTPS origin
+pub fn redirect_server(
+    host: String,
+    port: u16,
+    https_port: u16,
+) -> io::Result<actix_web::dev::Server> {
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(https_port))
+            .default_service(web::to(redirect))
+    })
+    .workers(1)
+    .bind((host, port))?
+    .run())
+}
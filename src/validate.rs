@@ -0,0 +1,306 @@
+//! Length and content checks for command arguments, applied before a
+//! command reaches `ChatServer`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Largest websocket frame accepted, in bytes
+    pub max_frame_bytes: usize,
+    pub room_max_bytes: usize,
+    pub name_max_bytes: usize,
+    pub avatar_max_bytes: usize,
+    pub msg_max_bytes: usize,
+    pub link_max_bytes: usize,
+    /// Punctuation allowed in room names besides letters and digits
+    pub room_extra_chars: String,
+    pub avatar_schemes: Vec<String>,
+    pub link_schemes: Vec<String>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_frame_bytes: 16 * 1024,
+            room_max_bytes: 64,
+            name_max_bytes: 64,
+            avatar_max_bytes: 1024,
+            msg_max_bytes: 2000,
+            link_max_bytes: 2048,
+            room_extra_chars: "-_. ".to_owned(),
+            avatar_schemes: vec!["http".to_owned(), "https".to_owned()],
+            link_schemes: vec!["http".to_owned(), "https".to_owned()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Room,
+    Name,
+    Avatar,
+    Msg,
+    Link,
+}
+
+/// Argument rejected by validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalid {
+    Empty(Field),
+    TooLong(Field),
+    Characters(Field),
+    Scheme(Field),
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (code, field) = match self {
+            Invalid::Empty(field) => ("EMPTY", field),
+            Invalid::TooLong(field) => ("TOO_LONG", field),
+            Invalid::Characters(field) => ("INVALID_CHARACTERS", field),
+            Invalid::Scheme(field) => ("INVALID_SCHEME", field),
+        };
+        write!(f, "{code}:{}", format!("{field:?}").to_lowercase())
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+impl ValidationConfig {
+    pub fn max_lengths(&self) -> [usize; 6] {
+        [
+            self.max_frame_bytes,
+            self.room_max_bytes,
+            self.name_max_bytes,
+            self.avatar_max_bytes,
+            self.msg_max_bytes,
+            self.link_max_bytes,
+        ]
+    }
+
+    /// Validate the argument of `command` and return it sanitized.
+    /// Multi-line arguments are checked field by field.
+    pub fn command(&self, command: &str, arg: &str) -> Result<String, Invalid> {
+        match command {
+            "/join" | "/spectate" => self.room(arg),
+            "/login" => match arg.split_once('\n') {
+                Some((name, avatar)) => {
+                    Ok(format!("{}\n{}", self.name(name)?, self.avatar(avatar)?))
+                }
+                None => self.name(arg),
+            },
+            "/msg" => self.msg(arg),
+            "/dm" => match arg.split_once('\n') {
+                Some((to, msg)) => Ok(format!("{}\n{}", self.name(to)?, self.msg(msg)?)),
+                None => self.name(arg),
+            },
+            "/share" => self.link(arg),
+            "/poll" => arg
+                .split('\n')
+                .map(|line| self.msg(line))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|lines| lines.join("\n")),
+            _ => Ok(strip_control(arg, true)),
+        }
+    }
+
+    pub fn room(&self, room: &str) -> Result<String, Invalid> {
+        let room = self.text(Field::Room, room, self.room_max_bytes, false)?;
+        if room
+            .chars()
+            .all(|c| c.is_alphanumeric() || self.room_extra_chars.contains(c))
+        {
+            Ok(room)
+        } else {
+            Err(Invalid::Characters(Field::Room))
+        }
+    }
+
+    /// Invisible format characters are dropped so names that look the same
+    /// compare the same
+    pub fn name(&self, name: &str) -> Result<String, Invalid> {
+        let name: String = name.chars().filter(|c| !is_format(*c)).collect();
+        self.text(Field::Name, &name, self.name_max_bytes, false)
+    }
+
+    pub fn avatar(&self, avatar: &str) -> Result<String, Invalid> {
+        let avatar = self.text(Field::Avatar, avatar, self.avatar_max_bytes, false)?;
+        // paths on our own origin are always fine
+        if avatar.starts_with('/') && !avatar.starts_with("//") {
+            return Ok(avatar);
+        }
+        check_scheme(Field::Avatar, &avatar, &self.avatar_schemes)?;
+        Ok(avatar)
+    }
+
+    pub fn msg(&self, msg: &str) -> Result<String, Invalid> {
+        self.text(Field::Msg, msg, self.msg_max_bytes, true)
+    }
+
+    pub fn link(&self, link: &str) -> Result<String, Invalid> {
+        let link = self.text(Field::Link, link, self.link_max_bytes, false)?;
+        check_scheme(Field::Link, &link, &self.link_schemes)?;
+        Ok(link)
+    }
+
+    fn text(
+        &self,
+        field: Field,
+        text: &str,
+        max_bytes: usize,
+        multiline: bool,
+    ) -> Result<String, Invalid> {
+        let text = strip_control(text, multiline);
+        let text = text.trim();
+        if text.is_empty() {
+            Err(Invalid::Empty(field))
+        } else if text.len() > max_bytes {
+            Err(Invalid::TooLong(field))
+        } else {
+            Ok(text.to_owned())
+        }
+    }
+}
+
+/// Drop control characters, optionally keeping line breaks
+fn strip_control(text: &str, keep_newlines: bool) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || (keep_newlines && *c == '\n'))
+        .collect()
+}
+
+/// Unicode format characters (category Cf): zero width spaces and joiners,
+/// bidi controls, soft hyphens and the like
+pub fn is_format(c: char) -> bool {
+    matches!(
+        c,
+        '\u{ad}'
+            | '\u{600}'..='\u{605}'
+            | '\u{61c}'
+            | '\u{6dd}'
+            | '\u{70f}'
+            | '\u{890}'..='\u{891}'
+            | '\u{8e2}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{206f}'
+            | '\u{feff}'
+            | '\u{fff9}'..='\u{fffb}'
+            | '\u{110bd}'
+            | '\u{110cd}'
+            | '\u{13430}'..='\u{1343f}'
+            | '\u{1bca0}'..='\u{1bca3}'
+            | '\u{1d173}'..='\u{1d17a}'
+            | '\u{e0001}'
+            | '\u{e0020}'..='\u{e007f}'
+    )
+}
+
+fn check_scheme(field: Field, url: &str, schemes: &[String]) -> Result<(), Invalid> {
+    match url.split_once("://") {
+        Some((scheme, rest))
+            if !rest.is_empty() && schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) =>
+        {
+            Ok(())
+        }
+        _ => Err(Invalid::Scheme(field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ValidationConfig {
+        ValidationConfig {
+            name_max_bytes: 8,
+            ..ValidationConfig::default()
+        }
+    }
+
+    #[test]
+    fn trims_and_strips_control() {
+        let config = config();
+        assert_eq!(config.name("  bob\t ").unwrap(), "bob");
+        assert_eq!(config.msg(" a\nb\u{7}c ").unwrap(), "a\nbc");
+        assert_eq!(config.link("http://a\n/b").unwrap(), "http://a/b");
+    }
+
+    #[test]
+    fn strips_format_characters_from_names() {
+        let config = config();
+        assert_eq!(config.name("ad\u{200b}min").unwrap(), "admin");
+        assert_eq!(config.name("\u{202e}bob\u{feff}").unwrap(), "bob");
+        assert_eq!(
+            config.name("\u{200b}\u{200d}"),
+            Err(Invalid::Empty(Field::Name))
+        );
+    }
+
+    #[test]
+    fn lengths() {
+        let config = config();
+        assert_eq!(config.name("   "), Err(Invalid::Empty(Field::Name)));
+        assert_eq!(config.name("abcdefgh").unwrap(), "abcdefgh");
+        assert_eq!(config.name("abcdefghi"), Err(Invalid::TooLong(Field::Name)));
+        // bytes, not characters
+        assert_eq!(config.name("ééééé"), Err(Invalid::TooLong(Field::Name)));
+    }
+
+    #[test]
+    fn rooms() {
+        let config = config();
+        assert_eq!(config.room(" my-room_1.2 ").unwrap(), "my-room_1.2");
+        assert_eq!(config.room("房间").unwrap(), "房间");
+        assert_eq!(config.room("a/b"), Err(Invalid::Characters(Field::Room)));
+        assert_eq!(
+            config.room("a\u{200b}b"),
+            Err(Invalid::Characters(Field::Room))
+        );
+    }
+
+    #[test]
+    fn schemes() {
+        let config = config();
+        assert!(config.link("HTTPS://example.com").is_ok());
+        assert_eq!(
+            config.link("javascript://alert(1)"),
+            Err(Invalid::Scheme(Field::Link))
+        );
+        assert_eq!(config.link("http://"), Err(Invalid::Scheme(Field::Link)));
+        assert_eq!(config.avatar("/avatars/a.png").unwrap(), "/avatars/a.png");
+        assert_eq!(
+            config.avatar("//evil.example/a.png"),
+            Err(Invalid::Scheme(Field::Avatar))
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let config = config();
+        assert_eq!(
+            config.command("/login", " bob \n/avatars/a.png").unwrap(),
+            "bob\n/avatars/a.png"
+        );
+        assert_eq!(config.command("/dm", "bob\n hi ").unwrap(), "bob\nhi");
+        assert_eq!(
+            config.command("/poll", "q?\n\nb"),
+            Err(Invalid::Empty(Field::Msg))
+        );
+        assert_eq!(config.command("/members", "a\u{0}b").unwrap(), "ab");
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(Invalid::TooLong(Field::Msg).to_string(), "TOO_LONG:msg");
+        assert_eq!(
+            Invalid::Characters(Field::Room).to_string(),
+            "INVALID_CHARACTERS:room"
+        );
+    }
+}
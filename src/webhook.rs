@@ -0,0 +1,719 @@
+//! Outgoing webhooks. Room events are POSTed as JSON to the endpoints in the
+//! config and to the ones a roomer adds with `/webhook`. Every body is signed
+//! with HMAC-SHA256 of the hook secret, sent as
+//! `X-Together-Signature: sha256=<hex>`. Failed deliveries are retried with
+//! exponential backoff; the ones that never get through are appended to the
+//! dead letter file. Room hooks only reach https endpoints on public
+//! addresses, resolved again before every attempt, and redirects are never
+//! followed.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::server::now_millis;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    RoomCreated,
+    RoomClosed,
+    MemberJoined,
+    MemberLeft,
+    MediaShared,
+    PlaybackStarted,
+}
+
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::RoomCreated => "room_created",
+            EventKind::RoomClosed => "room_closed",
+            EventKind::MemberJoined => "member_joined",
+            EventKind::MemberLeft => "member_left",
+            EventKind::MediaShared => "media_shared",
+            EventKind::PlaybackStarted => "playback_started",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "room_created" => Ok(EventKind::RoomCreated),
+            "room_closed" => Ok(EventKind::RoomClosed),
+            "member_joined" => Ok(EventKind::MemberJoined),
+            "member_left" => Ok(EventKind::MemberLeft),
+            "media_shared" => Ok(EventKind::MediaShared),
+            "playback_started" => Ok(EventKind::PlaybackStarted),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub url: String,
+    /// HMAC key of the signature header
+    pub secret: String,
+    /// Events to deliver, all of them when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Rooms of a global hook, all of them when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+}
+
+impl Hook {
+    fn wants(&self, room: &str, kind: EventKind) -> bool {
+        (self.events.is_empty() || self.events.contains(&kind))
+            && (self.rooms.is_empty() || self.rooms.iter().any(|r| r == room))
+    }
+}
+
+/// What `/webhook` reports to the roomer, the secret only right after `add`
+#[derive(Debug, Clone, Serialize)]
+pub struct HookInfo {
+    pub url: String,
+    pub events: Vec<EventKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<&Hook> for HookInfo {
+    fn from(hook: &Hook) -> Self {
+        HookInfo {
+            url: hook.url.clone(),
+            events: hook.events.clone(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Hooks receiving events of every room
+    pub hooks: Vec<Hook>,
+    /// Let roomers add their own hooks with `/webhook`
+    pub room_hooks: bool,
+    pub max_room_hooks: usize,
+    /// Deliveries per event, including the first one
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each following one
+    pub backoff_ms: u64,
+    pub timeout_secs: u64,
+    /// Deliveries retrying at once, further events go to the dead letter file
+    pub max_in_flight: usize,
+    /// Deliveries that ran out of attempts, one JSON object per line
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            hooks: Vec::new(),
+            room_hooks: false,
+            max_room_hooks: 3,
+            max_attempts: 5,
+            backoff_ms: 1000,
+            timeout_secs: 10,
+            max_in_flight: 256,
+            dead_letter: Some(PathBuf::from("./webhooks-dead.jsonl")),
+        }
+    }
+}
+
+/// `/webhook` subcommands, roomer only
+#[derive(Debug, Clone)]
+pub enum WebhookCommand {
+    /// Endpoint and the events it wants, all of them when empty
+    Add(String, Vec<EventKind>),
+    Remove(String),
+    List,
+}
+
+impl WebhookCommand {
+    /// `add url [event,event]`, `remove url` or `list`
+    pub fn parse(arg: &str) -> Option<WebhookCommand> {
+        let mut parts = arg.split_whitespace();
+        Some(match (parts.next(), parts.next(), parts.next()) {
+            (Some("add"), Some(url), events) => {
+                let events = match events {
+                    Some(events) => events
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .ok()?,
+                    None => Vec::new(),
+                };
+                WebhookCommand::Add(url.to_owned(), events)
+            }
+            (Some("remove"), Some(url), None) => WebhookCommand::Remove(url.to_owned()),
+            (Some("list") | None, None, None) => WebhookCommand::List,
+            _ => return None,
+        })
+    }
+}
+
+/// Random secret handed to the roomer when a room hook is added
+pub fn new_secret() -> String {
+    let bytes: [u8; 24] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Whether `ip` is on the public internet, room hooks may not reach anything
+/// else: loopback, private, shared, link local, documentation, multicast...
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && b & 0xfe == 18))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(v4.into());
+            }
+            let s = ip.segments();
+            !(ip.is_multicast()
+                // unspecified, loopback and IPv4 compatible
+                || s[..6] == [0; 6]
+                // NAT64
+                || s[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                // unique local
+                || s[0] & 0xfe00 == 0xfc00
+                // link local
+                || s[0] & 0xffc0 == 0xfe80
+                // documentation
+                || (s[0] == 0x2001 && s[1] == 0x0db8))
+        }
+    }
+}
+
+/// Resolve the host of a room hook. Only https URLs whose every address is
+/// public pass, the host is returned with the address to connect to.
+pub async fn public_addr(url: &str) -> Result<(String, SocketAddr), &'static str> {
+    let url = reqwest::Url::parse(url).map_err(|_| "INVALID_URL:webhook")?;
+    if url.scheme() != "https" {
+        return Err("INVALID_SCHEME:webhook");
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+    let host = url
+        .host_str()
+        .ok_or("INVALID_URL:webhook")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => {
+            let name = host.clone();
+            actix_web::rt::task::spawn_blocking(move || {
+                (name.as_str(), port)
+                    .to_socket_addrs()
+                    .map(Iterator::collect)
+            })
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .unwrap_or_default()
+        }
+    };
+    match addrs.first() {
+        None => Err("UNRESOLVABLE:webhook"),
+        Some(_) if !addrs.iter().all(|addr| is_public(addr.ip())) => Err("PRIVATE_ADDRESS:webhook"),
+        Some(&addr) => Ok((host, addr)),
+    }
+}
+
+/// Body of every delivery
+#[derive(Debug, Clone, Serialize)]
+struct Event<'a, T: Serialize> {
+    /// Same on every retry, receivers can drop duplicates with it
+    id: String,
+    event: EventKind,
+    room: &'a str,
+    /// Unix milliseconds
+    at: u64,
+    data: T,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    /// Unix milliseconds
+    at: u64,
+    url: &'a str,
+    attempts: u32,
+    error: &'a str,
+    payload: serde_json::Value,
+}
+
+/// Sends events to the hooks, owned by `ChatServer`
+pub struct Webhooks {
+    config: Arc<WebhooksConfig>,
+    /// For the hooks of the config, room hooks get one pinned to the
+    /// address checked right before each attempt
+    client: reqwest::Client,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// One of `max_in_flight`, given back when the delivery is over
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhooks")
+            .field("hooks", &self.config.hooks.len())
+            .finish()
+    }
+}
+
+impl Webhooks {
+    pub fn new(config: WebhooksConfig) -> reqwest::Result<Webhooks> {
+        let client = client_builder(&config).build()?;
+        Ok(Webhooks {
+            config: Arc::new(config),
+            client,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn slot(&self) -> Option<Slot> {
+        let max = self.config.max_in_flight.max(1);
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Slot(self.in_flight.clone()))
+    }
+
+    pub fn room_hooks(&self) -> bool {
+        self.config.room_hooks
+    }
+
+    pub fn max_room_hooks(&self) -> usize {
+        self.config.max_room_hooks
+    }
+
+    /// Deliver an event to the global hooks and `room_hooks` that want it.
+    /// Must be called from within the actix runtime.
+    pub fn emit<T: Serialize>(&self, room_hooks: &[Hook], room: &str, kind: EventKind, data: T) {
+        let hooks: Vec<(Hook, bool)> = self
+            .config
+            .hooks
+            .iter()
+            .map(|hook| (hook, false))
+            .chain(room_hooks.iter().map(|hook| (hook, true)))
+            .filter(|(hook, _)| hook.wants(room, kind))
+            .map(|(hook, public)| (hook.clone(), public))
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+        let event = Event {
+            id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            event: kind,
+            room,
+            at: now_millis(),
+            data,
+        };
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => Arc::new(body),
+            Err(err) => {
+                log::error!("failed to encode {kind} event: {err}");
+                return;
+            }
+        };
+        for (hook, public) in hooks {
+            let Some(slot) = self.slot() else {
+                log::warn!("too many webhook deliveries in flight, dropping {kind}");
+                dead_letter(&self.config, &hook.url, 0, "too many in flight", &body);
+                continue;
+            };
+            let delivery = deliver(
+                self.client.clone(),
+                self.config.clone(),
+                hook,
+                public,
+                kind,
+                event.id.clone(),
+                body.clone(),
+            );
+            actix::spawn(async move {
+                delivery.await;
+                drop(slot);
+            });
+        }
+    }
+}
+
+fn client_builder(config: &WebhooksConfig) -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs.max(1)))
+        .redirect(reqwest::redirect::Policy::none())
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={digest}")
+}
+
+/// A client for a room hook, connecting to a public address only
+async fn pinned_client(config: &WebhooksConfig, url: &str) -> Result<reqwest::Client, String> {
+    let (host, addr) = public_addr(url).await.map_err(str::to_owned)?;
+    client_builder(config)
+        .resolve(&host, addr)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+/// `public` hooks are room hooks, checked again on every attempt since
+/// their host may resolve elsewhere by now
+async fn deliver(
+    client: reqwest::Client,
+    config: Arc<WebhooksConfig>,
+    hook: Hook,
+    public: bool,
+    kind: EventKind,
+    id: String,
+    body: Arc<Vec<u8>>,
+) {
+    let signature = sign(&hook.secret, &body);
+    let attempts = config.max_attempts.max(1);
+    let mut backoff = Duration::from_millis(config.backoff_ms);
+    let mut error = String::new();
+    for attempt in 1..=attempts {
+        let client = match public {
+            true => pinned_client(&config, &hook.url).await,
+            false => Ok(client.clone()),
+        };
+        let result = match client {
+            Ok(client) => client
+                .post(&hook.url)
+                .header("Content-Type", "application/json")
+                .header("X-Together-Event", kind.name())
+                .header("X-Together-Delivery", &id)
+                .header("X-Together-Signature", &signature)
+                .body(body.as_ref().clone())
+                .send()
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(res) if res.status().is_success() => return,
+            Ok(res) => error = format!("HTTP {}", res.status()),
+            Err(err) => error = err,
+        }
+        log::warn!(
+            "webhook {} attempt {attempt}/{attempts} for {kind} failed: {error}",
+            hook.url
+        );
+        if attempt < attempts {
+            actix::clock::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+    dead_letter(&config, &hook.url, attempts, &error, &body);
+}
+
+fn dead_letter(config: &WebhooksConfig, url: &str, attempts: u32, error: &str, body: &[u8]) {
+    let Some(path) = &config.dead_letter else {
+        return;
+    };
+    let letter = DeadLetter {
+        at: now_millis(),
+        url,
+        attempts,
+        error,
+        payload: serde_json::from_slice(body).unwrap_or_default(),
+    };
+    let result = serde_json::to_string(&letter)
+        .map_err(std::io::Error::from)
+        .and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{line}")
+        });
+    if let Err(err) = result {
+        log::error!("failed to write dead letter to {}: {err}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::Mutex,
+        time::Instant,
+    };
+
+    use super::*;
+
+    struct Request {
+        at: Instant,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// HTTP endpoint answering with `statuses` in turn, the last one after
+    /// they run out
+    fn stand_in(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        headers.insert(name.to_lowercase(), value.to_owned());
+                    }
+                }
+                let length = headers["content-length"].parse().unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                seen.lock().unwrap().push(Request {
+                    at: Instant::now(),
+                    headers,
+                    body,
+                });
+                let status = statuses[n.min(statuses.len() - 1)];
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn config(dead_letter: Option<PathBuf>) -> WebhooksConfig {
+        WebhooksConfig {
+            max_attempts: 3,
+            backoff_ms: 100,
+            dead_letter,
+            ..WebhooksConfig::default()
+        }
+    }
+
+    fn hook(url: &str) -> Hook {
+        Hook {
+            url: url.to_owned(),
+            secret: "s3cret".to_owned(),
+            events: Vec::new(),
+            rooms: Vec::new(),
+        }
+    }
+
+    async fn run(config: WebhooksConfig, url: &str) {
+        let client = client_builder(&config).build().unwrap();
+        let body = br#"{"event":"room_created","room":"r"}"#.to_vec();
+        deliver(
+            client,
+            Arc::new(config),
+            hook(url),
+            false,
+            EventKind::RoomCreated,
+            "42".to_owned(),
+            Arc::new(body),
+        )
+        .await;
+    }
+
+    fn dead_letter_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("together-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[actix_web::test]
+    async fn signed_delivery() {
+        let (url, requests) = stand_in(vec![200]);
+        run(config(None), &url).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.headers["x-together-event"], "room_created");
+        assert_eq!(request.headers["x-together-delivery"], "42");
+        let hex = request.headers["x-together-signature"]
+            .strip_prefix("sha256=")
+            .unwrap();
+        let digest: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(&request.body);
+        assert!(mac.verify_slice(&digest).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn retries_with_backoff() {
+        let path = dead_letter_path("retries");
+        let (url, requests) = stand_in(vec![500, 503, 200]);
+        run(config(Some(path.clone())), &url).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let first = requests[1].at - requests[0].at;
+        let second = requests[2].at - requests[1].at;
+        assert!(first >= Duration::from_millis(100), "{first:?}");
+        assert!(second >= Duration::from_millis(200), "{second:?}");
+        assert!(requests
+            .iter()
+            .all(|r| r.headers["x-together-delivery"] == "42"));
+        assert!(!path.exists());
+    }
+
+    #[actix_web::test]
+    async fn dead_letter_after_last_attempt() {
+        let path = dead_letter_path("dead");
+        let (url, requests) = stand_in(vec![500]);
+        run(config(Some(path.clone())), &url).await;
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["url"], url.as_str());
+        assert_eq!(lines[0]["attempts"], 3);
+        assert_eq!(lines[0]["error"], "HTTP 500 Internal Server Error");
+        assert_eq!(lines[0]["payload"]["event"], "room_created");
+    }
+
+    #[actix_web::test]
+    async fn in_flight_is_bounded() {
+        let path = dead_letter_path("in-flight");
+        let (url, _) = stand_in(vec![500]);
+        let webhooks = Webhooks::new(WebhooksConfig {
+            hooks: vec![hook(&url)],
+            max_in_flight: 1,
+            backoff_ms: 60_000,
+            ..config(Some(path.clone()))
+        })
+        .unwrap();
+        webhooks.emit(&[], "r", EventKind::RoomCreated, ());
+        webhooks.emit(&[], "r", EventKind::RoomClosed, ());
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let letter: serde_json::Value = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(letter["attempts"], 0);
+        assert_eq!(letter["payload"]["event"], "room_closed");
+        assert_eq!(webhooks.in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "fd00::1",
+            "fe80::1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn room_hook_urls() {
+        assert_eq!(
+            public_addr("http://93.184.216.34/").await,
+            Err("INVALID_SCHEME:webhook")
+        );
+        assert_eq!(public_addr("not a url").await, Err("INVALID_URL:webhook"));
+        for url in [
+            "https://127.0.0.1/",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]:8443/",
+            "https://10.0.0.1/",
+            "https://localhost/",
+        ] {
+            assert_eq!(
+                public_addr(url).await,
+                Err("PRIVATE_ADDRESS:webhook"),
+                "{url}"
+            );
+        }
+        let (host, addr) = public_addr("https://93.184.216.34:8443/x").await.unwrap();
+        assert_eq!(host, "93.184.216.34");
+        assert_eq!(addr, "93.184.216.34:8443".parse().unwrap());
+    }
+}